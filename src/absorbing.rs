@@ -0,0 +1,468 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::data_reader::BoundaryStreet;
+use crate::error::{Error, Result};
+use crate::markov_chain::{MarkovChain, TransitionMatrix};
+use crate::stationary::{solve_dense, DENSE_SOLVE_MAX_DIM};
+
+// Below this a street's chance of leaving the network is rounding noise
+const EXIT_EPSILON: f64 = 1e-12;
+
+// Streets vehicles leave the network from and vehicles entering per step, by OSM way id.
+// Streets leaving a clipped network and streets with no way forward are exits without
+// being listed.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Boundary {
+    pub exits: Vec<u64>,
+    pub inflow: HashMap<u64, f64>,
+}
+
+impl Boundary {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        serde_json::from_str(&content).map_err(|e| Error::parse(path, None, e))
+    }
+}
+
+// Streets are the transient states, each exit street adds an absorbing state reached with
+// the probability mass its row no longer sends to other streets
+#[derive(Debug, Clone)]
+pub struct AbsorbingChain {
+    transient: TransitionMatrix,
+    exit: Vec<f64>,
+    pub exits: Vec<usize>,
+    pub inflow: Vec<f64>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Absorption {
+    pub exits: Vec<usize>,
+    // Expected steps before leaving the network, from each street
+    pub expected_steps: Vec<f64>,
+    // Probability of leaving through each exit, indexed [street][exit]
+    pub probabilities: Vec<Vec<f64>>,
+    pub iterations: usize,
+    pub residual: f64,
+    pub converged: bool,
+}
+
+// Vehicles on each street once inflow and outflow balance
+#[derive(Debug, Serialize, Clone)]
+pub struct Occupancy {
+    pub vehicles: Vec<f64>,
+    pub iterations: usize,
+    pub residual: f64,
+    pub converged: bool,
+}
+
+impl AbsorbingChain {
+    // Exit streets keep only their self transition, every other move becomes leaving the
+    // network. Unknown probabilities count as leaving too.
+    pub fn new(t_mtx: &TransitionMatrix, exits: &[usize], inflow: Vec<f64>) -> Result<Self> {
+        let dim = t_mtx.dim();
+        if inflow.len() != dim {
+            return Err(Error::InvalidInput(format!(
+                "inflow for {} streets, matrix has {}",
+                inflow.len(),
+                dim
+            )));
+        }
+        if let Some(i) = exits.iter().find(|i| **i >= dim) {
+            return Err(Error::InvalidInput(format!("no street {}", i)));
+        }
+
+        let mut is_exit = vec![false; dim];
+        exits.iter().for_each(|i| is_exit[*i] = true);
+        let transient = TransitionMatrix::new(
+            dim,
+            t_mtx
+                .entries()
+                .filter(|(from, to, p)| p.is_finite() && (from == to || !is_exit[*from]))
+                .collect(),
+        );
+        let exit: Vec<f64> = (0..dim)
+            .map(|i| {
+                let (_, values) = transient.row(i);
+                (1.0 - values.iter().sum::<f64>()).max(0.0)
+            })
+            .collect();
+        let exits = (0..dim).filter(|i| exit[*i] > EXIT_EPSILON).collect();
+
+        Ok(AbsorbingChain {
+            transient,
+            exit,
+            exits,
+            inflow,
+        })
+    }
+
+    // Inflow of a way is split between its streets entering the network. Streets flagged
+    // when clipping are the entries and exits, without them a way enters through its
+    // streets starting at a dead end, where the extract was cut.
+    pub fn from_boundary(
+        mkv_chain: &MarkovChain,
+        t_mtx: &TransitionMatrix,
+        boundary: &Boundary,
+        clipped: &[BoundaryStreet],
+    ) -> Result<Self> {
+        let ways = |id_osm: &u64| match mkv_chain.nodes_of_way(*id_osm) {
+            v if v.is_empty() => Err(Error::InvalidInput(format!(
+                "no street with way id {}",
+                id_osm
+            ))),
+            v => Ok(v),
+        };
+
+        let (entries, mut exits) = boundary_streets(mkv_chain, clipped);
+        for id_osm in boundary.exits.iter() {
+            exits.extend(ways(id_osm)?);
+        }
+        exits.sort_unstable();
+        exits.dedup();
+
+        let mut inflow = vec![0.0; t_mtx.dim()];
+        for (id_osm, rate) in boundary.inflow.iter() {
+            let streets: Vec<usize> = ways(id_osm)?
+                .into_iter()
+                .filter(|i| entries.contains(i))
+                .collect();
+            if streets.is_empty() {
+                return Err(Error::InvalidInput(format!(
+                    "way {} has no street entering the network",
+                    id_osm
+                )));
+            }
+            for i in streets.iter() {
+                inflow[*i] += rate / streets.len() as f64;
+            }
+        }
+
+        AbsorbingChain::new(t_mtx, &exits, inflow)
+    }
+
+    pub fn dim(&self) -> usize {
+        self.transient.dim()
+    }
+
+    // Probability of leaving the network from each street in one step
+    pub fn exit_probabilities(&self) -> &[f64] {
+        &self.exit
+    }
+
+    // N = (I - Q)⁻¹, expected visits to street j starting from street i
+    pub fn fundamental_matrix(&self) -> Result<Vec<Vec<f64>>> {
+        let dim = self.dim();
+        self.solve(
+            &(0..dim)
+                .map(|i| (0..dim).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+                .collect::<Vec<Vec<f64>>>(),
+        )
+    }
+
+    // Solves (I - Q) X = B directly, B indexed [street][column]
+    fn solve(&self, rhs: &[Vec<f64>]) -> Result<Vec<Vec<f64>>> {
+        let dim = self.dim();
+        if dim == 0 {
+            return Err(Error::Solver("empty transition matrix".to_string()));
+        }
+        if dim > DENSE_SOLVE_MAX_DIM {
+            return Err(Error::Solver(format!(
+                "dense solve limited to {} states, matrix has {}",
+                DENSE_SOLVE_MAX_DIM, dim
+            )));
+        }
+
+        let mut system: Vec<Vec<f64>> = rhs
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                let mut row = vec![0.0; dim];
+                row[i] = 1.0;
+                row.extend(b);
+                row
+            })
+            .collect();
+        for (from, to, p) in self.transient.entries() {
+            system[from][to] -= p;
+        }
+        solve_dense(system).ok_or_else(|| {
+            Error::Solver("singular system, some streets never reach an exit".to_string())
+        })
+    }
+
+    // t = N 1 and B = N R in one dense solve
+    pub fn absorption_direct(&self) -> Result<Absorption> {
+        let rhs = self.absorption_rhs();
+        let solution = self.solve(&rhs)?;
+        let residual = self.absorption_residual(&rhs, &solution);
+        Ok(self.absorption_from(solution, 1, residual, true))
+    }
+
+    // Iterates x = c + Q x from zero, which converges whenever every street can reach an exit
+    pub fn absorption(&self, tolerance: f64, max_iterations: usize) -> Absorption {
+        let rhs = self.absorption_rhs();
+        let mut x = vec![vec![0.0; self.exits.len() + 1]; self.dim()];
+        let mut iterations = 0;
+        let mut residual = f64::INFINITY;
+
+        while iterations < max_iterations && residual > tolerance {
+            let next: Vec<Vec<f64>> = rhs
+                .iter()
+                .enumerate()
+                .map(|(i, c)| {
+                    let (cols, values) = self.transient.row(i);
+                    let mut next = c.clone();
+                    for (j, p) in cols.iter().zip(values.iter()) {
+                        for (n, x) in next.iter_mut().zip(x[*j].iter()) {
+                            *n += p * x;
+                        }
+                    }
+                    next
+                })
+                .collect();
+            residual = max_distance(&next, &x);
+            x = next;
+            iterations += 1;
+        }
+
+        self.absorption_from(x, iterations, residual, residual <= tolerance)
+    }
+
+    // Column 0 counts steps, column k + 1 is leaving through exit k
+    fn absorption_rhs(&self) -> Vec<Vec<f64>> {
+        (0..self.dim())
+            .map(|i| {
+                let mut row = vec![0.0; self.exits.len() + 1];
+                row[0] = 1.0;
+                if let Ok(k) = self.exits.binary_search(&i) {
+                    row[k + 1] = self.exit[i];
+                }
+                row
+            })
+            .collect()
+    }
+
+    fn absorption_residual(&self, rhs: &[Vec<f64>], x: &[Vec<f64>]) -> f64 {
+        let next: Vec<Vec<f64>> = rhs
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let (cols, values) = self.transient.row(i);
+                c.iter()
+                    .enumerate()
+                    .map(|(k, c)| {
+                        c + cols
+                            .iter()
+                            .zip(values.iter())
+                            .map(|(j, p)| p * x[*j][k])
+                            .sum::<f64>()
+                    })
+                    .collect()
+            })
+            .collect();
+        max_distance(&next, x)
+    }
+
+    fn absorption_from(
+        &self,
+        solution: Vec<Vec<f64>>,
+        iterations: usize,
+        residual: f64,
+        converged: bool,
+    ) -> Absorption {
+        let (expected_steps, probabilities) = solution
+            .into_iter()
+            .map(|mut x| {
+                let probabilities = x.split_off(1);
+                (x[0], probabilities)
+            })
+            .unzip();
+        Absorption {
+            exits: self.exits.clone(),
+            expected_steps,
+            probabilities,
+            iterations,
+            residual,
+            converged,
+        }
+    }
+
+    // Iterates x = λ + x Q, the vehicles present once as many leave per step as enter
+    pub fn occupancy(&self, tolerance: f64, max_iterations: usize) -> Occupancy {
+        let mut vehicles = vec![0.0; self.dim()];
+        let mut iterations = 0;
+        let mut residual = f64::INFINITY;
+
+        while iterations < max_iterations && residual > tolerance {
+            let next: Vec<f64> = self
+                .transient
+                .vec_mul(&vehicles)
+                .iter()
+                .zip(self.inflow.iter())
+                .map(|(x, inflow)| x + inflow)
+                .collect();
+            residual = next
+                .iter()
+                .zip(vehicles.iter())
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f64::max);
+            vehicles = next;
+            iterations += 1;
+        }
+
+        Occupancy {
+            vehicles,
+            iterations,
+            residual,
+            converged: residual <= tolerance,
+        }
+    }
+}
+
+// Streets entering and leaving the network. The ones flagged when clipping if any, otherwise
+// the streets starting and ending at an intersection with a single neighbour.
+pub fn boundary_streets(
+    mkv_chain: &MarkovChain,
+    clipped: &[BoundaryStreet],
+) -> (Vec<usize>, Vec<usize>) {
+    let streets = mkv_chain.edge_states();
+    if !clipped.is_empty() {
+        let flagged = |entering: bool| {
+            (0..streets.len())
+                .filter(|i| {
+                    let x = &streets[*i];
+                    clipped.iter().any(|b| {
+                        b.entering == entering
+                            && (b.id, b.start, b.end) == (x.id_osm, x.start, x.end)
+                    })
+                })
+                .collect()
+        };
+        return (flagged(true), flagged(false));
+    }
+
+    let mut neighbours: HashMap<u64, HashSet<u64>> = HashMap::new();
+    for x in streets.iter() {
+        neighbours.entry(x.start).or_default().insert(x.end);
+        neighbours.entry(x.end).or_default().insert(x.start);
+    }
+    let dead_end = |id: &u64| neighbours[id].len() == 1;
+    (
+        (0..streets.len())
+            .filter(|i| dead_end(&streets[*i].start))
+            .collect(),
+        (0..streets.len())
+            .filter(|i| dead_end(&streets[*i].end))
+            .collect(),
+    )
+}
+
+fn max_distance(a: &[Vec<f64>], b: &[Vec<f64>]) -> f64 {
+    a.iter()
+        .zip(b.iter())
+        .flat_map(|(x, y)| x.iter().zip(y.iter()).map(|(x, y)| (x - y).abs()))
+        .fold(0.0, f64::max)
+}
+
+mod tests {
+    #[test]
+    fn absorption_of_a_one_way_street() {
+        use super::AbsorbingChain;
+
+        // Street 0 leads into street 1, which ends at the edge of the network
+        let t_mtx = crate::markov_chain::TransitionMatrix::new(
+            2,
+            vec![(0, 0, 0.5), (0, 1, 0.5), (1, 1, 0.75)],
+        );
+
+        let chain = AbsorbingChain::new(&t_mtx, &[], vec![1.0, 0.0]).unwrap();
+        assert_eq!(chain.exits, vec![1]);
+        assert_eq!(chain.exit_probabilities(), &[0.0, 0.25]);
+
+        let n = chain.fundamental_matrix().unwrap();
+        assert!((n[0][0] - 2.0).abs() < 1e-12 && (n[0][1] - 4.0).abs() < 1e-12);
+
+        let direct = chain.absorption_direct().unwrap();
+        assert!((direct.expected_steps[0] - 6.0).abs() < 1e-12);
+        assert!((direct.probabilities[0][0] - 1.0).abs() < 1e-12);
+        let iterative = chain.absorption(1e-10, 10_000);
+        assert!(iterative.converged);
+        assert!((iterative.expected_steps[1] - 4.0).abs() < 1e-8);
+
+        let occupancy = chain.occupancy(1e-10, 10_000);
+        assert!((occupancy.vehicles[0] - 2.0).abs() < 1e-8);
+        assert!((occupancy.vehicles[1] - 4.0).abs() < 1e-8);
+
+        // Listing street 0 as an exit stops it feeding street 1
+        let chain = AbsorbingChain::new(&t_mtx, &[0], vec![1.0, 0.0]).unwrap();
+        let absorption = chain.absorption_direct().unwrap();
+        assert_eq!(absorption.exits, vec![0, 1]);
+        assert_eq!(absorption.probabilities[0], vec![1.0, 0.0]);
+        assert_eq!(chain.occupancy(1e-10, 10_000).vehicles[1], 0.0);
+    }
+
+    #[actix_rt::test]
+    async fn entries_and_exits_of_a_clipped_network() {
+        use std::collections::HashSet;
+
+        use super::AbsorbingChain;
+        use crate::data_reader::fixtures::{node, street};
+        use crate::data_reader::NetworkData;
+
+        // Two-way 0 - 1 - 2 - 3, clipped to 1 - 3 so way 9 crosses the edge
+        let nw = NetworkData::new(
+            "line".to_string(),
+            (0..=3).map(|id| node(id, 0.0, id as f64 * 0.001)).collect(),
+            vec![
+                street(9, 1, 0),
+                street(9, 0, 1),
+                street(10, 2, 1),
+                street(10, 1, 2),
+                street(11, 3, 2),
+                street(11, 2, 3),
+            ],
+        );
+        let nw = nw.subnetwork(&HashSet::from([1, 2, 3]), true);
+        let clipped = nw.boundary.clone();
+
+        let mkv_chain = crate::markov_chain::MarkovChain::new_from_network(
+            &crate::traffic_source::OpenStreetMap,
+            nw,
+            &crate::markov_chain::TransitionOptions::default(),
+        )
+        .await
+        .unwrap();
+        let t_mtx = crate::markov_chain::TransitionMatrix::new_from_markov_chain(&mkv_chain);
+        let ends: Vec<(u64, u64)> = mkv_chain
+            .edge_states()
+            .iter()
+            .map(|x| (x.start, x.end))
+            .collect();
+        let street_of = |start, end| ends.iter().position(|x| *x == (start, end)).unwrap();
+
+        // The U-turn at 0 leads into the entering street, which still takes the inflow
+        let (_, values) = t_mtx.column(street_of(0, 1));
+        assert!(values.iter().any(|p| *p > 0.0));
+        let boundary: super::Boundary = serde_json::from_str(r#"{"inflow": {"9": 2.0}}"#).unwrap();
+        let chain = AbsorbingChain::from_boundary(&mkv_chain, &t_mtx, &boundary, &clipped).unwrap();
+        assert_eq!(chain.inflow[street_of(0, 1)], 2.0);
+        assert_eq!(chain.inflow.iter().sum::<f64>(), 2.0);
+        assert_eq!(chain.exits, vec![street_of(1, 0)]);
+
+        // Everything entering leaves through the same way
+        let absorption = chain.absorption_direct().unwrap();
+        assert!(absorption
+            .probabilities
+            .iter()
+            .all(|p| (p[0] - 1.0).abs() < 1e-9));
+        assert!(chain.occupancy(1e-10, 100_000).converged);
+
+        // Without the flags the street starting at the dead end is the entry
+        let chain = AbsorbingChain::from_boundary(&mkv_chain, &t_mtx, &boundary, &[]).unwrap();
+        assert_eq!(chain.inflow[street_of(0, 1)], 2.0);
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::data_reader::{haversine, BoundaryStreet, NetworkData};
+use crate::error::{Error, Result};
+
+// Part of a network to study, positions in degrees and distances in metres
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Area {
+    Circle {
+        latitude: f64,
+        longitude: f64,
+        radius: f64,
+    },
+    BoundingBox {
+        south: f64,
+        west: f64,
+        north: f64,
+        east: f64,
+    },
+    // Rings of (latitude, longitude). Holes and separate parts are all rings, a point is
+    // inside when it is within an odd number of them.
+    Polygon {
+        rings: Vec<Vec<(f64, f64)>>,
+    },
+}
+
+impl std::str::FromStr for Area {
+    type Err = String;
+
+    // A bounding box as south,west,north,east
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let bounds = s
+            .split(',')
+            .map(|x| x.trim().parse::<f64>().ok())
+            .collect::<Option<Vec<f64>>>();
+        match bounds.as_deref() {
+            Some(&[south, west, north, east]) if south < north && west < east => {
+                Ok(Area::BoundingBox {
+                    south,
+                    west,
+                    north,
+                    east,
+                })
+            }
+            _ => Err(format!(
+                "expected a bounding box south,west,north,east with south < north and \
+                 west < east, got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+impl Area {
+    // GeoJSON when the file starts with a brace, WKT otherwise
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        match content.trim_start().starts_with('{') {
+            true => {
+                let geojson: serde_json::Value =
+                    serde_json::from_str(&content).map_err(|e| Error::parse(path, None, e))?;
+                Area::from_geojson(&geojson)
+            }
+            false => Area::from_wkt(&content),
+        }
+    }
+
+    // Polygon or MultiPolygon geometries, bare or in a Feature or FeatureCollection.
+    // Every polygon of a collection is part of the area.
+    pub fn from_geojson(geojson: &serde_json::Value) -> Result<Self> {
+        let mut rings: Vec<Vec<(f64, f64)>> = Vec::new();
+        geojson_rings(geojson, &mut rings)?;
+        polygon(rings)
+    }
+
+    // POLYGON or MULTIPOLYGON, coordinates as longitude latitude
+    pub fn from_wkt(wkt: &str) -> Result<Self> {
+        let wkt = wkt.trim();
+        let kind = wkt.split('(').next().unwrap_or_default().trim();
+        if !kind.eq_ignore_ascii_case("polygon") && !kind.eq_ignore_ascii_case("multipolygon") {
+            return Err(Error::InvalidInput(format!(
+                "expected a WKT POLYGON or MULTIPOLYGON, got '{}'",
+                kind
+            )));
+        }
+
+        // Rings are the innermost parentheses
+        let mut rings: Vec<Vec<(f64, f64)>> = Vec::new();
+        let mut start: Option<usize> = None;
+        for (i, c) in wkt.char_indices() {
+            match (c, start) {
+                ('(', _) => start = Some(i + 1),
+                (')', Some(from)) => {
+                    let ring = wkt[from..i]
+                        .split(',')
+                        .map(|point| {
+                            let values: Vec<f64> = point
+                                .split_whitespace()
+                                .filter_map(|x| x.parse().ok())
+                                .collect();
+                            match values.as_slice() {
+                                [longitude, latitude, ..] => Some((*latitude, *longitude)),
+                                _ => None,
+                            }
+                        })
+                        .collect::<Option<Vec<(f64, f64)>>>()
+                        .ok_or_else(|| {
+                            Error::InvalidInput(format!("bad WKT ring '{}'", &wkt[from..i]))
+                        })?;
+                    rings.push(ring);
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+        polygon(rings)
+    }
+
+    pub fn contains(&self, position: (f64, f64)) -> bool {
+        let (latitude, longitude) = position;
+        match self {
+            Area::Circle {
+                latitude: center_latitude,
+                longitude: center_longitude,
+                radius,
+            } => haversine((*center_latitude, *center_longitude), position) <= *radius,
+            Area::BoundingBox {
+                south,
+                west,
+                north,
+                east,
+            } => (*south..=*north).contains(&latitude) && (*west..=*east).contains(&longitude),
+            Area::Polygon { rings } => {
+                rings
+                    .iter()
+                    .filter(|ring| crosses_odd(ring, position))
+                    .count()
+                    % 2
+                    == 1
+            }
+        }
+    }
+}
+
+fn polygon(rings: Vec<Vec<(f64, f64)>>) -> Result<Area> {
+    match rings.iter().any(|ring| ring.len() >= 3) {
+        true => Ok(Area::Polygon {
+            rings: rings.into_iter().filter(|ring| ring.len() >= 3).collect(),
+        }),
+        false => Err(Error::InvalidInput("no polygon in the area".to_string())),
+    }
+}
+
+fn geojson_rings(geojson: &serde_json::Value, rings: &mut Vec<Vec<(f64, f64)>>) -> Result<()> {
+    let positions = |ring: &serde_json::Value| -> Option<Vec<(f64, f64)>> {
+        ring.as_array()?
+            .iter()
+            .map(|x| Some((x.get(1)?.as_f64()?, x.get(0)?.as_f64()?)))
+            .collect()
+    };
+    let polygon_rings = |polygon: &serde_json::Value| -> Result<Vec<Vec<(f64, f64)>>> {
+        polygon
+            .as_array()
+            .and_then(|x| x.iter().map(positions).collect())
+            .ok_or_else(|| Error::InvalidInput("bad GeoJSON polygon coordinates".to_string()))
+    };
+
+    let coordinates = &geojson["coordinates"];
+    match geojson["type"].as_str() {
+        Some("FeatureCollection") => {
+            for feature in geojson["features"].as_array().into_iter().flatten() {
+                geojson_rings(feature, rings)?;
+            }
+        }
+        Some("Feature") => geojson_rings(&geojson["geometry"], rings)?,
+        Some("Polygon") => rings.extend(polygon_rings(coordinates)?),
+        Some("MultiPolygon") => {
+            for polygon in coordinates.as_array().into_iter().flatten() {
+                rings.extend(polygon_rings(polygon)?);
+            }
+        }
+        // Points and lines of a collection have no inside
+        _ => (),
+    }
+    Ok(())
+}
+
+// Ray cast toward increasing longitude
+fn crosses_odd(ring: &[(f64, f64)], position: (f64, f64)) -> bool {
+    let (y, x) = position;
+    let mut inside = false;
+    for (i, (yi, xi)) in ring.iter().enumerate() {
+        let (yj, xj) = ring[(i + ring.len() - 1) % ring.len()];
+        if (*yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+impl NetworkData {
+    // Intersections inside the area and the streets between them, like osmnx's graph_from_point
+    // without truncate_by_edge
+    pub fn clip(&self, area: &Area, keep_boundary: bool) -> NetworkData {
+        let inside: HashSet<u64> = self
+            .nodes
+            .iter()
+            .filter(|x| area.contains((x.latitude, x.longitude)))
+            .map(|x| x.id)
+            .collect();
+        self.subnetwork(&inside, keep_boundary)
+    }
+
+    // Intersections at most that many streets away from the seed, whatever their direction
+    pub fn within_hops(&self, seed: u64, hops: usize, keep_boundary: bool) -> Result<NetworkData> {
+        if !self.nodes.iter().any(|x| x.id == seed) {
+            return Err(Error::InvalidInput(format!("no intersection {}", seed)));
+        }
+        let mut neighbours: HashMap<u64, Vec<u64>> = HashMap::new();
+        for x in self.edges.iter() {
+            neighbours.entry(x.start).or_default().push(x.end);
+            neighbours.entry(x.end).or_default().push(x.start);
+        }
+
+        let mut distance: HashMap<u64, usize> = HashMap::from([(seed, 0)]);
+        let mut queue: VecDeque<u64> = VecDeque::from([seed]);
+        while let Some(node) = queue.pop_front() {
+            if distance[&node] == hops {
+                continue;
+            }
+            for next in neighbours.get(&node).into_iter().flatten() {
+                if !distance.contains_key(next) {
+                    distance.insert(*next, distance[&node] + 1);
+                    queue.push_back(*next);
+                }
+            }
+        }
+        Ok(self.subnetwork(&distance.into_keys().collect(), keep_boundary))
+    }
+
+    // Streets between the given intersections. Keeping the boundary also keeps the streets
+    // with one end inside, flagged, along with their outside intersections, so vehicles
+    // can enter and leave the smaller network. Restrictions naming a way with no street
+    // left are dropped.
+    pub fn subnetwork(&self, inside: &HashSet<u64>, keep_boundary: bool) -> NetworkData {
+        let mut boundary: Vec<BoundaryStreet> = Vec::new();
+        let edges: Vec<_> = self
+            .edges
+            .iter()
+            .filter(|x| {
+                let (from, to) = (inside.contains(&x.start), inside.contains(&x.end));
+                if keep_boundary && from != to {
+                    boundary.push(BoundaryStreet {
+                        id: x.id,
+                        start: x.start,
+                        end: x.end,
+                        entering: to,
+                    });
+                }
+                (from && to) || (keep_boundary && (from || to))
+            })
+            .cloned()
+            .collect();
+        let kept: HashSet<u64> = edges.iter().flat_map(|x| [x.start, x.end]).collect();
+        let ways: HashSet<u64> = edges.iter().map(|x| x.id).collect();
+
+        NetworkData {
+            name: self.name.clone(),
+            nodes: self
+                .nodes
+                .iter()
+                .filter(|x| inside.contains(&x.id) || kept.contains(&x.id))
+                .cloned()
+                .collect(),
+            edges,
+            restrictions: self
+                .restrictions
+                .iter()
+                .filter(|r| ways.contains(&r.from) && ways.contains(&r.to))
+                .cloned()
+                .collect(),
+            boundary,
+        }
+    }
+}
+
+mod tests {
+    #[test]
+    fn clip_to_circle_box_polygon_and_hops() {
+        use crate::area::Area;
+        use crate::data_reader::fixtures::{node, one_way};
+        use crate::data_reader::NetworkData;
+
+        // Intersections about 111 m apart along the equator
+        let nw = NetworkData::new(
+            "line".to_string(),
+            vec![node(1, 0.0, 0.0), node(2, 0.0, 0.001), node(3, 0.0, 0.002)],
+            vec![one_way(10, 1, 2), one_way(11, 2, 3)],
+        );
+
+        let circle = Area::Circle {
+            latitude: 0.0,
+            longitude: 0.0,
+            radius: 150.0,
+        };
+        let clipped = nw.clip(&circle, false);
+        assert_eq!(clipped.nodes.len(), 2);
+        assert_eq!(clipped.edges.len(), 1);
+        assert_eq!(clipped.edges[0].id, 10);
+        assert!(clipped.boundary.is_empty());
+
+        let bbox: Area = "-0.001,0.0005,0.001,0.003".parse().unwrap();
+        let clipped = nw.clip(&bbox, false);
+        assert_eq!(clipped.edges.len(), 1);
+        assert_eq!(clipped.edges[0].id, 11);
+        assert!("0.001,0,-0.001,1".parse::<Area>().is_err());
+
+        // A square around the first two intersections with a hole around the first
+        let wkt = Area::from_wkt(
+            "POLYGON ((-0.0005 -0.001, 0.0015 -0.001, 0.0015 0.001, -0.0005 0.001, \
+             -0.0005 -0.001), (-0.0002 -0.0002, 0.0002 -0.0002, 0.0002 0.0002, \
+             -0.0002 0.0002))",
+        )
+        .unwrap();
+        assert!(!wkt.contains((0.0, 0.0)) && wkt.contains((0.0, 0.001)));
+        let geojson = Area::from_geojson(&serde_json::json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "MultiPolygon",
+                "coordinates": [[[[0.0015, -0.001], [0.0025, -0.001], [0.0025, 0.001],
+                                  [0.0015, 0.001]]]]
+            }
+        }))
+        .unwrap();
+        let clipped = nw.clip(&geojson, true);
+        assert_eq!(clipped.edges.len(), 1);
+        assert_eq!(clipped.boundary.len(), 1);
+        assert!(clipped.boundary[0].entering);
+        assert_eq!(clipped.nodes.len(), 2);
+
+        let hops = nw.within_hops(1, 1, true).unwrap();
+        assert_eq!(hops.edges.len(), 2);
+        assert_eq!(
+            (hops.boundary[0].id, hops.boundary[0].entering),
+            (11, false)
+        );
+        assert!(nw.within_hops(4, 1, false).is_err());
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+use crate::markov_chain::{EdgeState, MarkovChain, TransitionMatrix};
+
+// Added to every probability before the KL divergence, so a move one chain allows and the
+// other does not gives a large but finite divergence
+const KL_SMOOTHING: f64 = 1e-9;
+
+type EdgeKey = (u64, u64, u64);
+
+// Values of a street in both chains, NaN on the side it does not exist in
+#[derive(Debug, Serialize, Clone)]
+pub struct EdgeComparison {
+    pub id_osm: u64,
+    pub start: u64,
+    pub end: u64,
+    pub baseline_travel_time: f64,
+    pub other_travel_time: f64,
+    pub baseline_speed: f64,
+    pub other_speed: f64,
+    pub baseline_density: f64,
+    pub other_density: f64,
+    // Sum of absolute differences between the outgoing transition probabilities
+    pub transition_l1: f64,
+    // KL divergence of the other chain's outgoing transitions from the baseline's
+    pub transition_kl: f64,
+}
+
+// Errors of the other chain against the baseline over the streets with both values
+#[derive(Debug, Serialize, Clone)]
+pub struct ErrorSummary {
+    pub quantity: String,
+    pub count: usize,
+    pub mean_difference: f64,
+    pub mae: f64,
+    pub rmse: f64,
+    pub correlation: f64,
+}
+
+impl ErrorSummary {
+    pub fn new(quantity: &str, pairs: &[(f64, f64)]) -> Self {
+        let pairs: Vec<(f64, f64)> = pairs
+            .iter()
+            .copied()
+            .filter(|(a, b)| a.is_finite() && b.is_finite())
+            .collect();
+        let n = pairs.len() as f64;
+        let mean = |f: &dyn Fn(&(f64, f64)) -> f64| pairs.iter().map(f).sum::<f64>() / n;
+
+        let (mean_a, mean_b) = (mean(&|x| x.0), mean(&|x| x.1));
+        let covariance = mean(&|x| (x.0 - mean_a) * (x.1 - mean_b));
+        let (var_a, var_b) = (
+            mean(&|x| (x.0 - mean_a).powi(2)),
+            mean(&|x| (x.1 - mean_b).powi(2)),
+        );
+        ErrorSummary {
+            quantity: quantity.to_string(),
+            count: pairs.len(),
+            mean_difference: mean(&|x| x.1 - x.0),
+            mae: mean(&|x| (x.1 - x.0).abs()),
+            rmse: mean(&|x| (x.1 - x.0).powi(2)).sqrt(),
+            // NaN when either side is constant
+            correlation: covariance / (var_a * var_b).sqrt(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Comparison {
+    pub edges: Vec<EdgeComparison>,
+    pub summaries: Vec<ErrorSummary>,
+    pub matched: usize,
+    pub only_baseline: usize,
+    pub only_other: usize,
+    pub mean_transition_kl: f64,
+    pub max_transition_kl: f64,
+}
+
+// Streets are matched by way and endpoints, ids differ between chains built from
+// different sources or networks
+pub fn compare(baseline: &MarkovChain, other: &MarkovChain) -> Comparison {
+    let key = |x: &EdgeState| (x.id_osm, x.start, x.end);
+    let baseline_states = baseline.edge_states();
+    let other_states = other.edge_states();
+    let other_ids: HashMap<EdgeKey, usize> = other_states
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (key(x), i))
+        .collect();
+
+    let baseline_rows = transition_rows(baseline, &baseline_states);
+    let other_rows = transition_rows(other, &other_states);
+
+    let mut probabilities: Vec<(f64, f64)> = Vec::new();
+    let mut matched: Vec<bool> = vec![false; other_states.len()];
+    let mut edges: Vec<EdgeComparison> = baseline_states
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            let j = other_ids.get(&key(x)).copied();
+            let y = j.map(|j| {
+                matched[j] = true;
+                &other_states[j]
+            });
+            let (transition_l1, transition_kl) = match j {
+                Some(j) => {
+                    let pairs = aligned(&baseline_rows[i], &other_rows[j]);
+                    probabilities.extend(pairs.iter().copied());
+                    (
+                        pairs.iter().map(|(p, q)| (p - q).abs()).sum(),
+                        kl_divergence(&pairs),
+                    )
+                }
+                None => (f64::NAN, f64::NAN),
+            };
+            EdgeComparison {
+                id_osm: x.id_osm,
+                start: x.start,
+                end: x.end,
+                baseline_travel_time: x.travel_time,
+                other_travel_time: y.map_or(f64::NAN, |y| y.travel_time),
+                baseline_speed: x.speed,
+                other_speed: y.map_or(f64::NAN, |y| y.speed),
+                baseline_density: x.density,
+                other_density: y.map_or(f64::NAN, |y| y.density),
+                transition_l1,
+                transition_kl,
+            }
+        })
+        .collect();
+    let only_baseline = edges.len() - matched.iter().filter(|m| **m).count();
+
+    let unmatched: Vec<&EdgeState> = other_states
+        .iter()
+        .zip(matched.iter())
+        .filter(|(_, m)| !**m)
+        .map(|(x, _)| x)
+        .collect();
+    let only_other = unmatched.len();
+    edges.extend(unmatched.into_iter().map(|y| EdgeComparison {
+        id_osm: y.id_osm,
+        start: y.start,
+        end: y.end,
+        baseline_travel_time: f64::NAN,
+        other_travel_time: y.travel_time,
+        baseline_speed: f64::NAN,
+        other_speed: y.speed,
+        baseline_density: f64::NAN,
+        other_density: y.density,
+        transition_l1: f64::NAN,
+        transition_kl: f64::NAN,
+    }));
+
+    let pairs = |f: &dyn Fn(&EdgeComparison) -> (f64, f64)| -> Vec<(f64, f64)> {
+        edges.iter().map(f).collect()
+    };
+    let summaries = vec![
+        ErrorSummary::new(
+            "travel_time",
+            &pairs(&|x| (x.baseline_travel_time, x.other_travel_time)),
+        ),
+        ErrorSummary::new("speed", &pairs(&|x| (x.baseline_speed, x.other_speed))),
+        ErrorSummary::new(
+            "density",
+            &pairs(&|x| (x.baseline_density, x.other_density)),
+        ),
+        ErrorSummary::new("transition_probability", &probabilities),
+    ];
+
+    let divergences: Vec<f64> = edges
+        .iter()
+        .map(|x| x.transition_kl)
+        .filter(|x| x.is_finite())
+        .collect();
+    Comparison {
+        matched: edges.len() - only_baseline - only_other,
+        only_baseline,
+        only_other,
+        mean_transition_kl: divergences.iter().sum::<f64>() / divergences.len() as f64,
+        max_transition_kl: divergences.iter().copied().fold(f64::NAN, f64::max),
+        summaries,
+        edges,
+    }
+}
+
+// Outgoing transitions of every street keyed by the destination street
+fn transition_rows(mkv_chain: &MarkovChain, states: &[EdgeState]) -> Vec<HashMap<EdgeKey, f64>> {
+    let t_mtx = TransitionMatrix::new_from_markov_chain(mkv_chain);
+    (0..t_mtx.dim())
+        .map(|i| {
+            let (cols, values) = t_mtx.row(i);
+            cols.iter()
+                .zip(values.iter())
+                .filter(|(_, p)| p.is_finite())
+                .map(|(j, p)| {
+                    let x = &states[*j];
+                    ((x.id_osm, x.start, x.end), *p)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Both rows over the destinations of either, zero where a chain has no transition
+fn aligned(p: &HashMap<EdgeKey, f64>, q: &HashMap<EdgeKey, f64>) -> Vec<(f64, f64)> {
+    let mut pairs: Vec<(f64, f64)> = p
+        .iter()
+        .map(|(k, v)| (*v, q.get(k).copied().unwrap_or(0.0)))
+        .collect();
+    pairs.extend(
+        q.iter()
+            .filter(|(k, _)| !p.contains_key(*k))
+            .map(|(_, v)| (0.0, *v)),
+    );
+    pairs
+}
+
+// Rows are smoothed and renormalised first, streets without transitions in either
+// chain have no divergence
+fn kl_divergence(pairs: &[(f64, f64)]) -> f64 {
+    let total_p: f64 = pairs.iter().map(|x| x.0 + KL_SMOOTHING).sum();
+    let total_q: f64 = pairs.iter().map(|x| x.1 + KL_SMOOTHING).sum();
+    if pairs.is_empty() {
+        return f64::NAN;
+    }
+    pairs
+        .iter()
+        .map(|(p, q)| {
+            let p = (p + KL_SMOOTHING) / total_p;
+            let q = (q + KL_SMOOTHING) / total_q;
+            p * (p / q).ln()
+        })
+        .sum()
+}
+
+impl Comparison {
+    pub fn summary(&self) -> String {
+        let mut table = String::new();
+        let _ = writeln!(
+            table,
+            "Streets: {} matched, {} only in the baseline, {} only in the other chain",
+            self.matched, self.only_baseline, self.only_other
+        );
+        let _ = writeln!(
+            table,
+            "{:<24}{:>8}{:>14}{:>14}{:>14}{:>14}",
+            "quantity", "count", "mean_diff", "mae", "rmse", "correlation"
+        );
+        for x in self.summaries.iter() {
+            let _ = writeln!(
+                table,
+                "{:<24}{:>8}{:>14.6}{:>14.6}{:>14.6}{:>14.6}",
+                x.quantity, x.count, x.mean_difference, x.mae, x.rmse, x.correlation
+            );
+        }
+        let _ = writeln!(
+            table,
+            "Transition KL divergence: mean {:.6}, max {:.6}",
+            self.mean_transition_kl, self.max_transition_kl
+        );
+        table
+    }
+
+    pub fn save_csv(&self, path: &str) -> Result<()> {
+        let file = File::create(path).map_err(|e| Error::io(path, e))?;
+        let mut file = BufWriter::new(file);
+
+        file.write_all(
+            b"id_osm,start,end,baseline_travel_time,other_travel_time,travel_time_change,\
+              baseline_speed,other_speed,speed_change,baseline_density,other_density,\
+              density_change,transition_l1,transition_kl\n",
+        )
+        .map_err(|e| Error::io(path, e))?;
+        for x in self.edges.iter() {
+            let line = format!(
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+                x.id_osm,
+                x.start,
+                x.end,
+                x.baseline_travel_time,
+                x.other_travel_time,
+                x.other_travel_time - x.baseline_travel_time,
+                x.baseline_speed,
+                x.other_speed,
+                x.other_speed - x.baseline_speed,
+                x.baseline_density,
+                x.other_density,
+                x.other_density - x.baseline_density,
+                x.transition_l1,
+                x.transition_kl
+            );
+            file.write_all(line.as_bytes())
+                .map_err(|e| Error::io(path, e))?;
+        }
+        file.flush().map_err(|e| Error::io(path, e))
+    }
+}
+
+mod tests {
+    #[actix_rt::test]
+    async fn compare_chains() {
+        use crate::data_reader::fixtures::{node, street};
+        use crate::data_reader::NetworkData;
+        use crate::turns::TurnWeights;
+
+        let nw = NetworkData::new(
+            "line".to_string(),
+            vec![node(1, 0.0, 0.0), node(2, 0.0, 0.001), node(3, 0.0, 0.002)],
+            vec![
+                street(10, 2, 1),
+                street(10, 1, 2),
+                street(11, 3, 2),
+                street(11, 2, 3),
+            ],
+        );
+        let build = |nw: NetworkData, options: crate::markov_chain::TransitionOptions| async move {
+            crate::markov_chain::MarkovChain::new_from_network(
+                &crate::traffic_source::OpenStreetMap,
+                nw,
+                &options,
+            )
+            .await
+            .unwrap()
+        };
+        let options = crate::markov_chain::TransitionOptions::default();
+        let baseline = build(nw.clone(), options.clone()).await;
+
+        let same = super::compare(&baseline, &baseline);
+        assert_eq!(
+            (same.matched, same.only_baseline, same.only_other),
+            (4, 0, 0)
+        );
+        // Densities are unknown until a distribution is spread over the chain
+        assert_eq!(same.summaries[0].count, 4);
+        assert_eq!(same.summaries[2].count, 0);
+        assert!(same
+            .summaries
+            .iter()
+            .all(|x| x.count == 0 || (x.mae == 0.0 && x.rmse == 0.0)));
+        assert!(same.max_transition_kl.abs() < 1e-12);
+
+        let u_turns = crate::markov_chain::TransitionOptions {
+            turn_weights: TurnWeights::default(),
+            forbid_u_turns: !options.forbid_u_turns,
+        };
+        let mut shorter = nw.without_way(11);
+        shorter.edges.push(street(12, 2, 3));
+        let other = build(shorter, u_turns).await;
+        let comparison = super::compare(&baseline, &other);
+        assert_eq!(
+            (
+                comparison.matched,
+                comparison.only_baseline,
+                comparison.only_other
+            ),
+            (2, 2, 1)
+        );
+        assert!(comparison.max_transition_kl > 0.0);
+        assert!(comparison.edges[2].transition_kl.is_nan());
+        assert_eq!(comparison.summaries[3].quantity, "transition_probability");
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::HashSet;
+use std::fmt::Write as _;
+
+use serde::Serialize;
+
+use crate::data_reader::{NetworkData, Street};
+use crate::error::{Error, Result};
+use crate::markov_chain::{allowed_turns, TransitionMatrix, TransitionOptions};
+
+const REPORT_MAX_WAYS: usize = 20;
+
+// Which strongly connected component of the street graph to keep
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Component {
+    Largest,
+    // Position when sorted from the largest, 0 is the largest
+    Rank(usize),
+    // The largest component with a street starting or ending at an intersection
+    Containing(u64),
+}
+
+impl std::str::FromStr for Component {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        match (s, s.strip_prefix("node:")) {
+            ("largest", _) => Ok(Component::Largest),
+            (_, Some(id)) => id
+                .parse()
+                .map(Component::Containing)
+                .map_err(|_| format!("bad intersection id '{}'", id)),
+            _ => s
+                .parse()
+                .map(Component::Rank)
+                .map_err(|_| format!("expected largest, a rank or node:<id>, got '{}'", s)),
+        }
+    }
+}
+
+// What filtering a network down to one component removed
+#[derive(Debug, Serialize, Clone)]
+pub struct ComponentReport {
+    // Streets in each component, largest first
+    pub sizes: Vec<usize>,
+    pub kept_nodes: usize,
+    pub kept_streets: usize,
+    pub dropped_nodes: Vec<u64>,
+    // (way id, start, end) of every street removed
+    pub dropped_streets: Vec<(u64, u64, u64)>,
+}
+
+impl ComponentReport {
+    pub fn report(&self) -> String {
+        let mut ways: Vec<u64> = self.dropped_streets.iter().map(|x| x.0).collect();
+        ways.sort_unstable();
+        ways.dedup();
+
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "Strongly connected components: {} (largest {})",
+            self.sizes.len(),
+            self.sizes.first().copied().unwrap_or(0)
+        );
+        let _ = writeln!(
+            report,
+            "Kept {} intersections and {} streets, dropped {} intersections and {} streets",
+            self.kept_nodes,
+            self.kept_streets,
+            self.dropped_nodes.len(),
+            self.dropped_streets.len()
+        );
+        if !ways.is_empty() {
+            let mut listed: Vec<String> = ways
+                .iter()
+                .take(REPORT_MAX_WAYS)
+                .map(|x| x.to_string())
+                .collect();
+            if ways.len() > REPORT_MAX_WAYS {
+                listed.push(format!("{} more", ways.len() - REPORT_MAX_WAYS));
+            }
+            let _ = writeln!(report, "Ways losing streets: {}", listed.join(", "));
+        }
+        report
+    }
+}
+
+impl NetworkData {
+    // Components of the directed graph of streets the chain moves over, a street leading
+    // to another when the chain may turn onto it, so restrictions and a U-turn ban split
+    // components too. Streets are positions in edges, largest first, ties broken by the
+    // first street.
+    pub fn strongly_connected_components(
+        &self,
+        options: &TransitionOptions,
+    ) -> Result<Vec<Vec<usize>>> {
+        let turns = allowed_turns(self, options)?;
+        // Only the pattern of the matrix matters to Tarjan's algorithm, turns of no weight
+        // are never taken
+        let graph = TransitionMatrix::new(
+            self.edges.len(),
+            turns
+                .iter()
+                .enumerate()
+                .flat_map(|(i, turns)| {
+                    turns
+                        .iter()
+                        .filter(|(_, turn)| options.turn_weights.weight(*turn) > 0.0)
+                        .map(move |(j, _)| (i, *j, 1.0))
+                })
+                .collect(),
+        );
+        let mut components: Vec<Vec<usize>> = graph
+            .strongly_connected_components()
+            .into_iter()
+            .map(|mut c| {
+                c.sort_unstable();
+                c
+            })
+            .collect();
+        components.sort_by(|a, b| b.len().cmp(&a.len()).then(a[0].cmp(&b[0])));
+        Ok(components)
+    }
+
+    // The chosen component's streets, the intersections they touch and the restrictions
+    // and boundary flags left on them
+    pub fn keep_component(
+        &self,
+        component: Component,
+        options: &TransitionOptions,
+    ) -> Result<(NetworkData, ComponentReport)> {
+        let components = self.strongly_connected_components(options)?;
+        let touches = |c: &&Vec<usize>, id: u64| {
+            c.iter()
+                .any(|i| self.edges[*i].start == id || self.edges[*i].end == id)
+        };
+        let kept = match component {
+            Component::Largest => components.first(),
+            Component::Rank(rank) => components.get(rank),
+            Component::Containing(id) => components.iter().find(|c| touches(c, id)),
+        }
+        .ok_or_else(|| {
+            Error::InvalidInput(match component {
+                Component::Largest => "the network has no streets".to_string(),
+                Component::Rank(rank) => {
+                    format!("no component {} of {}", rank, components.len())
+                }
+                Component::Containing(id) => format!("no street at intersection {}", id),
+            })
+        })?;
+
+        let inside: HashSet<usize> = kept.iter().copied().collect();
+        let edges: Vec<Street> = kept.iter().map(|i| self.edges[*i].clone()).collect();
+        let nodes: HashSet<u64> = edges.iter().flat_map(|x| [x.start, x.end]).collect();
+        let ways: HashSet<u64> = edges.iter().map(|x| x.id).collect();
+        let nw = NetworkData {
+            name: self.name.clone(),
+            nodes: self
+                .nodes
+                .iter()
+                .filter(|x| nodes.contains(&x.id))
+                .cloned()
+                .collect(),
+            restrictions: self
+                .restrictions
+                .iter()
+                .filter(|r| ways.contains(&r.from) && ways.contains(&r.to))
+                .cloned()
+                .collect(),
+            boundary: self
+                .boundary
+                .iter()
+                .filter(|b| {
+                    edges
+                        .iter()
+                        .any(|x| (x.id, x.start, x.end) == (b.id, b.start, b.end))
+                })
+                .cloned()
+                .collect(),
+            edges,
+        };
+
+        let report = ComponentReport {
+            sizes: components.iter().map(|c| c.len()).collect(),
+            kept_nodes: nw.nodes.len(),
+            kept_streets: nw.edges.len(),
+            dropped_nodes: self
+                .nodes
+                .iter()
+                .filter(|x| !nodes.contains(&x.id))
+                .map(|x| x.id)
+                .collect(),
+            dropped_streets: self
+                .edges
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !inside.contains(i))
+                .map(|(_, x)| (x.id, x.start, x.end))
+                .collect(),
+        };
+        Ok((nw, report))
+    }
+}
+
+mod tests {
+    #[test]
+    fn keep_largest_component() {
+        use crate::connectivity::Component;
+        use crate::data_reader::fixtures::{node, street};
+        use crate::data_reader::{NetworkData, RestrictionKind, TurnRestriction};
+        use crate::markov_chain::TransitionOptions;
+
+        // Two-way 1 - 2 - 3, a one-way trap from 3 to 4 and a two-way island 5 - 6
+        let mut nw = NetworkData::new(
+            "islands".to_string(),
+            (1..=6).map(|id| node(id, 0.0, id as f64 * 0.001)).collect(),
+            vec![
+                street(10, 2, 1),
+                street(10, 1, 2),
+                street(11, 3, 2),
+                street(11, 2, 3),
+                street(12, 3, 4),
+                street(13, 6, 5),
+                street(13, 5, 6),
+            ],
+        );
+        nw.edges[4].oneway = true;
+
+        let options = TransitionOptions::default();
+        // Streets are positions in edges, U-turns at the dead ends keep 1 - 2 - 3 together
+        let components = nw.strongly_connected_components(&options).unwrap();
+        assert_eq!(components, vec![vec![0, 1, 2, 3], vec![5, 6], vec![4]]);
+
+        let (largest, report) = nw.keep_component(Component::Largest, &options).unwrap();
+        assert_eq!(largest.edges.len(), 4);
+        assert_eq!(report.sizes, vec![4, 2, 1]);
+        assert_eq!(report.dropped_nodes, vec![4, 5, 6]);
+        assert_eq!(report.dropped_streets[0], (12, 3, 4));
+        assert!(report.report().contains("Ways losing streets: 12, 13"));
+
+        let (island, _) = nw
+            .keep_component("node:6".parse().unwrap(), &options)
+            .unwrap();
+        assert_eq!(island.nodes.len(), 2);
+        assert_eq!("1".parse::<Component>(), Ok(Component::Rank(1)));
+        assert!(nw.keep_component(Component::Rank(3), &options).is_err());
+
+        // No U-turn at 1 leaves 2 -> 1 a dead end and 1 -> 2 unreachable
+        nw.restrictions = vec![TurnRestriction {
+            from: 10,
+            via: 1,
+            to: 10,
+            restriction: RestrictionKind::NoUTurn,
+        }];
+        let (largest, report) = nw.keep_component(Component::Largest, &options).unwrap();
+        assert_eq!(largest.edges.len(), 2);
+        assert!(largest.edges.iter().all(|x| x.id == 11));
+        assert!(largest.restrictions.is_empty());
+        assert_eq!(report.sizes[0], 2);
+
+        // Without U-turns every street on a line, the island too, is its own component
+        nw.restrictions.clear();
+        let banned = TransitionOptions {
+            forbid_u_turns: true,
+            ..Default::default()
+        };
+        let components = nw.strongly_connected_components(&banned).unwrap();
+        assert_eq!(components.len(), 7);
+        assert!(components.iter().all(|c| c.len() == 1));
+    }
+}
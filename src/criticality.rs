@@ -0,0 +1,307 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+use serde::Serialize;
+
+use crate::data_reader::NetworkData;
+use crate::error::{Error, Result};
+use crate::markov_chain::{MarkovChain, TransitionMatrix, TransitionOptions};
+use crate::traffic_source::TrafficSource;
+
+// State of the network a closure is measured against
+#[derive(Debug, Serialize, Clone)]
+pub struct NetworkMetrics {
+    // Sum of the stationary densities of every street
+    pub total_density: f64,
+    // None when the chain is not irreducible or too large for a dense solve
+    pub kemeny_constant: Option<f64>,
+    // Why the Kemeny constant is missing
+    pub kemeny_error: Option<String>,
+    // Share of the streets in the largest communicating class
+    pub reachability: f64,
+}
+
+impl NetworkMetrics {
+    // Densities are set on the chain as a side effect. The Kemeny constant is a dense solve,
+    // skipped unless asked for, which fails past DENSE_SOLVE_MAX_DIM streets.
+    pub fn measure(
+        mkv_chain: &mut MarkovChain,
+        vehicle_count: u64,
+        tolerance: f64,
+        max_iterations: usize,
+        kemeny: bool,
+    ) -> Self {
+        let t_mtx = TransitionMatrix::new_from_markov_chain(mkv_chain);
+        let stationary = t_mtx.stationary_distribution(tolerance, max_iterations);
+        mkv_chain.calculate_density_from_stationary(&stationary, Some(vehicle_count));
+
+        let largest_class = t_mtx
+            .strongly_connected_components()
+            .iter()
+            .map(|c| c.len())
+            .max()
+            .unwrap_or(0);
+        let kemeny_constant = match kemeny {
+            true => t_mtx.kemeny_constant().map_err(|e| e.to_string()),
+            false => Err("not computed".to_string()),
+        };
+
+        NetworkMetrics {
+            total_density: mkv_chain
+                .densities()
+                .iter()
+                .map(|x| x.density)
+                .filter(|d| d.is_finite())
+                .sum(),
+            kemeny_constant: kemeny_constant.clone().ok(),
+            kemeny_error: kemeny_constant.err(),
+            reachability: largest_class as f64 / t_mtx.dim().max(1) as f64,
+        }
+    }
+}
+
+// Closing a way, measured as the metrics without it minus the baseline metrics
+#[derive(Debug, Serialize, Clone)]
+pub struct ClosureImpact {
+    pub rank: usize,
+    pub id_osm: u64,
+    pub total_density_change: f64,
+    // None when either chain has no Kemeny constant
+    pub kemeny_change: Option<f64>,
+    pub reachability_change: f64,
+}
+
+// Rebuilds the network without each way in turn, every way when ways is empty. The
+// traffic source is asked once, for the baseline, and its flows price every closure. The
+// same vehicle count is spread over every network so densities compare. Closures are
+// ranked by lost reachability, then by how much the Kemeny constant and the total density
+// move. Without a baseline Kemeny constant, see NetworkMetrics::kemeny_error, closures are
+// ranked without it.
+pub async fn rank_closures(
+    traffic_source: &dyn TrafficSource,
+    network_graph: &NetworkData,
+    options: &TransitionOptions,
+    ways: &[u64],
+    tolerance: f64,
+    max_iterations: usize,
+) -> Result<(NetworkMetrics, Vec<ClosureImpact>)> {
+    let mut ways = match ways.is_empty() {
+        true => network_graph.edges.iter().map(|x| x.id).collect(),
+        false => ways.to_vec(),
+    };
+    ways.sort_unstable();
+    ways.dedup();
+    if let Some(way) = ways
+        .iter()
+        .find(|way| !network_graph.edges.iter().any(|x| x.id == **way))
+    {
+        return Err(Error::InvalidInput(format!(
+            "no street with way id {}",
+            way
+        )));
+    }
+
+    // Kept unmeasured, closures take their traffic from it
+    let topology = MarkovChain::topology(network_graph.clone(), options)?;
+    let priced = topology.with_traffic_at(traffic_source, None).await?;
+    let mut baseline_chain = topology.with_traffic_of(&priced)?;
+    let vehicle_count = baseline_chain.estimate_vehicle_count();
+    let baseline = NetworkMetrics::measure(
+        &mut baseline_chain,
+        vehicle_count,
+        tolerance,
+        max_iterations,
+        true,
+    );
+    let kemeny = baseline.kemeny_constant.is_some();
+
+    let mut impacts = Vec::with_capacity(ways.len());
+    for way in ways {
+        let mut mkv_chain = MarkovChain::topology(network_graph.without_way(way), options)?
+            .with_traffic_of(&priced)?;
+        let closed = NetworkMetrics::measure(
+            &mut mkv_chain,
+            vehicle_count,
+            tolerance,
+            max_iterations,
+            kemeny,
+        );
+        impacts.push(ClosureImpact {
+            rank: 0,
+            id_osm: way,
+            total_density_change: closed.total_density - baseline.total_density,
+            kemeny_change: closed
+                .kemeny_constant
+                .zip(baseline.kemeny_constant)
+                .map(|(closed, baseline)| closed - baseline),
+            reachability_change: closed.reachability - baseline.reachability,
+        });
+    }
+
+    // Missing and NaN changes sort last
+    let magnitude = |x: f64| if x.is_nan() { -1.0 } else { x.abs() };
+    let kemeny = |x: &ClosureImpact| magnitude(x.kemeny_change.unwrap_or(f64::NAN));
+    impacts.sort_by(|a, b| {
+        a.reachability_change
+            .total_cmp(&b.reachability_change)
+            .then(kemeny(b).total_cmp(&kemeny(a)))
+            .then(magnitude(b.total_density_change).total_cmp(&magnitude(a.total_density_change)))
+    });
+    impacts
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, x)| x.rank = i + 1);
+    Ok((baseline, impacts))
+}
+
+pub fn save_csv(impacts: &[ClosureImpact], path: &str) -> Result<()> {
+    let file = File::create(path).map_err(|e| Error::io(path, e))?;
+    let mut file = BufWriter::new(file);
+
+    file.write_all(b"rank,id_osm,total_density_change,kemeny_change,reachability_change\n")
+        .map_err(|e| Error::io(path, e))?;
+    for x in impacts {
+        let line = format!(
+            "{},{},{},{},{}\n",
+            x.rank,
+            x.id_osm,
+            x.total_density_change,
+            x.kemeny_change.map_or(String::new(), |x| x.to_string()),
+            x.reachability_change
+        );
+        file.write_all(line.as_bytes())
+            .map_err(|e| Error::io(path, e))?;
+    }
+    file.flush().map_err(|e| Error::io(path, e))
+}
+
+// The chain's GeoJSON with the ranking added to the properties of every street of a way
+pub fn to_geojson(mkv_chain: &MarkovChain, impacts: &[ClosureImpact]) -> serde_json::Value {
+    let mut geojson = mkv_chain.to_geojson();
+    if let Some(features) = geojson["features"].as_array_mut() {
+        for feature in features.iter_mut() {
+            let id_osm = feature["properties"]["osm_id"].as_u64();
+            let impact = impacts.iter().find(|x| Some(x.id_osm) == id_osm);
+            if let (Some(properties), Some(impact)) =
+                (feature["properties"].as_object_mut(), impact)
+            {
+                properties.insert("closure_rank".to_string(), impact.rank.into());
+                properties.insert(
+                    "closure_density_change".to_string(),
+                    impact.total_density_change.into(),
+                );
+                properties.insert(
+                    "closure_kemeny_change".to_string(),
+                    impact.kemeny_change.into(),
+                );
+                properties.insert(
+                    "closure_reachability_change".to_string(),
+                    impact.reachability_change.into(),
+                );
+            }
+        }
+    }
+    geojson
+}
+
+pub fn save_geojson(mkv_chain: &MarkovChain, impacts: &[ClosureImpact], path: &str) -> Result<()> {
+    let output_str: String = serde_json::to_string(&to_geojson(mkv_chain, impacts))?;
+    fs::write(path, output_str).map_err(|e| Error::io(path, e))
+}
+
+mod tests {
+    #[actix_rt::test]
+    async fn ring_closure_ranks_first() {
+        use crate::data_reader::fixtures::{node, one_way, street};
+        use crate::data_reader::{NetworkData, RestrictionKind, TurnRestriction};
+        use crate::markov_chain::{MarkovChain, TransitionOptions};
+
+        // A one-way ring 1 -> 2 -> 3 -> 1 with a two-way spur from 3 to 4
+        let nw = NetworkData::new(
+            "ring".to_string(),
+            vec![
+                node(1, 0.0, 0.0),
+                node(2, 0.001, 0.0),
+                node(3, 0.0, 0.001),
+                node(4, -0.001, 0.001),
+            ],
+            vec![
+                one_way(10, 1, 2),
+                one_way(11, 2, 3),
+                one_way(12, 3, 1),
+                street(13, 3, 4),
+                street(13, 4, 3),
+            ],
+        );
+
+        let (baseline, impacts) = super::rank_closures(
+            &crate::traffic_source::OpenStreetMap,
+            &nw,
+            &crate::markov_chain::TransitionOptions::default(),
+            &[],
+            1e-10,
+            100_000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(baseline.reachability, 1.0);
+        assert!(baseline.kemeny_constant.is_some_and(f64::is_finite));
+        assert_eq!(impacts.len(), 4);
+        // Closing the spur keeps the ring strongly connected, closing the ring does not
+        assert_eq!(impacts[3].id_osm, 13);
+        assert_eq!(impacts[3].reachability_change, 0.0);
+        assert!(impacts[0].reachability_change < 0.0);
+        assert_eq!(impacts[0].rank, 1);
+
+        let mkv_chain = crate::markov_chain::MarkovChain::new_from_network(
+            &crate::traffic_source::OpenStreetMap,
+            nw.clone(),
+            &crate::markov_chain::TransitionOptions::default(),
+        )
+        .await
+        .unwrap();
+        let geojson = super::to_geojson(&mkv_chain, &impacts);
+        assert_eq!(geojson["features"][3]["properties"]["closure_rank"], 4);
+
+        // Closing the spur frees the turns an "only" restriction onto it blocked, so the
+        // closure is measured on the network rebuilt without it
+        let mut nw = nw;
+        nw.restrictions = vec![TurnRestriction {
+            from: 11,
+            via: 3,
+            to: 13,
+            restriction: RestrictionKind::OnlyStraightOn,
+        }];
+        let (baseline, impacts) = super::rank_closures(
+            &crate::traffic_source::OpenStreetMap,
+            &nw,
+            &TransitionOptions::default(),
+            &[13],
+            1e-10,
+            100_000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(impacts[0].reachability_change, 0.0);
+
+        let vehicle_count = MarkovChain::new_from_network(
+            &crate::traffic_source::OpenStreetMap,
+            nw.clone(),
+            &TransitionOptions::default(),
+        )
+        .await
+        .unwrap()
+        .estimate_vehicle_count();
+        let mut rebuilt = MarkovChain::new_from_network(
+            &crate::traffic_source::OpenStreetMap,
+            nw.without_way(13),
+            &TransitionOptions::default(),
+        )
+        .await
+        .unwrap();
+        let closed =
+            super::NetworkMetrics::measure(&mut rebuilt, vehicle_count, 1e-10, 100_000, false);
+        let change = closed.total_density - baseline.total_density;
+        assert!((impacts[0].total_density_change - change).abs() < 1e-12);
+    }
+}
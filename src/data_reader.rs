@@ -4,12 +4,43 @@
 - Process intersection and street data into network graph
 - Process network graph into markov chain graph
 */
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
 
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+use crate::error::{Error, Result};
+use crate::osm_extract::{OsmExtract, OsmMemberType, OsmRelation, OsmWay};
+
+// Highway values excluded by osmnx's network_type='drive' filter
+const NON_DRIVABLE_HIGHWAYS: [&str; 20] = [
+    "abandoned",
+    "bridleway",
+    "bus_guideway",
+    "construction",
+    "corridor",
+    "cycleway",
+    "elevator",
+    "escalator",
+    "footway",
+    "no",
+    "path",
+    "pedestrian",
+    "planned",
+    "platform",
+    "proposed",
+    "raceway",
+    "razed",
+    "service",
+    "steps",
+    "track",
+];
+
+pub(crate) const EARTH_RADIUS_M: f64 = 6_371_009.0;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Intersection {
     pub id: u64,
@@ -29,45 +60,502 @@ pub struct Street {
     pub highway: String,
 }
 
-#[derive(Debug)]
+// Networks for the tests of every module are built from these, residential streets of one
+// lane, 100 m long at 50 km/h. Tests needing other values override the fields they rely on.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::{Intersection, Street};
+
+    pub fn node(id: u64, latitude: f64, longitude: f64) -> Intersection {
+        Intersection {
+            id,
+            latitude,
+            longitude,
+        }
+    }
+
+    pub fn street(id: u64, start: u64, end: u64) -> Street {
+        Street {
+            id,
+            start,
+            end,
+            lanes: 1.0,
+            maxspeed: 50,
+            length: 100.0,
+            oneway: false,
+            highway: "residential".to_string(),
+        }
+    }
+
+    pub fn one_way(id: u64, start: u64, end: u64) -> Street {
+        Street {
+            oneway: true,
+            ..street(id, start, end)
+        }
+    }
+}
+
+// Street crossing the edge of a clipped network, its outside intersection is kept too
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BoundaryStreet {
+    pub id: u64,
+    pub start: u64,
+    pub end: u64,
+    // Leaving the area otherwise
+    pub entering: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RestrictionKind {
+    NoLeftTurn,
+    NoRightTurn,
+    NoStraightOn,
+    NoUTurn,
+    NoEntry,
+    NoExit,
+    OnlyLeftTurn,
+    OnlyRightTurn,
+    OnlyStraightOn,
+    OnlyUTurn,
+}
+
+impl RestrictionKind {
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "no_left_turn" => Some(RestrictionKind::NoLeftTurn),
+            "no_right_turn" => Some(RestrictionKind::NoRightTurn),
+            "no_straight_on" => Some(RestrictionKind::NoStraightOn),
+            "no_u_turn" => Some(RestrictionKind::NoUTurn),
+            "no_entry" => Some(RestrictionKind::NoEntry),
+            "no_exit" => Some(RestrictionKind::NoExit),
+            "only_left_turn" => Some(RestrictionKind::OnlyLeftTurn),
+            "only_right_turn" => Some(RestrictionKind::OnlyRightTurn),
+            "only_straight_on" => Some(RestrictionKind::OnlyStraightOn),
+            "only_u_turn" => Some(RestrictionKind::OnlyUTurn),
+            _ => None,
+        }
+    }
+
+    // Mandatory restrictions forbid every manoeuvre from the via node except the one listed
+    pub fn is_mandatory(&self) -> bool {
+        matches!(
+            self,
+            RestrictionKind::OnlyLeftTurn
+                | RestrictionKind::OnlyRightTurn
+                | RestrictionKind::OnlyStraightOn
+                | RestrictionKind::OnlyUTurn
+        )
+    }
+}
+
+// Restriction on moving from one OSM way to another through a via node
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TurnRestriction {
+    pub from: u64,
+    pub via: u64,
+    pub to: u64,
+    pub restriction: RestrictionKind,
+}
+
+impl TurnRestriction {
+    pub fn allows(&self, from: u64, via: u64, to: u64) -> bool {
+        if self.from != from || self.via != via {
+            return true;
+        }
+        match self.restriction.is_mandatory() {
+            true => self.to == to,
+            false => self.to != to,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct NetworkData {
     pub name: String,
     pub nodes: Vec<Intersection>,
     pub edges: Vec<Street>,
+    pub restrictions: Vec<TurnRestriction>,
+    // Empty unless the network was clipped keeping the streets crossing its edge
+    pub boundary: Vec<BoundaryStreet>,
 }
 
 impl NetworkData {
     pub fn new(name: String, nodes: Vec<Intersection>, edges: Vec<Street>) -> Self {
-        NetworkData { name, nodes, edges }
+        NetworkData {
+            name,
+            nodes,
+            edges,
+            restrictions: Vec::new(),
+            boundary: Vec::new(),
+        }
+    }
+
+    // restrictions.json is optional, the Python tool does not export it
+    pub fn new_from_file(name: String, files_location: String) -> Result<Self> {
+        let nodes: Vec<Intersection> = read_json_records(&format!("{files_location}/nodes.json"))?;
+        let edges: Vec<Street> = read_json_records(&format!("{files_location}/edges.json"))?;
+        let restrictions_filename = format!("{files_location}/restrictions.json");
+        let restrictions: Vec<TurnRestriction> = match Path::new(&restrictions_filename).exists() {
+            true => read_json_records(&restrictions_filename)?,
+            false => Vec::new(),
+        };
+
+        Ok(NetworkData {
+            name,
+            nodes,
+            edges,
+            restrictions,
+            boundary: Vec::new(),
+        })
+    }
+
+    pub fn turn_allowed(&self, from: u64, via: u64, to: u64) -> bool {
+        self.restrictions.iter().all(|r| r.allows(from, via, to))
+    }
+
+    // Copy with every street of an OSM way removed, as if the road were closed. Restrictions
+    // naming the way go too, an "only" turn onto a closed road would block the junction.
+    pub fn without_way(&self, id_osm: u64) -> NetworkData {
+        NetworkData {
+            name: self.name.clone(),
+            nodes: self.nodes.clone(),
+            edges: self
+                .edges
+                .iter()
+                .filter(|x| x.id != id_osm)
+                .cloned()
+                .collect(),
+            restrictions: self
+                .restrictions
+                .iter()
+                .filter(|r| r.from != id_osm && r.to != id_osm)
+                .cloned()
+                .collect(),
+            boundary: self
+                .boundary
+                .iter()
+                .filter(|x| x.id != id_osm)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn new_from_osm_extract(name: String, extract_path: String) -> Result<Self> {
+        Ok(NetworkData::new_from_osm(
+            name,
+            OsmExtract::new_from_file(&extract_path)?,
+        ))
+    }
+
+    pub fn new_from_osm(name: String, extract: OsmExtract) -> Self {
+        let ways: Vec<&OsmWay> = extract.ways.iter().filter(|w| is_drivable(w)).collect();
+
+        // Ways are split wherever they touch another way, as osmnx's simplification does
+        let mut node_usage: HashMap<u64, usize> = HashMap::new();
+        for way in ways.iter() {
+            for id in way.nodes.iter() {
+                *node_usage.entry(*id).or_insert(0) += 1;
+            }
+        }
+
+        let mut nodes: HashMap<u64, Intersection> = HashMap::new();
+        let mut edges: Vec<Street> = Vec::new();
+
+        for way in ways {
+            let highway = way.tag("highway").unwrap_or_default().to_string();
+            let lanes = parse_lanes(way.tag("lanes"));
+            let maxspeed = parse_maxspeed(way.tag("maxspeed"), &highway);
+            let (oneway, reversed) = parse_oneway(way);
+
+            // Node references missing from a clipped extract break the way into pieces
+            let pieces = way
+                .nodes
+                .split(|id| !extract.nodes.contains_key(id))
+                .filter(|p| p.len() > 1);
+
+            for piece in pieces {
+                let mut start = piece[0];
+                let mut length = 0.0;
+                for (i, pair) in piece.windows(2).enumerate() {
+                    length += haversine(extract.nodes[&pair[0]], extract.nodes[&pair[1]]);
+                    let end = pair[1];
+                    if i + 2 < piece.len() && node_usage[&end] < 2 {
+                        continue;
+                    }
+
+                    for id in [start, end] {
+                        let (latitude, longitude) = extract.nodes[&id];
+                        nodes.entry(id).or_insert(Intersection {
+                            id,
+                            latitude,
+                            longitude,
+                        });
+                    }
+
+                    let street = Street {
+                        id: way.id,
+                        start,
+                        end,
+                        lanes,
+                        maxspeed,
+                        length,
+                        oneway,
+                        highway: highway.clone(),
+                    };
+                    if !oneway {
+                        edges.push(Street {
+                            start: end,
+                            end: start,
+                            ..street.clone()
+                        });
+                        edges.push(street);
+                    } else if reversed {
+                        edges.push(Street {
+                            start: end,
+                            end: start,
+                            ..street
+                        });
+                    } else {
+                        edges.push(street);
+                    }
+
+                    start = end;
+                    length = 0.0;
+                }
+            }
+        }
+
+        let mut nodes: Vec<Intersection> = nodes.into_values().collect();
+        nodes.sort_by_key(|x| x.id);
+
+        let restrictions = extract
+            .relations
+            .iter()
+            .flat_map(turn_restrictions)
+            .collect();
+
+        NetworkData {
+            name,
+            nodes,
+            edges,
+            restrictions,
+            boundary: Vec::new(),
+        }
+    }
+}
+
+// Only restrictions through a single via node are supported, via ways are dropped
+fn turn_restrictions(relation: &OsmRelation) -> Vec<TurnRestriction> {
+    if relation.tag("type") != Some("restriction") {
+        return Vec::new();
+    }
+    let exempt = relation
+        .tag("except")
+        .is_some_and(|x| x.split(';').any(|v| v.trim() == "motorcar"));
+    let restriction = relation
+        .tag("restriction:motorcar")
+        .or(relation.tag("restriction"))
+        .and_then(RestrictionKind::from_tag);
+    let via = relation.members(OsmMemberType::Node, "via");
+    let (Some(restriction), [via], false) = (restriction, via.as_slice(), exempt) else {
+        return Vec::new();
+    };
+
+    let to = relation.members(OsmMemberType::Way, "to");
+    relation
+        .members(OsmMemberType::Way, "from")
+        .into_iter()
+        .flat_map(|from| {
+            to.iter().map(move |to| TurnRestriction {
+                from,
+                via: *via,
+                to: *to,
+                restriction,
+            })
+        })
+        .collect()
+}
+
+fn read_json_records<T: DeserializeOwned>(filename: &str) -> Result<Vec<T>> {
+    let file = File::open(Path::new(filename)).map_err(|e| Error::io(filename, e))?;
+    let reader = BufReader::new(file);
+    let records: Vec<serde_json::Value> =
+        serde_json::from_reader(reader).map_err(|e| Error::parse(filename, None, e))?;
+
+    records
+        .into_iter()
+        .enumerate()
+        .map(|(i, x)| serde_json::from_value(x).map_err(|e| Error::parse(filename, Some(i), e)))
+        .collect()
+}
+
+fn is_drivable(way: &OsmWay) -> bool {
+    let highway = match way.tag("highway") {
+        Some(h) => h,
+        None => return false,
+    };
+    if NON_DRIVABLE_HIGHWAYS.contains(&highway) || way.nodes.len() < 2 {
+        return false;
+    }
+    let denied = [
+        ("area", "yes"),
+        ("access", "private"),
+        ("motor_vehicle", "no"),
+        ("motorcar", "no"),
+    ];
+    if denied.iter().any(|(k, v)| way.tag(k) == Some(*v)) {
+        return false;
+    }
+    !matches!(
+        way.tag("service"),
+        Some("alley" | "driveway" | "emergency_access" | "parking" | "parking_aisle" | "private")
+    )
+}
+
+fn parse_oneway(way: &OsmWay) -> (bool, bool) {
+    match way.tag("oneway") {
+        Some("yes" | "true" | "1") => (true, false),
+        Some("-1" | "reverse") => (true, true),
+        _ => (way.tag("junction") == Some("roundabout"), false),
     }
+}
+
+fn parse_lanes(tag: Option<&str>) -> f64 {
+    tag.unwrap_or_default()
+        .split(';')
+        .filter_map(|x| x.trim().parse::<f64>().ok())
+        .filter(|x| *x > 0.0)
+        .reduce(f64::min)
+        .unwrap_or(1.0)
+}
 
-    pub fn new_from_file(name: String, files_location: String) -> Self {
-        let node_filename = format!("{files_location}/nodes.json");
-        let path = Path::new(&node_filename);
-        let file = File::open(path).unwrap();
-        let reader = BufReader::new(file);
-        let nodes: Vec<Intersection> =
-            serde_json::from_reader(reader).expect("Nodes JSON was not well-formatted");
+fn parse_maxspeed(tag: Option<&str>, highway: &str) -> u8 {
+    let speed = tag
+        .unwrap_or_default()
+        .split(';')
+        .filter_map(|x| {
+            let x = x.trim();
+            let value = x.split_whitespace().next()?.parse::<f64>().ok()?;
+            match x.ends_with("mph") {
+                true => Some(value * 1.609344),
+                false => Some(value),
+            }
+        })
+        .filter(|x| *x > 0.0)
+        .reduce(f64::min);
 
-        let edge_filename = format!("{files_location}/edges.json");
-        let path = Path::new(&edge_filename);
-        let file = File::open(path).unwrap();
-        let reader = BufReader::new(file);
-        let edges: Vec<Street> =
-            serde_json::from_reader(reader).expect("Edges JSON was not well-formatted");
+    match speed {
+        Some(v) => v.round().min(u8::MAX as f64) as u8,
+        None => default_maxspeed(highway),
+    }
+}
 
-        NetworkData { name, nodes, edges }
+// Limites para vias urbanas (CTB): trânsito rápido 80, arterial 60, coletora 40, local 30
+fn default_maxspeed(highway: &str) -> u8 {
+    match highway.trim_end_matches("_link") {
+        "motorway" | "trunk" => 80,
+        "primary" => 60,
+        "secondary" | "tertiary" => 40,
+        _ => 30,
     }
 }
 
+pub(crate) fn haversine(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lat1, lon1) = (from.0.to_radians(), from.1.to_radians());
+    let (lat2, lon2) = (to.0.to_radians(), to.1.to_radians());
+    let h = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * h.sqrt().asin()
+}
+
 mod tests {
 
     #[test]
+    #[cfg_attr(
+        not(feature = "osm-tool-tests"),
+        ignore = "downloads through osm_tool, run with --features osm-tool-tests"
+    )]
     fn read_output_from_osm() {
-        crate::osm::get_data_from_place("jose_mendes", "José Mendes, Florianópolis");
+        crate::osm::get_data_from_place("jose_mendes", "José Mendes, Florianópolis").unwrap();
         let _ = super::NetworkData::new_from_file(
             "jose_mendes".to_string(),
             "output/jose_mendes".to_string(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn read_network_from_osm_xml() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6">
+  <node id="1" lat="-27.6000" lon="-48.5000"/>
+  <node id="2" lat="-27.6010" lon="-48.5000"/>
+  <node id="3" lat="-27.6020" lon="-48.5000"/>
+  <node id="4" lat="-27.6010" lon="-48.5010"/>
+  <node id="5" lat="-27.6010" lon="-48.4990"/>
+  <way id="10">
+    <nd ref="1"/><nd ref="2"/><nd ref="3"/>
+    <tag k="highway" v="residential"/>
+    <tag k="lanes" v="2"/>
+  </way>
+  <way id="11">
+    <nd ref="4"/><nd ref="2"/>
+    <tag k="highway" v="primary"/>
+    <tag k="oneway" v="yes"/>
+    <tag k="maxspeed" v="50"/>
+  </way>
+  <way id="12">
+    <nd ref="2"/><nd ref="5"/>
+    <tag k="highway" v="footway"/>
+  </way>
+  <relation id="20">
+    <member type="way" ref="11" role="from"/>
+    <member type="node" ref="2" role="via"/>
+    <member type="way" ref="10" role="to"/>
+    <tag k="type" v="restriction"/>
+    <tag k="restriction" v="only_right_turn"/>
+  </relation>
+</osm>"#;
+        let extract = crate::osm_extract::OsmExtract::new_from_xml(xml.as_bytes()).unwrap();
+        let nw = super::NetworkData::new_from_osm("test".to_string(), extract);
+
+        assert_eq!(nw.nodes.len(), 4);
+        assert_eq!(nw.edges.len(), 5);
+        let way_11: Vec<&super::Street> = nw.edges.iter().filter(|x| x.id == 11).collect();
+        assert_eq!(way_11.len(), 1);
+        assert_eq!((way_11[0].start, way_11[0].end), (4, 2));
+        assert_eq!(way_11[0].maxspeed, 50);
+        let way_10 = nw.edges.iter().find(|x| x.id == 10).unwrap();
+        assert_eq!(way_10.lanes, 2.0);
+        assert_eq!(way_10.maxspeed, 30);
+        assert!((way_10.length - 111.2).abs() < 0.5);
+        assert_eq!(nw.restrictions.len(), 1);
+        assert!(nw.turn_allowed(11, 2, 10));
+        assert!(!nw.turn_allowed(11, 2, 12));
+    }
+
+    #[test]
+    fn reports_malformed_edge_record() {
+        let dir = std::env::temp_dir().join("geomarkover_malformed_edges");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("nodes.json"),
+            r#"[{"id": 1, "latitude": -27.6, "longitude": -48.5}]"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("edges.json"), r#"[{"id": 10, "start": 1}]"#).unwrap();
+
+        let result = super::NetworkData::new_from_file(
+            "malformed".to_string(),
+            dir.to_string_lossy().to_string(),
         );
+        match result {
+            Err(crate::error::Error::Parse { file, index, .. }) => {
+                assert!(file.ends_with("edges.json"));
+                assert_eq!(index, Some(0));
+            }
+            r => panic!("expected parse error, got {:?}", r),
+        }
     }
 }
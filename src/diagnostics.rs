@@ -0,0 +1,308 @@
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::markov_chain::{MarkovChain, TransitionMatrix};
+
+// Rows further than this from one are reported
+pub const ROW_SUM_TOLERANCE: f64 = 1e-9;
+
+// Classes longer than this are shortened in the report
+const REPORT_MAX_STATES: usize = 10;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct CommunicatingClass {
+    pub states: Vec<usize>,
+    // No transition leaves a closed class, the rest are transient
+    pub closed: bool,
+    // Zero for a single state without a self transition, it never returns
+    pub period: usize,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct RowIssue {
+    pub row: usize,
+    // Sum of the known probabilities
+    pub sum: f64,
+    pub unknown: usize,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Diagnostics {
+    pub dim: usize,
+    pub classes: Vec<CommunicatingClass>,
+    pub row_issues: Vec<RowIssue>,
+}
+
+impl Diagnostics {
+    pub fn irreducible(&self) -> bool {
+        self.classes.len() == 1
+    }
+
+    pub fn aperiodic(&self) -> bool {
+        self.classes.iter().all(|c| c.period <= 1)
+    }
+
+    // A unique stationary distribution that power iteration reaches from anywhere
+    pub fn ergodic(&self) -> bool {
+        self.irreducible() && self.aperiodic() && self.row_issues.is_empty()
+    }
+
+    pub fn closed_classes(&self) -> impl Iterator<Item = &CommunicatingClass> {
+        self.classes.iter().filter(|c| c.closed)
+    }
+
+    // Streets are named by their OSM way as well when ids_osm is given
+    pub fn report(&self, ids_osm: &[u64]) -> String {
+        let name = |i: usize| match ids_osm.get(i) {
+            Some(id_osm) => format!("{} (way {})", i, id_osm),
+            None => i.to_string(),
+        };
+        let closed = self.closed_classes().count();
+
+        let mut report = String::new();
+        let _ = writeln!(report, "States: {}", self.dim);
+        let _ = writeln!(
+            report,
+            "Communicating classes: {} ({} closed, {} transient)",
+            self.classes.len(),
+            closed,
+            self.classes.len() - closed
+        );
+        let _ = writeln!(report, "Irreducible: {}", self.irreducible());
+        let _ = writeln!(report, "Aperiodic: {}", self.aperiodic());
+        let _ = writeln!(report, "Ergodic: {}", self.ergodic());
+
+        // Singletons a vehicle passes through once are left out, there are usually many
+        for class in self
+            .classes
+            .iter()
+            .filter(|c| c.closed || c.states.len() > 1)
+        {
+            let mut states: Vec<String> = class
+                .states
+                .iter()
+                .take(REPORT_MAX_STATES)
+                .map(|i| name(*i))
+                .collect();
+            if class.states.len() > REPORT_MAX_STATES {
+                states.push(format!("{} more", class.states.len() - REPORT_MAX_STATES));
+            }
+            let _ = writeln!(
+                report,
+                "  {} class of {} states, period {}: {}",
+                if class.closed { "Closed" } else { "Transient" },
+                class.states.len(),
+                class.period,
+                states.join(", ")
+            );
+        }
+
+        let _ = writeln!(report, "Rows not summing to one: {}", self.row_issues.len());
+        for issue in self.row_issues.iter() {
+            let _ = writeln!(
+                report,
+                "  {}: sum = {}, unknown = {}",
+                name(issue.row),
+                issue.sum,
+                issue.unknown
+            );
+        }
+        report
+    }
+}
+
+impl TransitionMatrix {
+    // Structure follows transitions with a positive known probability, the ones the
+    // solvers use. Unknown probabilities only show up as row issues.
+    pub fn diagnose(&self, tolerance: f64) -> Diagnostics {
+        let mut component = vec![0; self.dim()];
+        let mut classes: Vec<CommunicatingClass> = self
+            .strongly_connected_components()
+            .into_iter()
+            .enumerate()
+            .map(|(c, mut states)| {
+                states.sort_unstable();
+                states.iter().for_each(|i| component[*i] = c);
+                CommunicatingClass {
+                    states,
+                    closed: true,
+                    period: 0,
+                }
+            })
+            .collect();
+
+        for (c, class) in classes.iter_mut().enumerate() {
+            class.closed = class
+                .states
+                .iter()
+                .all(|i| self.successors(*i).all(|j| component[j] == c));
+            class.period = self.period(&class.states, &component, c);
+        }
+        classes.sort_by_key(|c| c.states[0]);
+
+        let row_issues = self.row_issues(tolerance);
+
+        Diagnostics {
+            dim: self.dim(),
+            classes,
+            row_issues,
+        }
+    }
+
+    // Rows with unknown probabilities or whose known ones are further than the tolerance
+    // from summing to one
+    pub fn row_issues(&self, tolerance: f64) -> Vec<RowIssue> {
+        (0..self.dim())
+            .filter_map(|i| {
+                let (_, values) = self.row(i);
+                let sum: f64 = values.iter().filter(|p| p.is_finite()).sum();
+                let unknown = values.iter().filter(|p| !p.is_finite()).count();
+                (unknown > 0 || (sum - 1.0).abs() > tolerance).then_some(RowIssue {
+                    row: i,
+                    sum,
+                    unknown,
+                })
+            })
+            .collect()
+    }
+
+    fn successors(&self, i: usize) -> impl Iterator<Item = usize> + '_ {
+        let (cols, values) = self.row(i);
+        cols.iter()
+            .zip(values.iter())
+            .filter(|(_, p)| p.is_finite() && **p > 0.0)
+            .map(|(j, _)| *j)
+    }
+
+    // Tarjan's algorithm with an explicit call stack, long chains of streets would
+    // overflow a recursive one
+    pub fn strongly_connected_components(&self) -> Vec<Vec<usize>> {
+        let dim = self.dim();
+        let mut index = vec![usize::MAX; dim];
+        let mut low = vec![0; dim];
+        let mut on_stack = vec![false; dim];
+        let mut stack: Vec<usize> = Vec::new();
+        let mut components: Vec<Vec<usize>> = Vec::new();
+        let mut next_index = 0;
+
+        for root in 0..dim {
+            if index[root] != usize::MAX {
+                continue;
+            }
+            // (state, successors already visited)
+            let mut calls: Vec<(usize, usize)> = vec![(root, 0)];
+            while let Some((v, visited)) = calls.pop() {
+                if visited == 0 {
+                    index[v] = next_index;
+                    low[v] = next_index;
+                    next_index += 1;
+                    stack.push(v);
+                    on_stack[v] = true;
+                }
+
+                let mut child = None;
+                for (k, w) in self.successors(v).enumerate().skip(visited) {
+                    if index[w] == usize::MAX {
+                        child = Some((k, w));
+                        break;
+                    } else if on_stack[w] {
+                        low[v] = low[v].min(index[w]);
+                    }
+                }
+                if let Some((k, w)) = child {
+                    calls.push((v, k + 1));
+                    calls.push((w, 0));
+                    continue;
+                }
+
+                if low[v] == index[v] {
+                    let mut component = Vec::new();
+                    while let Some(w) = stack.pop() {
+                        on_stack[w] = false;
+                        component.push(w);
+                        if w == v {
+                            break;
+                        }
+                    }
+                    components.push(component);
+                }
+                if let Some((parent, _)) = calls.last() {
+                    low[*parent] = low[*parent].min(low[v]);
+                }
+            }
+        }
+        components
+    }
+
+    // gcd of the cycle lengths, from breadth-first levels: every transition u -> v inside
+    // the class closes cycles of length level(u) + 1 - level(v) modulo the period
+    fn period(&self, states: &[usize], component: &[usize], c: usize) -> usize {
+        let mut level = vec![usize::MAX; self.dim()];
+        let mut queue = std::collections::VecDeque::from([states[0]]);
+        level[states[0]] = 0;
+        let mut period = 0;
+        while let Some(u) = queue.pop_front() {
+            for v in self.successors(u).filter(|v| component[*v] == c) {
+                if level[v] == usize::MAX {
+                    level[v] = level[u] + 1;
+                    queue.push_back(v);
+                } else {
+                    period = gcd(period, (level[u] + 1).abs_diff(level[v]));
+                }
+            }
+        }
+        period
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    match b {
+        0 => a,
+        b => gcd(b, a % b),
+    }
+}
+
+impl MarkovChain {
+    pub fn diagnose(&self) -> Diagnostics {
+        TransitionMatrix::new_from_markov_chain(self).diagnose(ROW_SUM_TOLERANCE)
+    }
+}
+
+mod tests {
+    #[test]
+    fn classes_and_periods() {
+        // 0 feeds the two-cycle 1 <-> 2, 3 only stays put and has an unknown transition
+        let t_mtx = crate::markov_chain::TransitionMatrix::new(
+            4,
+            vec![
+                (0, 1, 1.0),
+                (1, 2, 1.0),
+                (2, 1, 1.0),
+                (3, 3, 0.5),
+                (3, 0, f64::NAN),
+            ],
+        );
+        let diagnostics = t_mtx.diagnose(super::ROW_SUM_TOLERANCE);
+
+        assert_eq!(diagnostics.classes.len(), 3);
+        let cycle = &diagnostics.classes[1];
+        assert_eq!(cycle.states, vec![1, 2]);
+        assert!(cycle.closed);
+        assert_eq!(cycle.period, 2);
+        assert!(!diagnostics.classes[0].closed);
+        assert_eq!(diagnostics.classes[0].period, 0);
+        assert_eq!(diagnostics.classes[2].period, 1);
+
+        assert_eq!(diagnostics.row_issues.len(), 1);
+        assert_eq!(diagnostics.row_issues[0].unknown, 1);
+        assert!(!diagnostics.irreducible() && !diagnostics.aperiodic());
+        assert!(diagnostics.report(&[10, 11, 12, 13]).contains("2 (way 12)"));
+
+        let lazy = crate::markov_chain::TransitionMatrix::new(
+            2,
+            vec![(0, 0, 0.5), (0, 1, 0.5), (1, 0, 1.0)],
+        );
+        assert!(lazy.diagnose(super::ROW_SUM_TOLERANCE).ergodic());
+    }
+}
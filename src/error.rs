@@ -0,0 +1,104 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    Parse {
+        file: String,
+        index: Option<usize>,
+        message: String,
+    },
+    DanglingEndpoint {
+        street: u64,
+        intersection: u64,
+    },
+    TrafficProvider {
+        street: Option<u64>,
+        message: String,
+    },
+    Serialization(serde_json::Error),
+    Solver(String),
+    InvalidInput(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn io(path: &str, source: std::io::Error) -> Self {
+        Error::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub fn parse(file: &str, index: Option<usize>, message: impl fmt::Display) -> Self {
+        Error::Parse {
+            file: file.to_string(),
+            index,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn traffic_provider(street: Option<u64>, message: impl fmt::Display) -> Self {
+        Error::TrafficProvider {
+            street,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "I/O error on {}: {}", path, source),
+            Error::Parse {
+                file,
+                index: Some(i),
+                message,
+            } => write!(f, "Failed to parse record {} of {}: {}", i, file, message),
+            Error::Parse {
+                file,
+                index: None,
+                message,
+            } => write!(f, "Failed to parse {}: {}", file, message),
+            Error::DanglingEndpoint {
+                street,
+                intersection,
+            } => write!(
+                f,
+                "Street {} references missing intersection {}",
+                street, intersection
+            ),
+            Error::TrafficProvider {
+                street: Some(s),
+                message,
+            } => write!(f, "Traffic provider failed for street {}: {}", s, message),
+            Error::TrafficProvider {
+                street: None,
+                message,
+            } => write!(f, "Traffic provider failed: {}", message),
+            Error::Serialization(e) => write!(f, "Serialization error: {}", e),
+            Error::Solver(message) => write!(f, "Solver error: {}", message),
+            Error::InvalidInput(message) => write!(f, "Invalid input: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Serialization(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Serialization(e)
+    }
+}
@@ -1,6 +1,14 @@
 use google_maps::prelude::*;
 use google_maps::directions::DepartureTime;
 
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::data_reader::*;
+use crate::error::Result;
+use crate::markov_chain::{TrafficFlow, Value};
+use crate::traffic_source::TrafficSource;
+
 pub struct GoogleMapsHandler {
     client: GoogleMapsClient,
 }
@@ -14,32 +22,47 @@ pub struct RoutesResponse {
 }
 
 impl GoogleMapsHandler {
-    pub async fn new(gcp_key: String) -> Self {
-        let client = GoogleMapsClient::try_new(gcp_key).unwrap();
-        GoogleMapsHandler { client }
+    pub async fn new(gcp_key: String) -> Result<Self> {
+        let client = GoogleMapsClient::try_new(gcp_key)
+            .map_err(|e| crate::error::Error::traffic_provider(None, e))?;
+        Ok(GoogleMapsHandler { client })
     }
 
-    pub async fn directions(
+    pub async fn directions(&self, from: (f64, f64), to: (f64, f64)) -> Result<RoutesResponse> {
+        self.directions_at(from, to, DepartureTime::Now).await
+    }
+
+    // Google only predicts traffic for departures in the future
+    pub async fn directions_at(
         &self,
         from: (f64, f64),
         to: (f64, f64),
-    ) -> Result<RoutesResponse, Error> {
+        departure_time: DepartureTime,
+    ) -> Result<RoutesResponse> {
+        let location = |(lat, lng): (f64, f64)| {
+            Location::try_from_f64(lat, lng)
+                .map_err(|e| crate::error::Error::traffic_provider(None, e))
+        };
         let result = self
             .client
-            .directions(
-                Location::try_from_f64(from.0, from.1).unwrap(),
-                Location::try_from_f64(to.0, to.1).unwrap(),
-            )
+            .directions(location(from)?, location(to)?)
             .with_travel_mode(TravelMode::Driving)
-            .with_departure_time(DepartureTime::Now)
+            .with_departure_time(departure_time)
             .execute()
             .await;
 
         match result {
             Ok(r) => {
-                let distance = (r.routes[0].legs[0].distance.value as f64).max(1.0);
-                let time_secs = (match &r.routes[0].legs[0].duration_in_traffic {
-                    None => r.routes[0].legs[0].duration.value.num_seconds() as f64,
+                let leg = r
+                    .routes
+                    .first()
+                    .and_then(|route| route.legs.first())
+                    .ok_or_else(|| {
+                        crate::error::Error::traffic_provider(None, "no route between points")
+                    })?;
+                let distance = (leg.distance.value as f64).max(1.0);
+                let time_secs = (match &leg.duration_in_traffic {
+                    None => leg.duration.value.num_seconds() as f64,
                     Some(v) => v.value.num_seconds() as f64,
                 }).max(0.0001);
                 let estimated_average_speed = (3.6 * distance) / time_secs;
@@ -51,11 +74,69 @@ impl GoogleMapsHandler {
                     estimated_travel_time,
                 })
             }
-            e => Err(e.err().unwrap()),
+            Err(e) => Err(crate::error::Error::traffic_provider(None, e)),
         }
     }
 }
 
+#[async_trait]
+impl TrafficSource for GoogleMapsHandler {
+    async fn traffic_flow(
+        &self,
+        street: &Street,
+        street_start: &Intersection,
+        street_end: &Intersection,
+    ) -> Result<TrafficFlow> {
+        self.street_flow(street, street_start, street_end, DepartureTime::Now)
+            .await
+    }
+
+    async fn traffic_flow_at(
+        &self,
+        street: &Street,
+        street_start: &Intersection,
+        street_end: &Intersection,
+        departure: DateTime<Utc>,
+    ) -> Result<TrafficFlow> {
+        self.street_flow(
+            street,
+            street_start,
+            street_end,
+            DepartureTime::At(departure.naive_utc()),
+        )
+        .await
+    }
+}
+
+impl GoogleMapsHandler {
+    async fn street_flow(
+        &self,
+        street: &Street,
+        street_start: &Intersection,
+        street_end: &Intersection,
+        departure_time: DepartureTime,
+    ) -> Result<TrafficFlow> {
+        let traffic_data = self
+            .directions_at(
+                (street_start.latitude, street_start.longitude),
+                (street_end.latitude, street_end.longitude),
+                departure_time,
+            )
+            .await
+            .map_err(|e| match e {
+                crate::error::Error::TrafficProvider { message, .. } => {
+                    crate::error::Error::traffic_provider(Some(street.id), message)
+                }
+                e => e,
+            })?;
+
+        Ok(TrafficFlow::new(
+            Value::Known(traffic_data.estimated_travel_time),
+            Value::Known(traffic_data.estimated_average_speed),
+        ))
+    }
+}
+
 mod tests {
     #[actix_rt::test]
     #[ignore]
@@ -63,7 +144,8 @@ mod tests {
         println!("create client");
         let handler =
             super::GoogleMapsHandler::new("insert_key_here".to_string())
-                .await;
+                .await
+                .unwrap();
         println!("get directions");
         let directions = handler
             .directions((-27.6075094, -48.5478889), (-27.6078129, -48.5477348))
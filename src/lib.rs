@@ -1,4 +1,24 @@
+pub mod absorbing;
+pub mod area;
+pub mod compare;
+pub mod connectivity;
+pub mod criticality;
 pub mod data_reader;
+pub mod diagnostics;
+pub mod error;
 pub mod google_routes;
+pub mod map_matching;
 pub mod markov_chain;
 pub mod osm;
+pub mod osm_extract;
+pub mod passage;
+pub mod render;
+pub mod scenario;
+pub mod server;
+pub mod simulation;
+pub mod speed_density;
+pub mod stationary;
+pub mod time_slices;
+pub mod traffic_source;
+pub mod trajectory;
+pub mod turns;
@@ -1,6 +1,13 @@
+use std::fs;
 use std::process::exit;
 
-use geomarkover::{data_reader, markov_chain, osm};
+use chrono::{DateTime, Utc};
+
+use geomarkover::{
+    absorbing, area, compare, connectivity, criticality, data_reader, error, map_matching,
+    markov_chain, osm, render, scenario, server, simulation, speed_density, stationary,
+    time_slices, traffic_source, trajectory, turns,
+};
 
 use structopt::StructOpt;
 
@@ -12,18 +19,211 @@ struct ArgsTransitionMatrix {
     place_name: Option<String>,
     #[structopt(short = "f", long = "filepath")]
     nw_graph_path: Option<String>,
+    #[structopt(short = "x", long = "extract")]
+    osm_extract_path: Option<String>,
+    #[structopt(long = "latitude", allow_hyphen_values = true, help = "Center of the area")]
+    latitude: Option<f64>,
+    #[structopt(long = "longitude", allow_hyphen_values = true, help = "Center of the area")]
+    longitude: Option<f64>,
+    #[structopt(long = "radius", help = "Metres around the center to keep")]
+    radius: Option<f64>,
+    #[structopt(long = "bbox", allow_hyphen_values = true, help = "south,west,north,east")]
+    bbox: Option<area::Area>,
+    #[structopt(long = "polygon", help = "GeoJSON or WKT file of the area to keep")]
+    polygon_path: Option<String>,
+    #[structopt(long = "seed", help = "Intersection id to keep the streets around")]
+    seed: Option<u64>,
+    #[structopt(long = "hops", help = "Streets away from --seed to keep")]
+    hops: Option<usize>,
+    #[structopt(long = "keep-boundary", help = "Keep the streets crossing the area's edge")]
+    keep_boundary: bool,
+    #[structopt(
+        long = "component",
+        help = "Strongly connected component to keep: largest, a rank or node:<id>"
+    )]
+    component: Option<connectivity::Component>,
     #[structopt(short = "d", long = "datasource", default_value = "osm")]
     data_source: String,
     #[structopt(short = "o", long = "output")]
     show_output: bool,
     #[structopt(short = "s", long = "save")]
     save_results: bool,
+    #[structopt(short = "t", long = "stationary")]
+    stationary: bool,
+    #[structopt(
+        long = "solver",
+        help = "power, gauss-seidel or dense, dense up to 2000 streets and gauss-seidel past it"
+    )]
+    solver: Option<stationary::StationaryMethod>,
+    #[structopt(long = "tolerance", default_value = "1e-10")]
+    tolerance: f64,
+    #[structopt(long = "max-iterations", default_value = "10000")]
+    max_iterations: usize,
+    #[structopt(
+        long = "turn-weights",
+        help = "straight,right,left,u_turn weights, 4,2,1.5,0.25 by default and 1,1,1,1 for uniform"
+    )]
+    turn_weights: Option<turns::TurnWeights>,
+    #[structopt(long = "no-u-turns")]
+    forbid_u_turns: bool,
+    #[structopt(long = "speed-density", help = "greenshields, greenberg or underwood")]
+    speed_density: Option<speed_density::SpeedDensityModel>,
+    #[structopt(long = "jam-density", default_value = "150")]
+    jam_density: f64,
+    #[structopt(long = "critical-density", default_value = "40")]
+    critical_density: f64,
+    #[structopt(long = "feedback-tolerance", default_value = "1e-3")]
+    feedback_tolerance: f64,
+    #[structopt(long = "feedback-iterations", default_value = "50")]
+    feedback_iterations: usize,
+    #[structopt(long = "trajectories", help = "CSV of vehicle_id,timestamp,lat,lon")]
+    trajectories_path: Option<String>,
+    #[structopt(long = "match-radius", default_value = "30")]
+    match_radius: f64,
+    #[structopt(long = "smoothing", default_value = "0")]
+    smoothing: f64,
+    #[structopt(long = "hmm", help = "Map-match trajectories with the hidden Markov matcher")]
+    hmm_matching: bool,
+    #[structopt(long = "gps-sigma", default_value = "5")]
+    gps_sigma: f64,
+    #[structopt(long = "slices", help = "Number of time-of-day slices to build chains for")]
+    slices: Option<usize>,
+    #[structopt(long = "slice-minutes", default_value = "15")]
+    slice_minutes: u32,
+    #[structopt(long = "slice-start", help = "RFC 3339 start, next Monday by default")]
+    slice_start: Option<DateTime<Utc>>,
+    #[structopt(long = "boundary", help = "JSON of exit way ids and inflow per way id")]
+    boundary_path: Option<String>,
+    #[structopt(long = "rank-closures", help = "Rank streets by the impact of closing them")]
+    rank_closures: bool,
+    #[structopt(long = "closures", help = "OSM way ids to try closing, every way by default")]
+    closures: Vec<u64>,
+    #[structopt(long = "scenario", help = "JSON of street edits to compare with the baseline")]
+    scenario_path: Option<String>,
+}
+
+#[derive(StructOpt)]
+struct ArgsServe {
+    #[structopt(short = "a", long = "address", default_value = "127.0.0.1")]
+    address: String,
+    #[structopt(short = "p", long = "port", default_value = "8080")]
+    port: u16,
+}
+
+#[derive(StructOpt)]
+struct ArgsSimulate {
+    #[structopt(short = "c", long = "chain", help = "Saved markov_chain_*.json")]
+    chain_path: String,
+    #[structopt(short = "m", long = "matrix", help = "Saved transtition_matrix_*.csv")]
+    matrix_path: Option<String>,
+    #[structopt(short = "n", long = "steps", default_value = "60")]
+    steps: usize,
+    #[structopt(long = "vehicles", help = "Total vehicles, estimated by default")]
+    vehicle_count: Option<u64>,
+    #[structopt(long = "counts", help = "CSV of id,vehicles to start from")]
+    counts_path: Option<String>,
+    #[structopt(long = "edges", help = "OSM way ids to start all vehicles on")]
+    edges: Vec<u64>,
+    #[structopt(short = "o", long = "output", default_value = "output")]
+    output: String,
+    #[structopt(long = "json", help = "Write JSON instead of CSV")]
+    json: bool,
+}
+
+#[derive(StructOpt)]
+struct ArgsValidate {
+    #[structopt(short = "c", long = "chain", help = "Saved markov_chain_*.json")]
+    chain_path: Option<String>,
+    #[structopt(short = "m", long = "matrix", help = "Saved transtition_matrix_*.csv")]
+    matrix_path: Option<String>,
+    #[structopt(long = "tolerance", default_value = "1e-9")]
+    tolerance: f64,
+}
+
+#[derive(StructOpt)]
+struct ArgsPassageTime {
+    #[structopt(short = "c", long = "chain", help = "Saved markov_chain_*.json")]
+    chain_path: String,
+    #[structopt(short = "m", long = "matrix", help = "Saved transtition_matrix_*.csv")]
+    matrix_path: Option<String>,
+    #[structopt(long = "from", help = "OSM way ids to start from, every street by default")]
+    sources: Vec<u64>,
+    #[structopt(long = "to", help = "OSM way ids to reach")]
+    targets: Vec<u64>,
+    #[structopt(long = "kemeny", help = "Also print the Kemeny constant of the chain")]
+    kemeny: bool,
+    #[structopt(long = "tolerance", default_value = "1e-10")]
+    tolerance: f64,
+    #[structopt(long = "max-iterations", default_value = "100000")]
+    max_iterations: usize,
+}
+
+#[derive(StructOpt)]
+struct ArgsRender {
+    #[structopt(short = "c", long = "chain", help = "Saved markov_chain_*.json")]
+    chain_path: String,
+    #[structopt(long = "diff", help = "Second saved chain, drawn as its change from --chain")]
+    diff_path: Option<String>,
+    #[structopt(long = "metric", default_value = "density", help = "density, speed or travel_time")]
+    metric: render::Metric,
+    #[structopt(long = "ramp", help = "traffic, traffic_r, viridis, diverging or greys")]
+    ramp: Option<String>,
+    #[structopt(long = "colors", use_delimiter = true, help = "Hex colours, e.g. #00ff00,#ff0000")]
+    colors: Vec<String>,
+    #[structopt(long = "breaks", use_delimiter = true, help = "Upper bounds of the classes")]
+    breaks: Vec<f64>,
+    #[structopt(long = "min", allow_hyphen_values = true)]
+    min: Option<f64>,
+    #[structopt(long = "max", allow_hyphen_values = true)]
+    max: Option<f64>,
+    #[structopt(short = "o", long = "output", help = "Image paths ending in .png or .svg")]
+    output_paths: Vec<String>,
+    #[structopt(long = "width", default_value = "2048")]
+    width: u32,
+    #[structopt(long = "height", help = "Same as the width by default")]
+    height: Option<u32>,
+    #[structopt(long = "line-width", default_value = "2")]
+    line_width: f64,
+}
+
+#[derive(StructOpt)]
+struct ArgsCompare {
+    #[structopt(short = "c", long = "chain", help = "Saved markov_chain_*.json, the baseline")]
+    chain_path: String,
+    #[structopt(long = "other", help = "Saved markov_chain_*.json compared to the baseline")]
+    other_path: String,
+    #[structopt(short = "o", long = "output", help = "Per-street differences as CSV")]
+    output_path: Option<String>,
 }
 
+// Parsed once per run, the size of the largest argument set does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(StructOpt)]
 enum Cli {
     #[structopt(about = "Calculate transition matrix for a given location.")]
     CalcTransitionMatrix(ArgsTransitionMatrix),
+    #[structopt(about = "Serve networks, transition matrices and densities over HTTP.")]
+    Serve(ArgsServe),
+    #[structopt(about = "Simulate how a vehicle distribution spreads over a saved chain.")]
+    Simulate(ArgsSimulate),
+    #[structopt(about = "Check a saved chain is irreducible, aperiodic and stochastic.")]
+    Validate(ArgsValidate),
+    #[structopt(about = "Mean steps and probability of reaching some streets from others.")]
+    PassageTime(ArgsPassageTime),
+    #[structopt(about = "Draw a saved chain, or the change between two, to PNG or SVG.")]
+    Render(ArgsRender),
+    #[structopt(about = "Compare two saved chains street by street.")]
+    Compare(ArgsCompare),
+}
+
+fn or_exit<T>(result: error::Result<T>) -> T {
+    match result {
+        Ok(v) => v,
+        Err(e) => {
+            println!("{}", e);
+            exit(1)
+        }
+    }
 }
 
 #[tokio::main]
@@ -31,8 +231,204 @@ async fn main() {
     let cli = Cli::from_args();
 
     match cli {
+        Cli::Serve(args) => {
+            println!("Listening on {}:{}", args.address, args.port);
+            or_exit(server::serve(&args.address, args.port).await);
+        }
+        Cli::Compare(args) => {
+            let baseline = or_exit(markov_chain::MarkovChain::load(&args.chain_path));
+            let other = or_exit(markov_chain::MarkovChain::load(&args.other_path));
+            let comparison = compare::compare(&baseline, &other);
+            print!("{}", comparison.summary());
+            if let Some(path) = &args.output_path {
+                or_exit(comparison.save_csv(path));
+                println!("Saved {}", path);
+            }
+        }
+        Cli::Render(args) => {
+            let mkv_chain = or_exit(markov_chain::MarkovChain::load(&args.chain_path));
+            let colors = match (args.colors.is_empty(), &args.ramp) {
+                (false, _) => Some(or_exit(
+                    args.colors.iter().map(|x| render::Rgb::from_hex(x)).collect(),
+                )),
+                (true, Some(ramp)) => Some(or_exit(render::named_ramp(ramp))),
+                (true, None) => None,
+            };
+            let range = match (args.min, args.max) {
+                (Some(min), Some(max)) => Some((min, max)),
+                (None, None) => None,
+                _ => {
+                    println!("--min and --max go together");
+                    exit(1)
+                }
+            };
+            let scale_options = render::ScaleOptions {
+                colors,
+                breaks: args.breaks.clone(),
+                range,
+            };
+            let map = match &args.diff_path {
+                Some(path) => {
+                    let other = or_exit(markov_chain::MarkovChain::load(path));
+                    or_exit(render::Map::diff(&mkv_chain, &other, args.metric, &scale_options))
+                }
+                None => or_exit(render::Map::new(&mkv_chain, args.metric, &scale_options)),
+            };
+
+            let options = render::RenderOptions {
+                width: args.width,
+                height: args.height.unwrap_or(args.width),
+                line_width: args.line_width,
+            };
+            let output_paths = match args.output_paths.is_empty() {
+                true => vec![format!("map_{}.png", mkv_chain.name())],
+                false => args.output_paths.clone(),
+            };
+            for path in output_paths.iter() {
+                or_exit(map.save(path, &options));
+                println!("Saved {}", path);
+            }
+        }
+        Cli::PassageTime(args) => {
+            let mkv_chain = or_exit(markov_chain::MarkovChain::load(&args.chain_path));
+            let t_mtx = match &args.matrix_path {
+                Some(path) => or_exit(markov_chain::TransitionMatrix::load(path)),
+                None => markov_chain::TransitionMatrix::new_from_markov_chain(&mkv_chain),
+            };
+            let streets = |ways: &[u64]| -> Vec<usize> {
+                ways.iter()
+                    .flat_map(|way| match mkv_chain.nodes_of_way(*way) {
+                        v if v.is_empty() => {
+                            println!("No street with way id {}", way);
+                            exit(1)
+                        }
+                        v => v,
+                    })
+                    .collect()
+            };
+            let targets = streets(&args.targets);
+            let sources = match args.sources.is_empty() {
+                true => (0..t_mtx.dim()).collect(),
+                false => streets(&args.sources),
+            };
+
+            let passage =
+                or_exit(t_mtx.first_passage(&targets, args.tolerance, args.max_iterations));
+            println!(
+                "First passage: converged = {}, iterations = {}, residual = {:e}",
+                passage.converged, passage.iterations, passage.residual
+            );
+            let ids_osm: Vec<u64> = mkv_chain.densities().iter().map(|x| x.id_osm).collect();
+            println!("id,id_osm,hitting_probability,mean_steps");
+            for i in sources {
+                println!(
+                    "{},{},{},{}",
+                    i, ids_osm[i], passage.hitting_probabilities[i], passage.mean_steps[i]
+                );
+            }
+            if args.kemeny {
+                println!("Kemeny constant: {}", or_exit(t_mtx.kemeny_constant()));
+            }
+        }
+        Cli::Validate(args) => {
+            let mkv_chain = args
+                .chain_path
+                .as_ref()
+                .map(|path| or_exit(markov_chain::MarkovChain::load(path)));
+            let t_mtx = match (&args.matrix_path, &mkv_chain) {
+                (Some(path), _) => or_exit(markov_chain::TransitionMatrix::load(path)),
+                (None, Some(mkv_chain)) => {
+                    markov_chain::TransitionMatrix::new_from_markov_chain(mkv_chain)
+                }
+                (None, None) => {
+                    println!("noop");
+                    exit(0)
+                }
+            };
+            // Streets are named by OSM way only when the chain is given
+            let ids_osm: Vec<u64> = mkv_chain.map_or(Vec::new(), |mkv_chain| {
+                mkv_chain.densities().iter().map(|x| x.id_osm).collect()
+            });
+
+            let report = t_mtx.diagnose(args.tolerance);
+            print!("{}", report.report(&ids_osm));
+            if !report.ergodic() {
+                exit(1)
+            }
+        }
+        Cli::Simulate(args) => {
+            let mkv_chain = or_exit(markov_chain::MarkovChain::load(&args.chain_path));
+            let t_mtx = match &args.matrix_path {
+                Some(path) => or_exit(markov_chain::TransitionMatrix::load(path)),
+                None => markov_chain::TransitionMatrix::new_from_markov_chain(&mkv_chain),
+            };
+            let initial = match (&args.counts_path, args.edges.is_empty()) {
+                (Some(path), _) => simulation::InitialDistribution::Counts(or_exit(
+                    simulation::read_counts(path, t_mtx.dim()),
+                )),
+                (None, false) => simulation::InitialDistribution::Concentrated(args.edges.clone()),
+                (None, true) => simulation::InitialDistribution::Uniform,
+            };
+            let result = or_exit(simulation::Simulation::run(
+                &mkv_chain,
+                &t_mtx,
+                &initial,
+                args.vehicle_count,
+                args.steps,
+            ));
+            println!(
+                "Simulated {} steps: {:.1} vehicles at the start, {:.1} at the end",
+                args.steps,
+                result.total_vehicles(0),
+                result.total_vehicles(args.steps)
+            );
+
+            if fs::create_dir_all(&args.output).is_err() {
+                println!("Failed to create output directory {}", args.output);
+            }
+            let path = match args.json {
+                true => format!("{}/simulation_{}.json", args.output, mkv_chain.name()),
+                false => format!("{}/simulation_{}.csv", args.output, mkv_chain.name()),
+            };
+            let saved = match args.json {
+                true => result.save_json(&path),
+                false => result.save_csv(&path),
+            };
+            match saved {
+                Ok(_) => println!("Saved simulation to {}", path),
+                Err(e) => println!("Failed to save simulation to {}: {}", path, e),
+            }
+        }
         Cli::CalcTransitionMatrix(args) => {
-            let data_source = markov_chain::TrafficDataSource::from_str(&args.data_source).await;
+            let data_source = or_exit(traffic_source::from_str(&args.data_source).await);
+
+            let polygon = args
+                .polygon_path
+                .as_ref()
+                .map(|path| or_exit(area::Area::from_file(path)));
+            let area = match (args.latitude, args.longitude, args.radius, args.bbox, polygon) {
+                (Some(latitude), Some(longitude), Some(radius), None, None) => {
+                    Some(area::Area::Circle {
+                        latitude,
+                        longitude,
+                        radius,
+                    })
+                }
+                (None, None, None, bbox, None) => bbox,
+                (None, None, None, None, polygon) => polygon,
+                _ => {
+                    println!("Either --latitude, --longitude and --radius, --bbox or --polygon");
+                    exit(1)
+                }
+            };
+            let hops = match (args.seed, args.hops) {
+                (Some(seed), Some(hops)) => Some((seed, hops)),
+                (None, None) => None,
+                _ => {
+                    println!("--seed and --hops go together");
+                    exit(1)
+                }
+            };
 
             let filepath: String;
             let nw = match args.nw_graph_path {
@@ -40,50 +436,411 @@ async fn main() {
                     filepath = path.clone();
                     data_reader::NetworkData::new_from_file(args.name, path)
                 }
-                None => match args.place_name {
-                    Some(place) => {
+                None => match (args.osm_extract_path, args.place_name) {
+                    (Some(extract), _) => {
                         filepath = format!("output/{}", args.name);
-                        osm::get_data_from_place(&args.name, &place);
-                        data_reader::NetworkData::new_from_file(args.name.clone(), filepath.clone())
+                        data_reader::NetworkData::new_from_osm_extract(args.name.clone(), extract)
                     }
-                    None => {
-                        println!("noop");
-                        exit(0)
+                    (None, Some(place)) => {
+                        filepath = format!("output/{}", args.name);
+                        or_exit(osm::get_data_from_place(&args.name, &place));
+                        data_reader::NetworkData::new_from_file(args.name.clone(), filepath.clone())
                     }
+                    // Without a local network the Python tool downloads one around the point
+                    (None, None) => match &area {
+                        Some(area::Area::Circle {
+                            latitude,
+                            longitude,
+                            radius,
+                        }) => {
+                            filepath = format!("output/{}", args.name);
+                            or_exit(osm::get_data_from_point(
+                                &args.name, *latitude, *longitude, *radius,
+                            ));
+                            data_reader::NetworkData::new_from_file(
+                                args.name.clone(),
+                                filepath.clone(),
+                            )
+                        }
+                        // Only a circle can be downloaded, other areas clip a local network
+                        Some(_) => {
+                            println!(
+                                "{}",
+                                error::Error::InvalidInput(
+                                    "--bbox and --polygon need a network from --filepath or \
+                                     --extract"
+                                        .to_string()
+                                )
+                            );
+                            exit(1)
+                        }
+                        None => {
+                            println!("noop");
+                            exit(0)
+                        }
+                    },
                 },
             };
+            let mut nw = or_exit(nw);
+            if let Some(area) = &area {
+                nw = nw.clip(area, args.keep_boundary);
+            }
+            if let Some((seed, hops)) = hops {
+                nw = or_exit(nw.within_hops(seed, hops, args.keep_boundary));
+            }
+            if !nw.boundary.is_empty() {
+                println!("Kept {} streets crossing the boundary", nw.boundary.len());
+            }
+            let options = markov_chain::TransitionOptions {
+                turn_weights: args.turn_weights.unwrap_or_default(),
+                forbid_u_turns: args.forbid_u_turns,
+            };
+
+            // After clipping, which usually strands a few streets, and on the turns the chain
+            // will take
+            if let Some(component) = args.component {
+                let (kept, report) = or_exit(nw.keep_component(component, &options));
+                print!("{}", report.report());
+                nw = kept;
+            }
+            if nw.edges.is_empty() {
+                println!("No streets left in the area");
+                exit(1)
+            }
+
+            // Matched before the network is consumed by the chain
+            let matched = args.trajectories_path.as_ref().map(|path| {
+                let trajectories = or_exit(trajectory::read_csv(path));
+                let matched: Vec<Vec<Option<trajectory::MatchedStreet>>> = match args.hmm_matching {
+                    true => {
+                        let parameters = map_matching::HmmParameters {
+                            sigma: args.gps_sigma,
+                            radius: args.match_radius,
+                            ..Default::default()
+                        };
+                        let matcher = or_exit(map_matching::HmmMatcher::new(&nw, parameters));
+                        let street = |m: map_matching::MatchedPoint| {
+                            let route = m.route;
+                            m.edge.map(|edge| trajectory::MatchedStreet { edge, route })
+                        };
+                        trajectories
+                            .iter()
+                            .map(|t| matcher.match_trajectory(t).into_iter().map(street).collect())
+                            .collect()
+                    }
+                    false => {
+                        let index = or_exit(map_matching::EdgeIndex::new(&nw, args.match_radius));
+                        let street = |edge| trajectory::MatchedStreet { edge, route: None };
+                        trajectories
+                            .iter()
+                            .map(|t| {
+                                map_matching::match_nearest(&index, t, args.match_radius)
+                                    .into_iter()
+                                    .map(|edge| edge.map(street))
+                                    .collect()
+                            })
+                            .collect()
+                    }
+                };
+                (trajectories, matched, map_matching::Router::for_network(&nw))
+            });
+
+            let boundary = args
+                .boundary_path
+                .as_ref()
+                .map(|path| or_exit(absorbing::Boundary::from_file(path)));
+            let boundary_streets = nw.boundary.clone();
+
+            // Closures are taken out of one baseline chain, before any learning or slicing
+            let closures = match args.rank_closures {
+                true => {
+                    let (baseline, impacts) = or_exit(
+                        criticality::rank_closures(
+                            data_source.as_ref(),
+                            &nw,
+                            &options,
+                            &args.closures,
+                            args.tolerance,
+                            args.max_iterations,
+                        )
+                        .await,
+                    );
+                    println!(
+                        "Baseline: total density = {}, Kemeny constant = {}, reachability = {}",
+                        baseline.total_density,
+                        baseline.kemeny_constant.map_or("-".to_string(), |x| x.to_string()),
+                        baseline.reachability
+                    );
+                    if let Some(error) = &baseline.kemeny_error {
+                        eprintln!(
+                            "Warning: closures ranked without the Kemeny constant: {}",
+                            error
+                        );
+                    }
+                    for x in impacts.iter().take(10) {
+                        println!(
+                            "Closure {} of way {}: reachability {:+}, Kemeny {}, density {:+}",
+                            x.rank,
+                            x.id_osm,
+                            x.reachability_change,
+                            x.kemeny_change.map_or("-".to_string(), |x| format!("{:+}", x)),
+                            x.total_density_change
+                        );
+                    }
+                    Some(impacts)
+                }
+                false => None,
+            };
 
-            let mut mkv_chain = markov_chain::MarkovChain::new_from_network(data_source, nw).await;
-            let t_mtx = markov_chain::TransitionMatrix::new_from_markov_chain(&mkv_chain);
-            mkv_chain.calculate_density_from_matrix(&t_mtx, None);
+            // Baseline and scenario are compared at their stationary densities
+            if let Some(path) = &args.scenario_path {
+                let what_if = or_exit(scenario::Scenario::from_file(path));
+                let (baseline, changed) = or_exit(
+                    what_if
+                        .run(
+                            data_source.as_ref(),
+                            &nw,
+                            &options,
+                            args.tolerance,
+                            args.max_iterations,
+                        )
+                        .await,
+                );
+                let diffs = scenario::diff(&baseline, &changed);
+                let changed_count = diffs
+                    .iter()
+                    .filter(|x| {
+                        x.scenario_travel_time != x.baseline_travel_time
+                            || (x.scenario_density - x.baseline_density).abs() > 1e-12
+                    })
+                    .count();
+                println!(
+                    "Scenario {}: {} of {} streets changed",
+                    what_if.name,
+                    changed_count,
+                    diffs.len()
+                );
 
-            if args.show_output {
-                println!("PRINT");
+                if args.save_results {
+                    if fs::create_dir_all(&filepath).is_err() {
+                        println!("Failed to create output directory {}", filepath);
+                    }
+                    let key = format!("{}_{}", args.data_source, what_if.name);
+                    let path = format!("{}/scenario_diff_{}.csv", filepath, key);
+                    match scenario::save_csv(&diffs, &path) {
+                        Ok(_) => println!("Saved scenario diff to {}", path),
+                        Err(e) => println!("Failed to save scenario diff to {}: {}", path, e),
+                    }
+                    match changed.save_data(filepath.clone(), key.clone()) {
+                        Ok(_) => println!(
+                            "Saved scenario chain to {}/markov_chain_{}.json",
+                            filepath, key
+                        ),
+                        Err(e) => println!(
+                            "Failed to save scenario chain to {}/markov_chain_{}.json: {}",
+                            filepath, key, e
+                        ),
+                    }
+                }
             }
 
-            if args.save_results {
-                if mkv_chain.save_data(filepath.clone(), args.data_source.clone()) {
+            let slicing = args.slices.map(|count| {
+                let start = args
+                    .slice_start
+                    .unwrap_or_else(|| time_slices::next_monday(Utc::now()));
+                or_exit(time_slices::TimeSlicing::new(
+                    start,
+                    args.slice_minutes,
+                    count,
+                ))
+            });
+            let chains = match &slicing {
+                Some(slicing) => {
+                    or_exit(
+                        time_slices::TimeSlicedChain::new_from_network(
+                            data_source.as_ref(),
+                            nw,
+                            &options,
+                            slicing.clone(),
+                        )
+                        .await,
+                    )
+                    .chains
+                }
+                None => vec![or_exit(
+                    markov_chain::MarkovChain::new_from_network(data_source.as_ref(), nw, &options)
+                        .await,
+                )],
+            };
+
+            for (i, mut mkv_chain) in chains.into_iter().enumerate() {
+                // Output files of each slice are keyed by its weekday and time
+                let output_key = match &slicing {
+                    Some(slicing) => {
+                        let label = slicing.slice(i).label();
+                        println!("Time slice {}", label);
+                        format!("{}_{}", args.data_source, label)
+                    }
+                    None => args.data_source.clone(),
+                };
+
+                if let Some((trajectories, matched, router)) = &matched {
+                    let matched = match &slicing {
+                        Some(slicing) => slicing.mask(i, trajectories, matched),
+                        None => matched.clone(),
+                    };
+                    let observed = trajectory::count_transitions(&matched, router);
+                    let learned = mkv_chain.learn_transitions(&observed.counts, args.smoothing);
                     println!(
-                        "Saved markov chain data to {}/markov_chain.json",
-                        filepath.clone()
+                        "Learned transitions of {} streets from {} trajectories, {} sample pairs \
+                         joined through other streets, {} with no route left out",
+                        learned,
+                        trajectories.len(),
+                        observed.expanded,
+                        observed.skipped
                     );
-                } else {
+                }
+                let mut t_mtx = markov_chain::TransitionMatrix::new_from_markov_chain(&mkv_chain);
+                if let Some(model) = args.speed_density {
+                    let relation = speed_density::SpeedDensity {
+                        model,
+                        jam_density: args.jam_density,
+                        critical_density: args.critical_density,
+                    };
+                    let (matrix, report) = mkv_chain.equilibrate(
+                        &relation,
+                        args.feedback_tolerance,
+                        args.feedback_iterations,
+                        None,
+                    );
+                    for (i, residual) in report.residuals.iter().enumerate() {
+                        println!("Feedback iteration {}: residual = {:e}", i + 1, residual);
+                    }
                     println!(
-                        "Failed to save markov chain data to {}/markov_chain.json",
-                        filepath.clone()
+                        "Speed-density feedback: converged = {}, iterations = {}",
+                        report.converged, report.iterations
                     );
+                    t_mtx = matrix;
                 }
-
-                if t_mtx.save_to_file(filepath.clone(), args.data_source.clone()) {
+                if args.stationary {
+                    let method = args
+                        .solver
+                        .unwrap_or_else(|| stationary::StationaryMethod::for_dim(t_mtx.dim()));
+                    let stationary =
+                        or_exit(t_mtx.stationary(method, args.tolerance, args.max_iterations));
                     println!(
-                        "Saved markov chain data to {}/transition_matrix.csv",
-                        filepath.clone()
+                        "Stationary distribution ({:?}): converged = {}, iterations = {}, \
+                         residual = {:e}",
+                        stationary.method,
+                        stationary.converged,
+                        stationary.iterations,
+                        stationary.residual
                     );
+                    if !stationary.row_issues.is_empty() {
+                        eprintln!(
+                            "Warning: {} rows do not sum to one, first at street {} with {}; \
+                             see validate",
+                            stationary.row_issues.len(),
+                            stationary.row_issues[0].row,
+                            stationary.row_issues[0].sum
+                        );
+                    }
+                    mkv_chain.calculate_density_from_stationary(&stationary, None);
                 } else {
+                    mkv_chain.calculate_density_from_matrix(&t_mtx, None);
+                }
+
+                // Absorption and occupancy are saved as a single JSON document
+                let absorption = boundary.as_ref().map(|boundary| {
+                    let chain = or_exit(absorbing::AbsorbingChain::from_boundary(
+                        &mkv_chain,
+                        &t_mtx,
+                        boundary,
+                        &boundary_streets,
+                    ));
+                    let absorption = match chain.dim() <= stationary::DENSE_SOLVE_MAX_DIM {
+                        true => or_exit(chain.absorption_direct()),
+                        false => chain.absorption(args.tolerance, args.max_iterations),
+                    };
+                    println!(
+                        "Absorption: {} exits, converged = {}, iterations = {}",
+                        absorption.exits.len(),
+                        absorption.converged,
+                        absorption.iterations
+                    );
+                    let occupancy = chain.occupancy(args.tolerance, args.max_iterations);
                     println!(
-                        "Failed to save markov chain data to {}/transition_matrix.csv",
-                        filepath.clone()
+                        "Occupancy: {:.1} vehicles, converged = {}",
+                        occupancy.vehicles.iter().sum::<f64>(),
+                        occupancy.converged
                     );
+                    serde_json::json!({ "absorption": absorption, "occupancy": occupancy })
+                });
+
+                if args.show_output {
+                    println!("PRINT");
+                }
+
+                if args.save_results {
+                    if fs::create_dir_all(&filepath).is_err() {
+                        println!("Failed to create output directory {}", filepath);
+                    }
+
+                    match mkv_chain.save_data(filepath.clone(), output_key.clone()) {
+                        Ok(_) => println!(
+                            "Saved markov chain data to {}/markov_chain_{}.json",
+                            filepath, output_key
+                        ),
+                        Err(e) => println!(
+                            "Failed to save markov chain data to {}/markov_chain_{}.json: {}",
+                            filepath, output_key, e
+                        ),
+                    }
+
+                    match mkv_chain.save_geojson(filepath.clone(), output_key.clone()) {
+                        Ok(_) => println!(
+                            "Saved markov chain geojson to {}/markov_chain_{}.geojson",
+                            filepath, output_key
+                        ),
+                        Err(e) => println!(
+                            "Failed to save markov chain geojson to {}/markov_chain_{}.geojson: {}",
+                            filepath, output_key, e
+                        ),
+                    }
+
+                    if let Some(absorption) = &absorption {
+                        let path = format!("{}/absorption_{}.json", filepath, output_key);
+                        match fs::write(&path, absorption.to_string()) {
+                            Ok(_) => println!("Saved absorption to {}", path),
+                            Err(e) => println!("Failed to save absorption to {}: {}", path, e),
+                        }
+                    }
+
+                    // Ranked on the un-sliced baseline, so saved once under the run's key
+                    if let (0, Some(impacts)) = (i, &closures) {
+                        let output_key = &args.data_source;
+                        let path = format!("{}/critical_links_{}.csv", filepath, output_key);
+                        match criticality::save_csv(impacts, &path) {
+                            Ok(_) => println!("Saved closure ranking to {}", path),
+                            Err(e) => println!("Failed to save closure ranking to {}: {}", path, e),
+                        }
+                        let path = format!("{}/critical_links_{}.geojson", filepath, output_key);
+                        match criticality::save_geojson(&mkv_chain, impacts, &path) {
+                            Ok(_) => println!("Saved closure ranking to {}", path),
+                            Err(e) => println!("Failed to save closure ranking to {}: {}", path, e),
+                        }
+                    }
+
+                    match t_mtx.save_to_file(filepath.clone(), output_key.clone()) {
+                        Ok(_) => println!(
+                            "Saved transition matrix to {}/transtition_matrix_{}.csv",
+                            filepath, output_key
+                        ),
+                        Err(e) => println!(
+                            "Failed to save transition matrix to {}/transtition_matrix_{}.csv: {}",
+                            filepath, output_key, e
+                        ),
+                    }
                 }
             }
         }
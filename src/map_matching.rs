@@ -0,0 +1,727 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::data_reader::{haversine, Intersection, NetworkData, EARTH_RADIUS_M};
+use crate::error::{Error, Result};
+use crate::trajectory::{GpsPoint, Trajectory};
+use crate::turns;
+
+// Samples closer than this to their neighbour give no usable heading
+const MIN_HEADING_DISTANCE: f64 = 1.0;
+// Streets heading further than this from the vehicle are only matched as a last resort
+const MAX_HEADING_DIFFERENCE: f64 = 90.0;
+// Routes between consecutive samples longer than this many times their great-circle
+// distance, plus the search diameter, are not considered
+const MAX_DETOUR_FACTOR: f64 = 4.0;
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    // Position of the street in NetworkData.edges, which is also its markov node id
+    pub edge: u64,
+    // Metres from the sample to the street
+    pub distance: f64,
+    // Metres from the street start to the sample's projection on it
+    pub offset: f64,
+}
+
+#[derive(Debug, Clone)]
+struct Segment {
+    start: (f64, f64),
+    end: (f64, f64),
+    length: f64,
+    bearing: f64,
+}
+
+// Streets bucketed in a grid of cell_size metres, over an equirectangular
+// projection centred on the network, which is accurate enough at city scale
+#[derive(Debug, Clone)]
+pub struct EdgeIndex {
+    reference_latitude: f64,
+    cell_size: f64,
+    segments: Vec<Segment>,
+    cells: HashMap<(i64, i64), Vec<usize>>,
+}
+
+impl EdgeIndex {
+    pub fn new(network: &NetworkData, cell_size: f64) -> Result<Self> {
+        let nodes: HashMap<u64, &Intersection> = network.nodes.iter().map(|x| (x.id, x)).collect();
+        let reference_latitude = match network.nodes.len() {
+            0 => 0.0,
+            n => network.nodes.iter().map(|x| x.latitude).sum::<f64>() / n as f64,
+        };
+        let mut index = EdgeIndex {
+            reference_latitude,
+            cell_size,
+            segments: Vec::with_capacity(network.edges.len()),
+            cells: HashMap::new(),
+        };
+
+        for (i, street) in network.edges.iter().enumerate() {
+            let endpoint = |id: u64| {
+                nodes.get(&id).copied().ok_or(Error::DanglingEndpoint {
+                    street: street.id,
+                    intersection: id,
+                })
+            };
+            let (start, end) = (endpoint(street.start)?, endpoint(street.end)?);
+            let (p, q) = (
+                index.project(start.latitude, start.longitude),
+                index.project(end.latitude, end.longitude),
+            );
+            let segment = Segment {
+                start: p,
+                end: q,
+                length: ((q.0 - p.0).powi(2) + (q.1 - p.1).powi(2)).sqrt(),
+                bearing: turns::bearing(start, end),
+            };
+
+            let (min_cell, max_cell) = (
+                index.cell(
+                    segment.start.0.min(segment.end.0),
+                    segment.start.1.min(segment.end.1),
+                ),
+                index.cell(
+                    segment.start.0.max(segment.end.0),
+                    segment.start.1.max(segment.end.1),
+                ),
+            );
+            for cx in min_cell.0..=max_cell.0 {
+                for cy in min_cell.1..=max_cell.1 {
+                    index.cells.entry((cx, cy)).or_default().push(i);
+                }
+            }
+            index.segments.push(segment);
+        }
+        Ok(index)
+    }
+
+    fn project(&self, latitude: f64, longitude: f64) -> (f64, f64) {
+        (
+            EARTH_RADIUS_M * longitude.to_radians() * self.reference_latitude.to_radians().cos(),
+            EARTH_RADIUS_M * latitude.to_radians(),
+        )
+    }
+
+    fn cell(&self, x: f64, y: f64) -> (i64, i64) {
+        (
+            (x / self.cell_size).floor() as i64,
+            (y / self.cell_size).floor() as i64,
+        )
+    }
+
+    pub fn bearing(&self, edge: u64) -> f64 {
+        self.segments[edge as usize].bearing
+    }
+
+    // Straight-line length in metres, consistent with candidate offsets
+    pub fn length(&self, edge: u64) -> f64 {
+        self.segments[edge as usize].length
+    }
+
+    // Streets within radius metres of the point, nearest first
+    pub fn candidates(&self, point: &GpsPoint, radius: f64) -> Vec<Candidate> {
+        let p = self.project(point.latitude, point.longitude);
+        let (cx, cy) = self.cell(p.0, p.1);
+        let ring = (radius / self.cell_size).ceil() as i64;
+
+        let mut edges: Vec<usize> = (cx - ring..=cx + ring)
+            .flat_map(|x| (cy - ring..=cy + ring).map(move |y| (x, y)))
+            .filter_map(|c| self.cells.get(&c))
+            .flatten()
+            .copied()
+            .collect();
+        edges.sort_unstable();
+        edges.dedup();
+
+        let mut candidates: Vec<Candidate> = edges
+            .into_iter()
+            .map(|i| {
+                let (distance, offset) = distance_to_segment(p, &self.segments[i]);
+                Candidate {
+                    edge: i as u64,
+                    distance,
+                    offset,
+                }
+            })
+            .filter(|c| c.distance <= radius)
+            .collect();
+        candidates.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        candidates
+    }
+}
+
+// Distance to the closest point of the segment and how far along the segment it lies
+fn distance_to_segment(p: (f64, f64), segment: &Segment) -> (f64, f64) {
+    let (dx, dy) = (
+        segment.end.0 - segment.start.0,
+        segment.end.1 - segment.start.1,
+    );
+    let length = segment.length;
+    let t = match length > 0.0 {
+        true => (((p.0 - segment.start.0) * dx + (p.1 - segment.start.1) * dy) / (length * length))
+            .clamp(0.0, 1.0),
+        false => 0.0,
+    };
+    let closest = (segment.start.0 + t * dx, segment.start.1 + t * dy);
+    (
+        ((p.0 - closest.0).powi(2) + (p.1 - closest.1).powi(2)).sqrt(),
+        t * length,
+    )
+}
+
+// Heading of the vehicle at each sample, None where it barely moved
+pub fn headings(points: &[GpsPoint]) -> Vec<Option<f64>> {
+    let position = |p: &GpsPoint| Intersection {
+        id: 0,
+        latitude: p.latitude,
+        longitude: p.longitude,
+    };
+    (0..points.len())
+        .map(|i| {
+            let (a, b) = match i + 1 < points.len() {
+                true => (&points[i], &points[i + 1]),
+                false if i > 0 => (&points[i - 1], &points[i]),
+                false => return None,
+            };
+            let (a, b) = (position(a), position(b));
+            let moved = haversine((a.latitude, a.longitude), (b.latitude, b.longitude));
+            (moved >= MIN_HEADING_DISTANCE).then(|| turns::bearing(&a, &b))
+        })
+        .collect()
+}
+
+pub fn heading_difference(a: f64, b: f64) -> f64 {
+    let difference = (a - b).rem_euclid(360.0);
+    difference.min(360.0 - difference)
+}
+
+// Matches every sample to the nearest street within radius metres, preferring streets
+// that run in the direction the vehicle is moving
+pub fn match_nearest(index: &EdgeIndex, trajectory: &Trajectory, radius: f64) -> Vec<Option<u64>> {
+    trajectory
+        .points
+        .iter()
+        .zip(headings(&trajectory.points))
+        .map(|(point, heading)| {
+            let candidates = index.candidates(point, radius);
+            let aligned = candidates.iter().find(|c| match heading {
+                Some(h) => heading_difference(h, index.bearing(c.edge)) <= MAX_HEADING_DIFFERENCE,
+                None => true,
+            });
+            aligned.or(candidates.first()).map(|c| c.edge)
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HmmParameters {
+    // Standard deviation of the GPS noise in metres
+    pub sigma: f64,
+    // Scale in metres of the gap between route and great-circle distances
+    pub beta: f64,
+    // Search radius around each sample in metres
+    pub radius: f64,
+    pub max_candidates: usize,
+}
+
+impl Default for HmmParameters {
+    fn default() -> Self {
+        HmmParameters {
+            sigma: 5.0,
+            beta: 10.0,
+            radius: 50.0,
+            max_candidates: 8,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct MatchedPoint {
+    pub edge: Option<u64>,
+    // Posterior probability of the matched street given the whole trajectory
+    pub confidence: f64,
+    // Streets driven from the previous sample's street to this one, both left out. None at
+    // the start of a piece of trajectory.
+    pub route: Option<Vec<u64>>,
+}
+
+struct Step {
+    candidates: Vec<Candidate>,
+    emissions: Vec<f64>,
+    // Log transition probabilities from the previous step's candidates, [from][to]
+    transitions: Option<Vec<Vec<f64>>>,
+    // Forward log probabilities
+    alpha: Vec<f64>,
+}
+
+#[derive(PartialEq)]
+struct Visit {
+    distance: f64,
+    node: u64,
+}
+
+impl Eq for Visit {}
+
+// Reversed so the binary heap pops the closest node first
+impl Ord for Visit {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.distance.total_cmp(&self.distance)
+    }
+}
+
+impl PartialOrd for Visit {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Streets as a graph between intersections, for the shortest routes between them
+#[derive(Debug, Clone)]
+pub struct Router {
+    endpoints: Vec<(u64, u64)>,
+    lengths: Vec<f64>,
+    outgoing: HashMap<u64, Vec<usize>>,
+}
+
+impl Router {
+    // Street i goes from endpoints[i].0 to endpoints[i].1 and is lengths[i] metres long
+    pub fn new(endpoints: Vec<(u64, u64)>, lengths: Vec<f64>) -> Self {
+        let mut outgoing: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (i, (start, _)) in endpoints.iter().enumerate() {
+            outgoing.entry(*start).or_default().push(i);
+        }
+        Router {
+            endpoints,
+            lengths,
+            outgoing,
+        }
+    }
+
+    pub fn for_network(network: &NetworkData) -> Self {
+        Router::new(
+            network.edges.iter().map(|x| (x.start, x.end)).collect(),
+            network.edges.iter().map(|x| x.length).collect(),
+        )
+    }
+
+    // Streets of the shortest route from the end of one street to the start of another,
+    // None when it is longer than limit metres
+    pub fn between(&self, from: u64, to: u64, limit: f64) -> Option<Vec<u64>> {
+        let (source, target) = (
+            self.endpoints.get(from as usize)?.1,
+            self.endpoints.get(to as usize)?.0,
+        );
+        let visited = self.search(source, limit);
+        let mut route: Vec<u64> = Vec::new();
+        let mut node = target;
+        while node != source {
+            let (_, edge) = visited.get(&node)?;
+            let edge = (*edge)?;
+            route.push(edge as u64);
+            node = self.endpoints[edge].0;
+        }
+        route.reverse();
+        Some(route)
+    }
+
+    // Dijkstra over street lengths from an intersection, up to limit metres, with the street
+    // each intersection is reached by
+    fn search(&self, source: u64, limit: f64) -> HashMap<u64, (f64, Option<usize>)> {
+        let mut visited: HashMap<u64, (f64, Option<usize>)> =
+            HashMap::from([(source, (0.0, None))]);
+        let mut heap = BinaryHeap::from([Visit {
+            distance: 0.0,
+            node: source,
+        }]);
+        while let Some(Visit { distance, node }) = heap.pop() {
+            if distance > visited.get(&node).map_or(f64::INFINITY, |x| x.0) {
+                continue;
+            }
+            for edge in self
+                .outgoing
+                .get(&node)
+                .map(|e| e.as_slice())
+                .unwrap_or_default()
+            {
+                let next = self.endpoints[*edge].1;
+                let d = distance + self.lengths[*edge];
+                if d <= limit && d < visited.get(&next).map_or(f64::INFINITY, |x| x.0) {
+                    visited.insert(next, (d, Some(*edge)));
+                    heap.push(Visit {
+                        distance: d,
+                        node: next,
+                    });
+                }
+            }
+        }
+        visited
+    }
+}
+
+// Hidden Markov map matching after Newson and Krumm: streets near each sample are the
+// hidden states, emissions fall off with the distance to the sample and transitions
+// with the difference between the route and great-circle distances of consecutive samples
+pub struct HmmMatcher {
+    index: EdgeIndex,
+    parameters: HmmParameters,
+    // Over the projected lengths the candidates' offsets are measured on
+    router: Router,
+}
+
+impl HmmMatcher {
+    pub fn new(network: &NetworkData, parameters: HmmParameters) -> Result<Self> {
+        let index = EdgeIndex::new(network, parameters.radius)?;
+        let router = Router::new(
+            network.edges.iter().map(|x| (x.start, x.end)).collect(),
+            (0..network.edges.len())
+                .map(|i| index.length(i as u64))
+                .collect(),
+        );
+        Ok(HmmMatcher {
+            index,
+            parameters,
+            router,
+        })
+    }
+
+    // Matches each sample to a street along with the route driven from the previous one,
+    // samples without nearby streets or that no route reaches split the trajectory into
+    // independently decoded pieces
+    pub fn match_trajectory(&self, trajectory: &Trajectory) -> Vec<MatchedPoint> {
+        let points = &trajectory.points;
+        let mut matched = vec![
+            MatchedPoint {
+                edge: None,
+                confidence: 0.0,
+                route: None,
+            };
+            points.len()
+        ];
+        let mut steps: Vec<Step> = Vec::new();
+        let mut first = 0;
+
+        for (t, point) in points.iter().enumerate() {
+            let mut candidates = self.index.candidates(point, self.parameters.radius);
+            candidates.truncate(self.parameters.max_candidates);
+            if candidates.is_empty() {
+                self.decode(&steps, first, points, &mut matched);
+                steps.clear();
+                continue;
+            }
+            let emissions: Vec<f64> = candidates
+                .iter()
+                .map(|c| -0.5 * (c.distance / self.parameters.sigma).powi(2))
+                .collect();
+
+            let mut step = match steps.last() {
+                Some(previous) => {
+                    let great_circle = haversine(
+                        (points[t - 1].latitude, points[t - 1].longitude),
+                        (point.latitude, point.longitude),
+                    );
+                    let transitions =
+                        self.transitions(&previous.candidates, &candidates, great_circle);
+                    let alpha: Vec<f64> = (0..candidates.len())
+                        .map(|j| {
+                            emissions[j]
+                                + log_sum_exp(
+                                    previous
+                                        .alpha
+                                        .iter()
+                                        .zip(transitions.iter())
+                                        .map(|(a, row)| a + row[j]),
+                                )
+                        })
+                        .collect();
+                    Step {
+                        candidates,
+                        emissions,
+                        transitions: Some(transitions),
+                        alpha,
+                    }
+                }
+                None => Step {
+                    alpha: emissions.clone(),
+                    candidates,
+                    emissions,
+                    transitions: None,
+                },
+            };
+
+            if step.alpha.iter().all(|a| a.is_infinite()) {
+                self.decode(&steps, first, points, &mut matched);
+                steps.clear();
+                step.alpha = step.emissions.clone();
+                step.transitions = None;
+            }
+            if steps.is_empty() {
+                first = t;
+            }
+            steps.push(step);
+        }
+        self.decode(&steps, first, points, &mut matched);
+        matched
+    }
+
+    // Matches a connected piece of trajectory, then routes between its chosen streets
+    fn decode(
+        &self,
+        steps: &[Step],
+        first: usize,
+        points: &[GpsPoint],
+        matched: &mut [MatchedPoint],
+    ) {
+        let chosen = decode(steps, first, matched);
+        for t in 1..chosen.len() {
+            let (a, b) = (
+                &steps[t - 1].candidates[chosen[t - 1]],
+                &steps[t].candidates[chosen[t]],
+            );
+            let (p, q) = (&points[first + t - 1], &points[first + t]);
+            let great_circle = haversine((p.latitude, p.longitude), (q.latitude, q.longitude));
+            matched[first + t].route = match self.moves_along(a, b) {
+                true => Some(Vec::new()),
+                false => self
+                    .router
+                    .between(a.edge, b.edge, self.limit(great_circle)),
+            };
+        }
+    }
+
+    // Moving backwards along a street by less than the GPS noise is not a turn
+    fn moves_along(&self, a: &Candidate, b: &Candidate) -> bool {
+        a.edge == b.edge && b.offset + self.parameters.sigma >= a.offset
+    }
+
+    fn limit(&self, great_circle: f64) -> f64 {
+        great_circle * MAX_DETOUR_FACTOR + 2.0 * self.parameters.radius
+    }
+
+    fn transitions(
+        &self,
+        from: &[Candidate],
+        to: &[Candidate],
+        great_circle: f64,
+    ) -> Vec<Vec<f64>> {
+        from.iter()
+            .map(|a| {
+                let remaining = self.index.length(a.edge) - a.offset;
+                let end = self.router.endpoints[a.edge as usize].1;
+                let distances = self.router.search(end, self.limit(great_circle));
+                to.iter()
+                    .map(|b| {
+                        let route = match self.moves_along(a, b) {
+                            true => Some((b.offset - a.offset).max(0.0)),
+                            false => distances
+                                .get(&self.router.endpoints[b.edge as usize].0)
+                                .map(|(d, _)| remaining + d + b.offset),
+                        };
+                        match route {
+                            Some(r) => -(r - great_circle).abs() / self.parameters.beta,
+                            None => f64::NEG_INFINITY,
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+// Viterbi path of a connected piece of trajectory, with forward-backward posteriors.
+// Returns the chosen candidate of every step.
+fn decode(steps: &[Step], first: usize, matched: &mut [MatchedPoint]) -> Vec<usize> {
+    let n = steps.len();
+    if n == 0 {
+        return Vec::new();
+    }
+
+    let mut delta: Vec<Vec<f64>> = vec![steps[0].emissions.clone()];
+    let mut back: Vec<Vec<usize>> = vec![vec![0; steps[0].candidates.len()]];
+    for step in steps.iter().skip(1) {
+        let previous = delta.last().unwrap();
+        let transitions = step.transitions.as_ref().unwrap();
+        let (scores, pointers): (Vec<f64>, Vec<usize>) = (0..step.candidates.len())
+            .map(|j| {
+                let (i, best) = previous
+                    .iter()
+                    .zip(transitions.iter())
+                    .map(|(d, row)| d + row[j])
+                    .enumerate()
+                    .max_by(|a, b| a.1.total_cmp(&b.1))
+                    .unwrap();
+                (step.emissions[j] + best, i)
+            })
+            .unzip();
+        delta.push(scores);
+        back.push(pointers);
+    }
+
+    let mut beta: Vec<Vec<f64>> = vec![Vec::new(); n];
+    beta[n - 1] = vec![0.0; steps[n - 1].candidates.len()];
+    for t in (0..n - 1).rev() {
+        let next = &steps[t + 1];
+        let transitions = next.transitions.as_ref().unwrap();
+        beta[t] = transitions
+            .iter()
+            .map(|row| {
+                log_sum_exp(
+                    row.iter()
+                        .zip(next.emissions.iter().zip(beta[t + 1].iter()))
+                        .map(|(p, (e, b))| p + e + b),
+                )
+            })
+            .collect();
+    }
+    let log_z = log_sum_exp(steps[n - 1].alpha.iter().copied());
+
+    let mut chosen = vec![argmax(&delta[n - 1]); n];
+    for t in (0..n).rev() {
+        let best = chosen[t];
+        matched[first + t] = MatchedPoint {
+            edge: Some(steps[t].candidates[best].edge),
+            confidence: (steps[t].alpha[best] + beta[t][best] - log_z).exp(),
+            route: None,
+        };
+        if t > 0 {
+            chosen[t - 1] = back[t][best];
+        }
+    }
+    chosen
+}
+
+fn argmax(values: &[f64]) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+fn log_sum_exp(values: impl Iterator<Item = f64>) -> f64 {
+    let values: Vec<f64> = values.collect();
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if max == f64::NEG_INFINITY {
+        return max;
+    }
+    max + values.iter().map(|x| (x - max).exp()).sum::<f64>().ln()
+}
+
+mod tests {
+    #[cfg(test)]
+    fn corner() -> crate::data_reader::NetworkData {
+        use crate::data_reader::fixtures::{node, street};
+        use crate::data_reader::NetworkData;
+
+        // A two-way street heading north, then east
+        NetworkData::new(
+            "corner".to_string(),
+            vec![
+                node(1, 0.0, 0.0),
+                node(2, 0.001, 0.0),
+                node(3, 0.001, 0.001),
+            ],
+            vec![
+                street(10, 1, 2),
+                street(10, 2, 1),
+                street(11, 2, 3),
+                street(11, 3, 2),
+            ],
+        )
+    }
+
+    #[cfg(test)]
+    fn point(timestamp: f64, latitude: f64, longitude: f64) -> crate::trajectory::GpsPoint {
+        crate::trajectory::GpsPoint {
+            timestamp,
+            latitude,
+            longitude,
+        }
+    }
+
+    #[test]
+    fn nearest_street_follows_heading() {
+        use crate::trajectory::Trajectory;
+
+        let nw = corner();
+        let index = super::EdgeIndex::new(&nw, 50.0).unwrap();
+
+        let northbound = Trajectory {
+            vehicle: "a".to_string(),
+            points: vec![
+                point(0.0, 0.0002, 0.00001),
+                point(5.0, 0.0006, 0.00001),
+                point(10.0, 0.00101, 0.0004),
+                point(15.0, 0.00101, 0.0008),
+                point(20.0, 0.01, 0.01),
+            ],
+        };
+        assert_eq!(
+            super::match_nearest(&index, &northbound, 20.0),
+            vec![Some(0), Some(0), Some(2), Some(2), None]
+        );
+
+        let southbound = Trajectory {
+            vehicle: "b".to_string(),
+            points: vec![point(0.0, 0.0006, 0.0), point(5.0, 0.0002, 0.0)],
+        };
+        assert_eq!(
+            super::match_nearest(&index, &southbound, 20.0),
+            vec![Some(1), Some(1)]
+        );
+    }
+
+    #[test]
+    fn hmm_matches_noisy_trace() {
+        use crate::trajectory::Trajectory;
+
+        let matcher = super::HmmMatcher::new(&corner(), super::HmmParameters::default()).unwrap();
+        // Northbound then eastbound, a few metres off the streets, with one sample far away
+        let trajectory = Trajectory {
+            vehicle: "a".to_string(),
+            points: vec![
+                point(0.0, 0.0001, 0.00003),
+                point(5.0, 0.0004, -0.00002),
+                point(10.0, 0.0007, 0.00003),
+                point(15.0, 0.00104, 0.0003),
+                point(20.0, 0.00097, 0.0007),
+                point(25.0, 0.01, 0.01),
+                point(30.0, 0.00103, 0.0006),
+                point(35.0, 0.00098, 0.0009),
+            ],
+        };
+        let matched = matcher.match_trajectory(&trajectory);
+
+        let edges: Vec<Option<u64>> = matched.iter().map(|m| m.edge).collect();
+        assert_eq!(
+            edges,
+            vec![
+                Some(0),
+                Some(0),
+                Some(0),
+                Some(2),
+                Some(2),
+                None,
+                Some(2),
+                Some(2)
+            ]
+        );
+        // The reverse street is equally close, only the route makes it unlikely
+        assert!(matched[1].confidence > 0.9 && matched[1].confidence <= 1.0 + 1e-9);
+        assert_eq!(matched[5].confidence, 0.0);
+
+        // Turning the corner drives no street in between, a new piece has no route yet
+        let routes: Vec<Option<Vec<u64>>> = matched.iter().map(|m| m.route.clone()).collect();
+        assert_eq!(routes[0], None);
+        assert_eq!(routes[3], Some(vec![]));
+        assert_eq!(routes[6], None);
+        assert_eq!(routes[7], Some(vec![]));
+
+        // From the northbound street to the westbound one through the eastbound one
+        let router = super::Router::for_network(&corner());
+        assert_eq!(router.between(0, 3, 1000.0), Some(vec![2]));
+        assert_eq!(router.between(0, 3, 50.0), None);
+    }
+}
@@ -1,19 +1,33 @@
+use std::collections::HashMap;
 use std::fs::{self, File, OpenOptions};
-use std::io::Write;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::io::{BufReader, BufWriter, Write};
 
 use crate::data_reader::*;
-use crate::google_routes::*;
+use crate::error::{Error, Result};
+use crate::speed_density::SpeedDensity;
+use crate::stationary::StationaryDistribution;
+use crate::traffic_source::TrafficSource;
+use crate::trajectory::TransitionCounts;
+use crate::turns::{self, TurnKind, TurnWeights};
 
+use chrono::{DateTime, Utc};
 use futures::future;
-use futures::stream::Collect;
-use serde::Serialize;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json;
 
-#[derive(Debug, Serialize, Clone)]
+// Version of the saved markov chain and transition matrix formats, files
+// written before versioning was introduced are version 0
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Value {
-    Known(f64),
-    Unknown(f64),
+    Known(#[serde(deserialize_with = "nan_if_null")] f64),
+    Unknown(#[serde(deserialize_with = "nan_if_null")] f64),
+}
+
+// serde_json writes NaN as null
+fn nan_if_null<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<f64, D::Error> {
+    Ok(Option::<f64>::deserialize(deserializer)?.unwrap_or(f64::NAN))
 }
 
 impl Value {
@@ -34,7 +48,7 @@ impl std::fmt::Display for Value {
     }
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MarkovNode {
     id: u64,
     id_osm: u64,
@@ -45,41 +59,117 @@ pub struct MarkovNode {
     transitions: Vec<MarkovTransition>,
 }
 
-#[derive(Debug, Serialize, Clone)]
+impl MarkovNode {
+    fn travel_time(&self) -> f64 {
+        match &self.traffic_data {
+            Some(t) => t.estimated_travel_time.as_f64(),
+            None => f64::NAN,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TrafficFlow {
     estimated_travel_time: Value,
     estimated_average_speed: Value,
     estimated_density: Value,
 }
 
-#[derive(Debug, Serialize, Clone)]
+impl TrafficFlow {
+    pub fn new(estimated_travel_time: Value, estimated_average_speed: Value) -> Self {
+        TrafficFlow {
+            estimated_travel_time,
+            estimated_average_speed,
+            estimated_density: Value::Unknown(0.0),
+        }
+    }
+
+    pub fn estimated_travel_time(&self) -> &Value {
+        &self.estimated_travel_time
+    }
+
+    pub fn estimated_average_speed(&self) -> &Value {
+        &self.estimated_average_speed
+    }
+
+    pub fn estimated_density(&self) -> &Value {
+        &self.estimated_density
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MarkovTransition {
     id_to: u64,
     probability: Value,
 }
 
+// Compressed sparse rows for outgoing transitions, mirrored as compressed sparse
+// columns so incoming transitions of a street are a slice as well
 #[derive(Debug, Serialize, Clone)]
 pub struct TransitionMatrix {
     dim: usize,
-    pub matrix: Vec<(usize, usize, f64)>,
+    row_ptr: Vec<usize>,
+    col_idx: Vec<usize>,
+    values: Vec<f64>,
+    col_ptr: Vec<usize>,
+    row_idx: Vec<usize>,
+    col_values: Vec<f64>,
 }
 
 impl std::ops::Index<(u64, u64)> for TransitionMatrix {
     type Output = f64;
 
     fn index(&self, i: (u64, u64)) -> &f64 {
-        match &self
-            .matrix
-            .iter()
-            .find(|(n, m, _)| *n == i.0 as usize && *m == i.1 as usize)
-        {
-            None => &0.0,
-            Some((_, _, value)) => value,
+        let (from, to) = (i.0 as usize, i.1 as usize);
+        if from >= self.dim {
+            return &0.0;
+        }
+        let range = self.row_ptr[from]..self.row_ptr[from + 1];
+        match self.col_idx[range.clone()].binary_search(&to) {
+            Ok(k) => &self.values[range.start + k],
+            Err(_) => &0.0,
         }
     }
 }
 
 impl TransitionMatrix {
+    // Duplicate (from, to) entries are summed, entries outside dim are dropped
+    pub fn new(dim: usize, mut matrix: Vec<(usize, usize, f64)>) -> Self {
+        matrix.retain(|(n, m, _)| *n < dim && *m < dim);
+        matrix.sort_by_key(|(n, m, _)| (*n, *m));
+        matrix.dedup_by(|(n, m, p), (kept_n, kept_m, kept_p)| {
+            let duplicate = n == kept_n && m == kept_m;
+            if duplicate {
+                *kept_p += *p;
+            }
+            duplicate
+        });
+
+        let (row_ptr, col_idx, values) = compress(dim, matrix.iter().map(|x| (x.0, x.1, x.2)));
+
+        matrix.sort_by_key(|(n, m, _)| (*m, *n));
+        let (col_ptr, row_idx, col_values) =
+            compress(dim, matrix.iter().map(|x| (x.1, x.0, x.2)));
+
+        TransitionMatrix {
+            dim,
+            row_ptr,
+            col_idx,
+            values,
+            col_ptr,
+            row_idx,
+            col_values,
+        }
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+
     pub fn new_from_markov_chain(mkv_chain: &MarkovChain) -> Self {
         let dim = mkv_chain.graph.len();
         let mut matrix: Vec<(usize, usize, f64)> = Vec::new();
@@ -89,163 +179,340 @@ impl TransitionMatrix {
             }
         }
 
-        TransitionMatrix { dim, matrix }
+        TransitionMatrix::new(dim, matrix)
     }
 
-    fn to(&self, i: u64) -> Vec<(usize, usize, f64)> {
-        self.clone()
-            .matrix
-            .into_iter()
-            .filter(|(_, m, _)| *m == i as usize)
-            .collect::<Vec<(usize, usize, f64)>>()
+    // Outgoing transitions of a street as (destination ids, probabilities)
+    pub fn row(&self, i: usize) -> (&[usize], &[f64]) {
+        if i >= self.dim {
+            return (&[], &[]);
+        }
+        let range = self.row_ptr[i]..self.row_ptr[i + 1];
+        (&self.col_idx[range.clone()], &self.values[range])
     }
 
-    fn _from(&self, i: u64) -> Vec<(usize, usize, f64)> {
-        self.clone()
-            .matrix
-            .into_iter()
-            .filter(|(n, _, _)| *n == i as usize)
-            .collect::<Vec<(usize, usize, f64)>>()
+    // Incoming transitions of a street as (origin ids, probabilities)
+    pub fn column(&self, j: usize) -> (&[usize], &[f64]) {
+        if j >= self.dim {
+            return (&[], &[]);
+        }
+        let range = self.col_ptr[j]..self.col_ptr[j + 1];
+        (&self.row_idx[range.clone()], &self.col_values[range])
     }
 
-    pub fn print(&self) {
-        let dim = self.dim;
-        let matrix = &self.matrix;
-        for i in 0..dim {
-            let mut line = vec![0.0; dim];
-            for x in matrix {
-                match x {
-                    (a, b, p) if *a == i => {
-                        line[*b] = *p;
-                    }
-                    _ => (),
-                }
-            }
+    pub fn entries(&self) -> impl Iterator<Item = (usize, usize, f64)> + '_ {
+        (0..self.dim).flat_map(move |i| {
+            let (cols, values) = self.row(i);
+            cols.iter().zip(values.iter()).map(move |(j, p)| (i, *j, *p))
+        })
+    }
+
+    // P x, the expected value of x one step ahead from each street
+    pub fn mul_vec(&self, x: &[f64]) -> Vec<f64> {
+        (0..self.dim)
+            .map(|i| {
+                let (cols, values) = self.row(i);
+                cols.iter().zip(values.iter()).map(|(j, p)| p * x[*j]).sum()
+            })
+            .collect()
+    }
+
+    // π P, a distribution over streets propagated one step
+    pub fn vec_mul(&self, pi: &[f64]) -> Vec<f64> {
+        (0..self.dim)
+            .map(|j| {
+                let (rows, values) = self.column(j);
+                rows.iter().zip(values.iter()).map(|(i, p)| p * pi[*i]).sum()
+            })
+            .collect()
+    }
 
-            for x in line {
+    fn dense_row(&self, i: usize) -> Vec<f64> {
+        let mut line = vec![0.0; self.dim];
+        let (cols, values) = self.row(i);
+        for (j, p) in cols.iter().zip(values.iter()) {
+            line[*j] = *p;
+        }
+        line
+    }
+
+    pub fn print(&self) {
+        for i in 0..self.dim {
+            for x in self.dense_row(i) {
                 print!("{}\t", x);
             }
             println!();
         }
     }
 
-    pub fn save_to_file(&self, path: String, data_source_str: String) -> bool {
-        let dim = self.dim;
-        let matrix = &self.matrix;
+    pub fn save_to_file(&self, path: String, data_source_str: String) -> Result<()> {
         let path = format!("{}/transtition_matrix_{}.csv", path, data_source_str);
         if fs::remove_file(path.clone()).is_ok() {
             println!("Removed previous data from {}", path);
         }
-        let mut file = OpenOptions::new()
+        let file = OpenOptions::new()
             .create(true)
             .append(true)
-            .open(path)
-            .unwrap();
+            .open(&path)
+            .map_err(|e| Error::io(&path, e))?;
+        let mut file = BufWriter::new(file);
 
-        for i in 0..dim {
-            let mut line = vec![0.0; dim];
-            for x in matrix {
-                match x {
-                    (a, b, p) if *a == i => {
-                        line[*b] = *p;
-                    }
-                    _ => (),
-                }
-            }
+        file.write_all(format!("# format_version={}\n", FORMAT_VERSION).as_bytes())
+            .map_err(|e| Error::io(&path, e))?;
 
-            let mut line_str = "".to_string();
-            for x in line {
-                let element = format!("{},", x).to_string();
-                line_str = format!("{}{}", line_str, element);
+        for i in 0..self.dim {
+            let mut line_str = String::new();
+            for x in self.dense_row(i) {
+                line_str.push_str(&format!("{},", x));
             }
-            line_str = format!("{}\n", line_str);
-            match file.write_all(line_str.as_bytes()) {
-                Ok(_) => (),
-                _ => {
-                    println!("Failed to save content to file");
-                    return false;
-                }
+            line_str.push('\n');
+            file.write_all(line_str.as_bytes())
+                .map_err(|e| Error::io(&path, e))?;
+        }
+        file.flush().map_err(|e| Error::io(&path, e))
+    }
+
+    // Reads the dense csv written by save_to_file, with or without the version header
+    pub fn load(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        let mut lines = content.lines().filter(|l| !l.trim().is_empty()).peekable();
+
+        let version = match lines.next_if(|l| l.starts_with('#')) {
+            Some(header) => header
+                .trim_start_matches('#')
+                .trim()
+                .strip_prefix("format_version=")
+                .and_then(|v| v.parse::<u32>().ok())
+                .ok_or_else(|| Error::parse(path, None, format!("bad header '{}'", header)))?,
+            None => 0,
+        };
+        if version > FORMAT_VERSION {
+            return Err(Error::parse(
+                path,
+                None,
+                format!("unsupported format version {}", version),
+            ));
+        }
+
+        let rows = lines
+            .enumerate()
+            .map(|(i, line)| {
+                line.split(',')
+                    .map(str::trim)
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.parse::<f64>().map_err(|e| Error::parse(path, Some(i), e)))
+                    .collect::<Result<Vec<f64>>>()
+            })
+            .collect::<Result<Vec<Vec<f64>>>>()?;
+
+        let dim = rows.len();
+        let mut matrix: Vec<(usize, usize, f64)> = Vec::new();
+        for (i, row) in rows.into_iter().enumerate() {
+            if row.len() != dim {
+                return Err(Error::parse(
+                    path,
+                    Some(i),
+                    format!("expected {} columns, found {}", dim, row.len()),
+                ));
             }
+            matrix.extend(
+                row.into_iter()
+                    .enumerate()
+                    .filter(|(_, p)| *p != 0.0)
+                    .map(|(j, p)| (i, j, p)),
+            );
         }
-        true
+
+        Ok(TransitionMatrix::new(dim, matrix))
     }
 }
 
-pub enum TrafficDataSource {
-    GoogleRoutes(GoogleMapsHandler),
-    OpenStreetMap,
-    NoSource,
-    Unknown,
+fn compress(
+    dim: usize,
+    sorted: impl Iterator<Item = (usize, usize, f64)>,
+) -> (Vec<usize>, Vec<usize>, Vec<f64>) {
+    let mut ptr = vec![0; dim + 1];
+    let mut idx = Vec::new();
+    let mut values = Vec::new();
+    for (major, minor, p) in sorted {
+        ptr[major + 1] += 1;
+        idx.push(minor);
+        values.push(p);
+    }
+    for i in 0..dim {
+        ptr[i + 1] += ptr[i];
+    }
+    (ptr, idx, values)
 }
 
-impl TrafficDataSource {
-    pub async fn from_str(s: &str) -> Self {
-        match s {
-            "gmaps" => TrafficDataSource::GoogleRoutes(
-                GoogleMapsHandler::new("insert_key_here".to_string()).await,
-            ),
-            "osm" => TrafficDataSource::OpenStreetMap,
-            _ => TrafficDataSource::Unknown,
-        }
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct TransitionOptions {
+    pub turn_weights: TurnWeights,
+    pub forbid_u_turns: bool,
+}
+
+// Turns from the end of each street onto the next, by position in the network's edges,
+// once turn restrictions and the U-turn ban are applied. Streets between the same two
+// intersections are the street staying put, not a turn.
+pub fn allowed_turns(
+    network_graph: &NetworkData,
+    options: &TransitionOptions,
+) -> Result<Vec<Vec<(usize, TurnKind)>>> {
+    let nodes: HashMap<u64, &Intersection> =
+        network_graph.nodes.iter().map(|x| (x.id, x)).collect();
+    let endpoints = |street: &Street| -> Result<(&Intersection, &Intersection)> {
+        let endpoint = |id: u64| {
+            nodes.get(&id).copied().ok_or(Error::DanglingEndpoint {
+                street: street.id,
+                intersection: id,
+            })
+        };
+        Ok((endpoint(street.start)?, endpoint(street.end)?))
+    };
+
+    let edges = &network_graph.edges;
+    let mut outgoing: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (i, x) in edges.iter().enumerate() {
+        outgoing.entry(x.start).or_default().push(i);
+    }
+    // Restrictions indexed by (from way, via node) to check candidate turns quickly
+    let mut restrictions: HashMap<(u64, u64), Vec<&TurnRestriction>> = HashMap::new();
+    for r in network_graph.restrictions.iter() {
+        restrictions.entry((r.from, r.via)).or_default().push(r);
     }
+
+    edges
+        .iter()
+        .map(|x| {
+            let incoming = endpoints(x)?;
+            let turn_restrictions = restrictions
+                .get(&(x.id, x.end))
+                .map(|r| r.as_slice())
+                .unwrap_or_default();
+            let mut turns = Vec::new();
+            for j in outgoing.get(&x.end).into_iter().flatten() {
+                let y = &edges[*j];
+                if y.end == x.end && y.start == x.start {
+                    continue;
+                }
+                let turn = turns::classify(incoming, endpoints(y)?);
+                if options.forbid_u_turns && turn == TurnKind::UTurn {
+                    continue;
+                }
+                if turn_restrictions.iter().all(|r| r.allows(x.id, x.end, y.id)) {
+                    turns.push((*j, turn));
+                }
+            }
+            Ok(turns)
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct EdgeDensity {
+    pub id: u64,
+    pub id_osm: u64,
+    pub density: f64,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Clone)]
+pub struct EdgeState {
+    pub id: u64,
+    pub id_osm: u64,
+    pub start: u64,
+    pub end: u64,
+    // (latitude, longitude) of the start and end intersections
+    pub start_position: (f64, f64),
+    pub end_position: (f64, f64),
+    // Hours
+    pub travel_time: f64,
+    // km/h
+    pub speed: f64,
+    pub density: f64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct EquilibriumReport {
+    pub iterations: usize,
+    // Largest density change of each iteration, in vehicles per km per lane
+    pub residuals: Vec<f64>,
+    pub converged: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct MarkovChain {
+    #[serde(default)]
+    version: u32,
     name: String,
     graph: Vec<MarkovNode>,
 }
 
-static ID_COUNTER: AtomicUsize = AtomicUsize::new(0);
 impl MarkovChain {
     pub async fn new_from_network(
-        traffic_data_source: TrafficDataSource,
+        traffic_source: &dyn TrafficSource,
+        network_graph: NetworkData,
+        options: &TransitionOptions,
+    ) -> Result<Self> {
+        MarkovChain::new_from_network_at(traffic_source, network_graph, options, None).await
+    }
+
+    // Traffic is requested for the given departure time, or the source's current
+    // estimate when there is none
+    pub async fn new_from_network_at(
+        traffic_source: &dyn TrafficSource,
+        network_graph: NetworkData,
+        options: &TransitionOptions,
+        departure: Option<DateTime<Utc>>,
+    ) -> Result<Self> {
+        MarkovChain::topology(network_graph, options)?
+            .with_traffic_at(traffic_source, departure)
+            .await
+    }
+
+    // Streets and the turns allowed between them, with no traffic yet. Turns hold their
+    // weight as the unknown value until with_traffic_at sets the probabilities.
+    pub(crate) fn topology(
         network_graph: NetworkData,
-    ) -> Self {
+        options: &TransitionOptions,
+    ) -> Result<Self> {
+        let allowed = allowed_turns(&network_graph, options)?;
         let name = network_graph.name;
+        let intersection = |street: &Street, id: u64| {
+            network_graph
+                .nodes
+                .iter()
+                .find(|i| i.id == id)
+                .cloned()
+                .ok_or(Error::DanglingEndpoint {
+                    street: street.id,
+                    intersection: id,
+                })
+        };
 
-        let mut graph: Vec<MarkovNode> =
-            future::join_all(network_graph.edges.into_iter().map(|x| async {
-                MarkovNode {
-                    id: ID_COUNTER.fetch_add(1, Ordering::Relaxed) as u64,
+        let mut graph: Vec<MarkovNode> = network_graph
+            .edges
+            .into_iter()
+            .enumerate()
+            .map(|(id, x)| {
+                Ok(MarkovNode {
+                    id: id as u64,
                     id_osm: x.id,
-                    street_start: network_graph
-                        .nodes
-                        .iter()
-                        .find(|i| i.id == x.start)
-                        .unwrap()
-                        .clone(),
-                    street_end: network_graph
-                        .nodes
-                        .iter()
-                        .find(|i| i.id == x.end)
-                        .unwrap()
-                        .clone(),
+                    street_start: intersection(&x, x.start)?,
+                    street_end: intersection(&x, x.end)?,
                     street_data: x,
                     traffic_data: None,
                     transitions: Vec::new(),
-                }
-            }))
-            .await;
+                })
+            })
+            .collect::<Result<Vec<MarkovNode>>>()?;
 
-        let street_vec: Vec<(u64, Intersection, Intersection)> = graph
+        let street_vec: Vec<(u64, u64, Intersection, Intersection)> = graph
             .clone()
             .into_iter()
-            .map(|x| (x.id, x.street_start, x.street_end))
+            .map(|x| (x.id, x.id_osm, x.street_start, x.street_end))
             .collect();
 
-        graph = future::join_all(graph.into_iter().map(|mut x| async {
-            x.traffic_data = MarkovChain::get_traffic_data(
-                &traffic_data_source,
-                &x.street_data,
-                &x.street_start,
-                &x.street_end,
-            )
-            .await;
-            x
-        }))
-        .await;
-
         graph = graph
             .into_iter()
             .map(|mut x| {
@@ -256,21 +523,29 @@ impl MarkovChain {
                 x.street_data.lanes = adjusted_lanes;
                 for y in street_vec.iter() {
                     let x_start = x.street_data.start;
-                    let y_start = y.1.id;
+                    let y_start = y.2.id;
                     let x_end = x.street_data.end;
-                    let y_end = y.2.id;
+                    let y_end = y.3.id;
                     match (x_start, y_start, x_end, y_end) {
                         (xs, ys, xe, ye) if xs == ys && xe == ye => {
+                            // Set from the travel time once there is traffic
                             x.transitions.push(MarkovTransition {
                                 id_to: x.id,
-                                probability: x.traffic_data.clone().unwrap().estimated_travel_time,
+                                probability: Value::Unknown(0.0),
                             });
                         }
                         (_, ys, xe, _) if ys == xe => {
-                            x.transitions.push(MarkovTransition {
-                                id_to: y.0,
-                                probability: Value::Unknown(0.0),
-                            });
+                            // Turn weight is kept as the unknown value until probabilities are set
+                            let turn = allowed[x.id as usize]
+                                .iter()
+                                .find(|(j, _)| *j as u64 == y.0)
+                                .map(|(_, turn)| *turn);
+                            if let Some(turn) = turn {
+                                x.transitions.push(MarkovTransition {
+                                    id_to: y.0,
+                                    probability: Value::Unknown(options.turn_weights.weight(turn)),
+                                });
+                            }
                         }
                         (_, _, _, _) => (),
                     }
@@ -279,40 +554,92 @@ impl MarkovChain {
             })
             .collect();
 
-        let min_travel_time = graph
+        Ok(MarkovChain {
+            version: FORMAT_VERSION,
+            name,
+            graph,
+        })
+    }
+
+    // Asks the traffic source about every street of a topology and turns its travel
+    // times and turn weights into probabilities, the topology is left as it was
+    pub(crate) async fn with_traffic_at(
+        &self,
+        traffic_source: &dyn TrafficSource,
+        departure: Option<DateTime<Utc>>,
+    ) -> Result<Self> {
+        let flows = future::join_all(self.graph.iter().map(|x| async move {
+            match departure {
+                Some(t) => {
+                    traffic_source
+                        .traffic_flow_at(&x.street_data, &x.street_start, &x.street_end, t)
+                        .await
+                }
+                None => {
+                    traffic_source
+                        .traffic_flow(&x.street_data, &x.street_start, &x.street_end)
+                        .await
+                }
+            }
+        }))
+        .await
+        .into_iter()
+        .collect::<Result<Vec<TrafficFlow>>>()?;
+        Ok(self.with_traffic(flows))
+    }
+
+    // Prices a topology with the traffic of a chain over the same streets or more, as
+    // after closing a road, without asking the traffic source again
+    pub(crate) fn with_traffic_of(&self, priced: &MarkovChain) -> Result<Self> {
+        let flows: HashMap<(u64, u64, u64), &TrafficFlow> = priced
+            .graph
+            .iter()
+            .filter_map(|x| {
+                let key = (x.id_osm, x.street_data.start, x.street_data.end);
+                Some((key, x.traffic_data.as_ref()?))
+            })
+            .collect();
+        let flows = self
+            .graph
             .iter()
             .map(|x| {
-                x.traffic_data
-                    .clone()
-                    .unwrap()
-                    .estimated_travel_time
-                    .as_f64()
+                flows
+                    .get(&(x.id_osm, x.street_data.start, x.street_data.end))
+                    .map(|flow| (*flow).clone())
+                    .ok_or_else(|| {
+                        Error::InvalidInput(format!(
+                            "no traffic for the street of way {} from {} to {}",
+                            x.id_osm, x.street_data.start, x.street_data.end
+                        ))
+                    })
             })
-            .collect::<Vec<f64>>()
-            .into_iter()
-            .min_by(|a, b| match a.partial_cmp(b) {
-                Some(x) => x,
-                None => {
-                    println!("a = {a}, b = {b}");
-                    if a.is_nan() {
-                         b.partial_cmp(b).unwrap()
-                    } else {
-                         a.partial_cmp(a).unwrap()
-                    }
-                }
+            .collect::<Result<Vec<TrafficFlow>>>()?;
+        Ok(self.with_traffic(flows))
+    }
+
+    // One flow per street of the topology, in order
+    fn with_traffic(&self, flows: Vec<TrafficFlow>) -> Self {
+        let mut graph: Vec<MarkovNode> = self
+            .graph
+            .iter()
+            .cloned()
+            .zip(flows)
+            .map(|(mut x, flow)| {
+                x.traffic_data = Some(flow);
+                x
             })
-            .unwrap();
+            .collect();
+
+        // f64::min skips NaN travel times from sources without data for a street
+        let min_travel_time = graph
+            .iter()
+            .map(|x| x.travel_time())
+            .fold(f64::INFINITY, f64::min);
 
         graph = graph
             .into_iter()
             .map(|mut mkv_node| {
-                let norm_tt = mkv_node
-                    .traffic_data
-                    .clone()
-                    .unwrap()
-                    .estimated_travel_time
-                    .as_f64()
-                    / min_travel_time;
+                let norm_tt = mkv_node.travel_time() / min_travel_time;
                 mkv_node.transitions = mkv_node
                     .transitions
                     .into_iter()
@@ -332,11 +659,14 @@ impl MarkovChain {
                     .clone()
                     .into_iter()
                     .find(|t| t.id_to == mkv_node.id)
-                    .unwrap()
-                    .probability
-                    .as_f64();
+                    .map(|t| t.probability.as_f64())
+                    .unwrap_or(0.0);
 
-                let num_transitions = mkv_node.transitions.len();
+                let turn_weight = |t: &MarkovTransition| match t.probability {
+                    Value::Unknown(w) if t.id_to != mkv_node.id => w,
+                    _ => 0.0,
+                };
+                let total_weight: f64 = mkv_node.transitions.iter().map(turn_weight).sum();
 
                 mkv_node.transitions = mkv_node
                     .transitions
@@ -344,9 +674,10 @@ impl MarkovChain {
                     .map(|mut t| {
                         t.probability = match t.id_to {
                             id if id == mkv_node.id => t.probability,
-                            _ => Value::Known(
-                                (1.0 - self_transition_prob) / (num_transitions as f64 - 1.0),
+                            _ if total_weight > 0.0 => Value::Known(
+                                (1.0 - self_transition_prob) * turn_weight(&t) / total_weight,
                             ),
+                            _ => Value::Known(0.0),
                         };
                         t
                     })
@@ -354,52 +685,39 @@ impl MarkovChain {
                 mkv_node
             })
             .collect();
-        MarkovChain { name, graph }
-    }
-
-    async fn get_traffic_data(
-        source: &TrafficDataSource,
-        street_info: &Street,
-        street_start: &Intersection,
-        street_end: &Intersection,
-    ) -> Option<TrafficFlow> {
-        match source {
-            TrafficDataSource::OpenStreetMap => Some(TrafficFlow {
-                estimated_travel_time: Value::Known(
-                    (street_info.length / 1000.0) / (street_info.maxspeed as f64),
-                ),
-                estimated_average_speed: Value::Known(street_info.maxspeed as f64),
-                estimated_density: Value::Unknown(0.0),
-            }),
-            TrafficDataSource::GoogleRoutes(handler) => {
-                let traffic_data = match handler
-                    .directions(
-                        (street_start.latitude, street_start.longitude),
-                        (street_end.latitude, street_end.longitude),
-                    )
-                    .await
-                {
-                    Ok(r) => r,
-                    e => e.unwrap(),
-                };
-
-                Some(TrafficFlow {
-                    estimated_travel_time: Value::Known(traffic_data.estimated_travel_time),
-                    estimated_average_speed: Value::Known(traffic_data.estimated_average_speed),
-                    estimated_density: Value::Unknown(0.0),
-                })
-            }
-            _ => None,
+        MarkovChain {
+            version: FORMAT_VERSION,
+            name: self.name.clone(),
+            graph,
         }
     }
 
-    fn node(graph: &[MarkovNode], i: u64) -> MarkovNode {
-        graph
-            .to_owned()
-            .clone()
-            .into_iter()
-            .find(|x| x.id == i)
-            .unwrap()
+    // Node ids are their position in the graph
+    fn node(graph: &[MarkovNode], i: u64) -> Option<&MarkovNode> {
+        graph.get(i as usize).filter(|x| x.id == i)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Positions of the streets built from an OSM way, one per direction and segment
+    pub fn nodes_of_way(&self, id_osm: u64) -> Vec<usize> {
+        self.graph
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.id_osm == id_osm)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    // Length times lanes of each street in metres, what a vehicle count is divided by
+    // to get a density
+    pub fn lane_lengths(&self) -> Vec<f64> {
+        self.graph
+            .iter()
+            .map(|x| x.street_data.length * x.street_data.lanes)
+            .collect()
     }
 
     // Supondo densidade livre em todos os trechos inicialmente -> 7 vei/km/faixa
@@ -418,79 +736,367 @@ impl MarkovChain {
             Some(v) => v,
         };
 
-        let h_graph = self.graph.clone();
+        // Incoming transitions include the street's own self transition
+        let densities: Vec<f64> = self
+            .graph
+            .iter()
+            .map(|x| {
+                let (from, probs) = t_mtx.column(x.id as usize);
+                from.iter()
+                    .zip(probs.iter())
+                    .filter_map(|(from, prob)| {
+                        let node = MarkovChain::node(&self.graph, *from as u64)?;
+                        Some(MarkovChain::calculate_density_parcel(
+                            vehicle_count,
+                            *prob,
+                            node.street_data.length,
+                            node.street_data.lanes,
+                        ))
+                    })
+                    .sum()
+            })
+            .collect();
+
+        for (x, density) in self.graph.iter_mut().zip(densities) {
+            if let Some(traffic_data) = x.traffic_data.as_mut() {
+                traffic_data.estimated_density = Value::Known(density);
+            }
+        }
+    }
+
+    pub fn calculate_density_from_stationary(
+        &mut self,
+        stationary: &StationaryDistribution,
+        vehicle_count: Option<u64>,
+    ) {
+        let vehicle_count = match vehicle_count {
+            None => self.estimate_vehicle_count(),
+            Some(v) => v,
+        };
+
+        for x in self.graph.iter_mut() {
+            let share = stationary
+                .distribution
+                .get(x.id as usize)
+                .copied()
+                .unwrap_or(f64::NAN);
+            let density = MarkovChain::calculate_density_parcel(
+                vehicle_count,
+                share,
+                x.street_data.length,
+                x.street_data.lanes,
+            );
+            if let Some(traffic_data) = x.traffic_data.as_mut() {
+                traffic_data.estimated_density = Value::Known(density);
+            }
+        }
+    }
+
+    // Density of each street, NaN where it has not been calculated yet
+    pub fn densities(&self) -> Vec<EdgeDensity> {
+        self.graph
+            .iter()
+            .map(|x| EdgeDensity {
+                id: x.id,
+                id_osm: x.id_osm,
+                density: match &x.traffic_data {
+                    Some(t) => t.estimated_density.as_f64(),
+                    None => f64::NAN,
+                },
+            })
+            .collect()
+    }
+
+    pub fn edge_states(&self) -> Vec<EdgeState> {
+        self.graph
+            .iter()
+            .zip(self.densities())
+            .map(|(x, d)| EdgeState {
+                id: x.id,
+                id_osm: x.id_osm,
+                start: x.street_data.start,
+                end: x.street_data.end,
+                start_position: (x.street_start.latitude, x.street_start.longitude),
+                end_position: (x.street_end.latitude, x.street_end.longitude),
+                travel_time: x.travel_time(),
+                speed: match &x.traffic_data {
+                    Some(t) => t.estimated_average_speed.as_f64(),
+                    None => f64::NAN,
+                },
+                density: d.density,
+            })
+            .collect()
+    }
+
+    // Re-estimates each street's transitions from observed counts, smoothed toward the
+    // current probabilities by a Dirichlet prior of the given strength. Moves the chain
+    // does not allow are ignored and streets without observations keep their
+    // probabilities. Returns how many streets were re-estimated.
+    pub fn learn_transitions(&mut self, counts: &TransitionCounts, smoothing: f64) -> usize {
+        let mut learned = 0;
+        for x in self.graph.iter_mut() {
+            let observed: Vec<f64> = x
+                .transitions
+                .iter()
+                .map(|t| counts.get(&(x.id, t.id_to)).copied().unwrap_or(0) as f64)
+                .collect();
+            let total = observed.iter().sum::<f64>() + smoothing;
+            if total <= 0.0 || observed.iter().all(|n| *n == 0.0) {
+                continue;
+            }
 
-        self.graph = self
+            for (t, n) in x.transitions.iter_mut().zip(observed) {
+                let prior = match t.probability.as_f64() {
+                    p if p.is_nan() => 0.0,
+                    p => p,
+                };
+                t.probability = Value::Known((n + smoothing * prior) / total);
+            }
+            learned += 1;
+        }
+        learned
+    }
+
+    // Feeds matrix densities back into speeds until densities stop changing, returning
+    // the matrix of the last iteration
+    pub fn equilibrate(
+        &mut self,
+        relation: &SpeedDensity,
+        tolerance: f64,
+        max_iterations: usize,
+        vehicle_count: Option<u64>,
+    ) -> (TransitionMatrix, EquilibriumReport) {
+        let vehicle_count = vehicle_count.unwrap_or_else(|| self.estimate_vehicle_count());
+        // Speeds reported by the traffic source are taken as the free-flow speeds
+        let free_speeds: Vec<f64> = self
             .graph
-            .clone()
-            .into_iter()
-            .map(|mut x| {
-                let self_prob = t_mtx[(x.street_start.id, x.street_end.id)];
-                let other_prob = t_mtx.to(x.id);
-                let mut density = MarkovChain::calculate_density_parcel(
-                    vehicle_count,
-                    self_prob,
-                    x.street_data.length,
-                    x.street_data.lanes,
-                );
-                for (from, _, prob) in other_prob {
-                    let node = MarkovChain::node(&h_graph, from as u64);
-                    density += MarkovChain::calculate_density_parcel(
-                        vehicle_count,
-                        prob,
-                        node.street_data.length,
-                        node.street_data.lanes,
-                    );
-                }
-                x.traffic_data = Some(TrafficFlow {
-                    estimated_travel_time: x.traffic_data.clone().unwrap().estimated_travel_time,
-                    estimated_average_speed: x
-                        .traffic_data
-                        .clone()
-                        .unwrap()
-                        .estimated_average_speed,
-                    estimated_density: Value::Known(density),
-                });
-                x
+            .iter()
+            .map(|x| match &x.traffic_data {
+                Some(t) => t.estimated_average_speed.as_f64(),
+                None => f64::NAN,
             })
-            .collect::<Vec<MarkovNode>>();
+            .collect();
+
+        let mut t_mtx = TransitionMatrix::new_from_markov_chain(self);
+        self.calculate_density_from_matrix(&t_mtx, Some(vehicle_count));
+        let mut residuals: Vec<f64> = Vec::new();
+
+        while residuals.len() < max_iterations
+            && residuals.last().is_none_or(|r| *r > tolerance)
+        {
+            let previous = self.densities();
+            for (x, free_speed) in self.graph.iter_mut().zip(free_speeds.iter()) {
+                // Streets without a known speed keep their traffic data
+                if !free_speed.is_finite() || *free_speed <= 0.0 {
+                    continue;
+                }
+                if let Some(t) = x.traffic_data.as_mut() {
+                    // Densities are per metre of lane, the relations work per km
+                    let speed = relation.speed(*free_speed, t.estimated_density.as_f64() * 1000.0);
+                    t.estimated_average_speed = Value::Known(speed);
+                    t.estimated_travel_time = Value::Known((x.street_data.length / 1000.0) / speed);
+                }
+            }
+            self.update_self_transitions();
+            t_mtx = TransitionMatrix::new_from_markov_chain(self);
+            self.calculate_density_from_matrix(&t_mtx, Some(vehicle_count));
+
+            let residual = self
+                .densities()
+                .iter()
+                .zip(previous.iter())
+                .map(|(a, b)| (a.density - b.density).abs() * 1000.0)
+                .filter(|d| !d.is_nan())
+                .fold(0.0, f64::max);
+            residuals.push(residual);
+        }
+
+        let report = EquilibriumReport {
+            iterations: residuals.len(),
+            converged: residuals.last().is_some_and(|r| *r <= tolerance),
+            residuals,
+        };
+        (t_mtx, report)
+    }
+
+    // Sets self transitions from the current travel times, the other transitions keep
+    // their relative shares of what is left
+    fn update_self_transitions(&mut self) {
+        let min_travel_time = self
+            .graph
+            .iter()
+            .map(|x| x.travel_time())
+            .fold(f64::INFINITY, f64::min);
+
+        for x in self.graph.iter_mut() {
+            let norm_tt = x.travel_time() / min_travel_time;
+            let self_transition_prob = (norm_tt - 1.0) / norm_tt;
+            let id = x.id;
+            let previous_prob = x
+                .transitions
+                .iter()
+                .find(|t| t.id_to == id)
+                .map(|t| t.probability.as_f64())
+                .unwrap_or(0.0);
+            let scale = (1.0 - self_transition_prob) / (1.0 - previous_prob);
+            if !scale.is_finite() {
+                continue;
+            }
+            for t in x.transitions.iter_mut() {
+                t.probability = match t.id_to {
+                    to if to == id => Value::Known(self_transition_prob),
+                    _ => Value::Known(t.probability.as_f64() * scale),
+                };
+            }
+        }
     }
 
     fn calculate_density_parcel(v: u64, prob: f64, l: f64, n: f64) -> f64 {
         (v as f64 * prob) / (l * n)
     }
 
-    pub fn save_data(&self, path: String, data_source_str: String) -> bool {
-        let output_str: String = match serde_json::to_string_pretty(&self) {
-            Ok(v) => v,
-            _ => return false,
-        };
+    pub fn save_data(&self, path: String, data_source_str: String) -> Result<()> {
+        let output_str: String = serde_json::to_string_pretty(&self)?;
 
         let path = format!("{}/markov_chain_{}.json", path, data_source_str);
 
-        let mut file = File::create(path).unwrap();
-        match file.write_all(output_str.as_bytes()) {
-            Ok(_) => true,
-            _ => {
-                println!("Failed to save content to file");
-                false
+        let mut file = File::create(&path).map_err(|e| Error::io(&path, e))?;
+        file.write_all(output_str.as_bytes())
+            .map_err(|e| Error::io(&path, e))
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).map_err(|e| Error::io(path, e))?;
+        let mut mkv_chain: MarkovChain = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| Error::parse(path, None, e))?;
+
+        match mkv_chain.version {
+            0 => mkv_chain.renumber(),
+            FORMAT_VERSION => (),
+            v => {
+                return Err(Error::parse(
+                    path,
+                    None,
+                    format!("unsupported format version {}", v),
+                ))
             }
         }
+        mkv_chain.version = FORMAT_VERSION;
+        Ok(mkv_chain)
+    }
+
+    // Version 0 files took ids from a global counter, map them back to graph positions
+    fn renumber(&mut self) {
+        let ids: HashMap<u64, u64> = self
+            .graph
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (x.id, i as u64))
+            .collect();
+        for x in self.graph.iter_mut() {
+            x.id = ids[&x.id];
+            x.transitions = x
+                .transitions
+                .drain(..)
+                .filter_map(|t| {
+                    Some(MarkovTransition {
+                        id_to: *ids.get(&t.id_to)?,
+                        probability: t.probability,
+                    })
+                })
+                .collect();
+        }
+    }
+
+    // One LineString feature per street, coordinates in GeoJSON's longitude, latitude order
+    pub fn to_geojson(&self) -> serde_json::Value {
+        let features: Vec<serde_json::Value> = self
+            .graph
+            .iter()
+            .map(|x| {
+                let (travel_time, speed, density) = match &x.traffic_data {
+                    Some(t) => (
+                        t.estimated_travel_time.as_f64(),
+                        t.estimated_average_speed.as_f64(),
+                        t.estimated_density.as_f64(),
+                    ),
+                    None => (f64::NAN, f64::NAN, f64::NAN),
+                };
+                let transitions: Vec<serde_json::Value> = x
+                    .transitions
+                    .iter()
+                    .map(|t| {
+                        serde_json::json!({
+                            "to": t.id_to,
+                            "to_osm": MarkovChain::node(&self.graph, t.id_to).map(|n| n.id_osm),
+                            "probability": t.probability.as_f64(),
+                        })
+                    })
+                    .collect();
+                serde_json::json!({
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "LineString",
+                        "coordinates": [
+                            [x.street_start.longitude, x.street_start.latitude],
+                            [x.street_end.longitude, x.street_end.latitude],
+                        ],
+                    },
+                    "properties": {
+                        "id": x.id,
+                        "osm_id": x.id_osm,
+                        "highway": x.street_data.highway,
+                        "lanes": x.street_data.lanes,
+                        "maxspeed": x.street_data.maxspeed,
+                        "length": x.street_data.length,
+                        "oneway": x.street_data.oneway,
+                        "travel_time": travel_time,
+                        "speed": speed,
+                        "density": density,
+                        "transitions": transitions,
+                    },
+                })
+            })
+            .collect();
+
+        serde_json::json!({
+            "type": "FeatureCollection",
+            "name": self.name,
+            "features": features,
+        })
+    }
+
+    pub fn save_geojson(&self, path: String, data_source_str: String) -> Result<()> {
+        let output_str: String = serde_json::to_string(&self.to_geojson())?;
+
+        let path = format!("{}/markov_chain_{}.geojson", path, data_source_str);
+
+        let mut file = File::create(&path).map_err(|e| Error::io(&path, e))?;
+        file.write_all(output_str.as_bytes())
+            .map_err(|e| Error::io(&path, e))
     }
 }
 
 mod tests {
     #[actix_rt::test]
+    #[cfg_attr(
+        not(feature = "osm-tool-tests"),
+        ignore = "reads what osm_tool downloads, run with --features osm-tool-tests"
+    )]
     async fn new_markov_chain_from_file() {
         let nw = crate::data_reader::NetworkData::new_from_file(
             "jose_mendes".to_string(),
             "output/jose_mendes".to_string(),
-        );
+        )
+        .unwrap();
+        let traffic_source = crate::traffic_source::from_str("osm").await.unwrap();
         let mkv_chain = super::MarkovChain::new_from_network(
-            super::TrafficDataSource::from_str("osm").await,
+            traffic_source.as_ref(),
             nw,
+            &super::TransitionOptions::default(),
         )
-        .await;
+        .await
+        .unwrap();
         for node in mkv_chain.graph {
             println!("NODE ID: {:.?}", node.id);
             println!(
@@ -502,4 +1108,269 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn sparse_row_and_column_access() {
+        let t_mtx = super::TransitionMatrix::new(
+            3,
+            vec![(2, 0, 1.0), (0, 1, 0.25), (0, 0, 0.75), (1, 2, 0.5), (1, 2, 0.5)],
+        );
+
+        assert_eq!(t_mtx.nnz(), 4);
+        assert_eq!(t_mtx.row(0), (&[0, 1][..], &[0.75, 0.25][..]));
+        assert_eq!(t_mtx.column(0), (&[0, 2][..], &[0.75, 1.0][..]));
+        assert_eq!(t_mtx[(1, 2)], 1.0);
+        assert_eq!(t_mtx[(2, 1)], 0.0);
+        assert_eq!(t_mtx.vec_mul(&[1.0, 0.0, 0.0]), vec![0.75, 0.25, 0.0]);
+        assert_eq!(t_mtx.mul_vec(&[1.0, 2.0, 3.0]), vec![1.25, 3.0, 1.0]);
+    }
+
+    #[cfg(test)]
+    fn crossing() -> crate::data_reader::NetworkData {
+        use crate::data_reader::fixtures::{node, one_way};
+
+        // Tests on the crossing read speeds of 30 km/h back
+        let street = |id, start, end| crate::data_reader::Street {
+            maxspeed: 30,
+            ..one_way(id, start, end)
+        };
+        // Street 10 heads north into intersection 2, then north, east, west or back south
+        crate::data_reader::NetworkData::new(
+            "crossing".to_string(),
+            vec![
+                node(1, -0.001, 0.0),
+                node(2, 0.0, 0.0),
+                node(3, 0.001, 0.0),
+                node(4, 0.0, 0.001),
+                node(5, 0.0, -0.001),
+            ],
+            vec![
+                street(10, 1, 2),
+                street(11, 2, 3),
+                street(12, 2, 4),
+                street(13, 2, 5),
+                street(14, 2, 1),
+            ],
+        )
+    }
+
+    #[cfg(test)]
+    fn probability(mkv_chain: &super::MarkovChain, from: usize, to: u64) -> Option<f64> {
+        mkv_chain.graph[from]
+            .transitions
+            .iter()
+            .find(|t| t.id_to == to)
+            .map(|t| t.probability.as_f64())
+    }
+
+    #[actix_rt::test]
+    async fn turn_weighted_transitions() {
+        let options = super::TransitionOptions {
+            turn_weights: "4,2,1,1".parse().unwrap(),
+            forbid_u_turns: true,
+        };
+        let traffic_source = crate::traffic_source::OpenStreetMap;
+        let mkv_chain = super::MarkovChain::new_from_network(&traffic_source, crossing(), &options)
+            .await
+            .unwrap();
+
+        // Equal travel times leave no probability of staying on the street
+        assert_eq!(probability(&mkv_chain, 0, 0), Some(0.0));
+        assert!((probability(&mkv_chain, 0, 1).unwrap() - 4.0 / 7.0).abs() < 1e-12);
+        assert!((probability(&mkv_chain, 0, 2).unwrap() - 2.0 / 7.0).abs() < 1e-12);
+        assert!((probability(&mkv_chain, 0, 3).unwrap() - 1.0 / 7.0).abs() < 1e-12);
+        assert_eq!(probability(&mkv_chain, 0, 4), None);
+
+        let geojson = mkv_chain.to_geojson();
+        let feature = &geojson["features"][0];
+        assert_eq!(geojson["features"].as_array().unwrap().len(), 5);
+        assert_eq!(feature["geometry"]["coordinates"][0][1], -0.001);
+        assert_eq!(feature["properties"]["osm_id"], 10);
+        assert_eq!(feature["properties"]["transitions"][1]["to_osm"], 11);
+        assert_eq!(feature["properties"]["speed"], 30.0);
+    }
+
+    #[actix_rt::test]
+    async fn default_weights_discourage_u_turns() {
+        let traffic_source = crate::traffic_source::OpenStreetMap;
+        let options = super::TransitionOptions::default();
+        let mkv_chain = super::MarkovChain::new_from_network(&traffic_source, crossing(), &options)
+            .await
+            .unwrap();
+
+        // Straight, right, left, then back south, each less likely than the one before
+        let turns: Vec<f64> = (1..=4)
+            .map(|to| probability(&mkv_chain, 0, to).unwrap())
+            .collect();
+        assert!(turns.windows(2).all(|x| x[0] > x[1]));
+        assert!(turns[3] < 0.05);
+    }
+
+    #[actix_rt::test]
+    async fn turn_restrictions_applied() {
+        use crate::data_reader::{RestrictionKind, TurnRestriction};
+
+        let mut nw = crossing();
+        nw.restrictions = vec![
+            TurnRestriction {
+                from: 10,
+                via: 2,
+                to: 13,
+                restriction: RestrictionKind::NoLeftTurn,
+            },
+            TurnRestriction {
+                from: 10,
+                via: 2,
+                to: 14,
+                restriction: RestrictionKind::NoUTurn,
+            },
+        ];
+        let traffic_source = crate::traffic_source::OpenStreetMap;
+        let options = super::TransitionOptions {
+            turn_weights: crate::turns::TurnWeights::uniform(),
+            ..Default::default()
+        };
+        let mkv_chain = super::MarkovChain::new_from_network(&traffic_source, nw, &options)
+            .await
+            .unwrap();
+
+        assert_eq!(probability(&mkv_chain, 0, 1), Some(0.5));
+        assert_eq!(probability(&mkv_chain, 0, 2), Some(0.5));
+        assert_eq!(probability(&mkv_chain, 0, 3), None);
+        assert_eq!(probability(&mkv_chain, 0, 4), None);
+
+        let mut nw = crossing();
+        nw.restrictions = vec![TurnRestriction {
+            from: 10,
+            via: 2,
+            to: 11,
+            restriction: RestrictionKind::OnlyStraightOn,
+        }];
+        let mkv_chain = super::MarkovChain::new_from_network(&traffic_source, nw, &options)
+            .await
+            .unwrap();
+        assert_eq!(probability(&mkv_chain, 0, 1), Some(1.0));
+        assert_eq!(mkv_chain.graph[0].transitions.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn save_and_load_round_trip() {
+        let dir = std::env::temp_dir().join("geomarkover_round_trip");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.to_string_lossy().to_string();
+
+        let traffic_source = crate::traffic_source::OpenStreetMap;
+        let options = super::TransitionOptions::default();
+        let mut mkv_chain =
+            super::MarkovChain::new_from_network(&traffic_source, crossing(), &options)
+                .await
+                .unwrap();
+        let t_mtx = super::TransitionMatrix::new_from_markov_chain(&mkv_chain);
+        mkv_chain.calculate_density_from_matrix(&t_mtx, None);
+        mkv_chain.save_data(path.clone(), "test".to_string()).unwrap();
+        t_mtx.save_to_file(path.clone(), "test".to_string()).unwrap();
+
+        let loaded =
+            super::MarkovChain::load(&format!("{}/markov_chain_test.json", path)).unwrap();
+        assert_eq!(loaded.graph.len(), 5);
+        assert_eq!(probability(&loaded, 0, 1), probability(&mkv_chain, 0, 1));
+        let densities = loaded.densities();
+        assert_eq!(densities[1].density, mkv_chain.densities()[1].density);
+
+        let loaded_mtx =
+            super::TransitionMatrix::load(&format!("{}/transtition_matrix_test.csv", path))
+                .unwrap();
+        assert_eq!(loaded_mtx.dim(), 5);
+        assert_eq!(loaded_mtx[(0, 2)], t_mtx[(0, 2)]);
+
+        // Unversioned output from before ids were graph positions
+        let legacy = std::fs::read_to_string(format!("{}/markov_chain_test.json", path))
+            .unwrap()
+            .replace("\"version\": 1,", "")
+            .replace("\"id\": 0,", "\"id\": 100,")
+            .replace("\"id_to\": 0,", "\"id_to\": 100,");
+        assert!(legacy.contains("\"id_to\": 100") && !legacy.contains("version"));
+        let legacy_path = format!("{}/markov_chain_legacy.json", path);
+        std::fs::write(&legacy_path, legacy).unwrap();
+        let loaded = super::MarkovChain::load(&legacy_path).unwrap();
+        assert_eq!(loaded.graph[0].id, 0);
+        assert_eq!(loaded.graph[0].transitions[0].id_to, 0);
+    }
+
+    #[actix_rt::test]
+    async fn speed_density_feedback() {
+        use crate::speed_density::{SpeedDensity, SpeedDensityModel};
+
+        let traffic_source = crate::traffic_source::OpenStreetMap;
+        // Uniform turns keep the dead ends as fast as the rest, so nothing stays on them
+        let options = super::TransitionOptions {
+            turn_weights: crate::turns::TurnWeights::uniform(),
+            ..Default::default()
+        };
+        let mut nw = crossing();
+        // A wider approach holds more vehicles than the rest of the crossing
+        nw.edges[0].lanes = 4.0;
+        let mut mkv_chain = super::MarkovChain::new_from_network(&traffic_source, nw, &options)
+            .await
+            .unwrap();
+
+        let relation = SpeedDensity::new(SpeedDensityModel::Greenshields);
+        let (t_mtx, report) = mkv_chain.equilibrate(&relation, 1e-9, 200, Some(100));
+        assert!(report.converged);
+        assert_eq!(report.residuals.len(), report.iterations);
+        assert_eq!(t_mtx.dim(), 5);
+        for x in mkv_chain.graph.iter() {
+            let speed = x.traffic_data.as_ref().unwrap().estimated_average_speed.as_f64();
+            assert!(speed > 0.0 && speed <= 30.0);
+            let total: f64 = x.transitions.iter().map(|t| t.probability.as_f64()).sum();
+            assert!(total == 0.0 || (total - 1.0).abs() < 1e-9);
+        }
+
+        // A street the source has no speed for is left out of the feedback
+        let mut mkv_chain =
+            super::MarkovChain::new_from_network(&traffic_source, crossing(), &options)
+                .await
+                .unwrap();
+        mkv_chain.graph[1].traffic_data.as_mut().unwrap().estimated_average_speed =
+            super::Value::Unknown(0.0);
+        let (_, report) = mkv_chain.equilibrate(&relation, 1e-9, 200, Some(100));
+        assert!(report.iterations > 0);
+        let unknown = &mkv_chain.graph[1].traffic_data.as_ref().unwrap();
+        assert!(unknown.estimated_average_speed.as_f64().is_nan());
+    }
+
+    #[actix_rt::test]
+    async fn learn_transitions_from_counts() {
+        let traffic_source = crate::traffic_source::OpenStreetMap;
+        let options = super::TransitionOptions {
+            turn_weights: crate::turns::TurnWeights::uniform(),
+            ..Default::default()
+        };
+        let mut mkv_chain =
+            super::MarkovChain::new_from_network(&traffic_source, crossing(), &options)
+                .await
+                .unwrap();
+
+        // Six vehicles went straight, two turned right, one made a turn the chain forbids
+        let counts = crate::trajectory::TransitionCounts::from([
+            ((0, 1), 6),
+            ((0, 2), 2),
+            ((0, 7), 1),
+        ]);
+        let mut smoothed = super::MarkovChain {
+            version: mkv_chain.version,
+            name: mkv_chain.name.clone(),
+            graph: mkv_chain.graph.clone(),
+        };
+
+        assert_eq!(mkv_chain.learn_transitions(&counts, 0.0), 1);
+        assert_eq!(probability(&mkv_chain, 0, 1), Some(0.75));
+        assert_eq!(probability(&mkv_chain, 0, 3), Some(0.0));
+        assert_eq!(probability(&mkv_chain, 1, 1), probability(&smoothed, 1, 1));
+
+        // Four pseudo-observations spread over the four uniform turns
+        smoothed.learn_transitions(&counts, 4.0);
+        assert_eq!(probability(&smoothed, 0, 1), Some(7.0 / 12.0));
+        assert_eq!(probability(&smoothed, 0, 3), Some(1.0 / 12.0));
+    }
 }
@@ -1,25 +1,77 @@
+use std::io;
+use std::path::Path;
 use std::process::Command;
 
-pub fn get_data_from_place(name: &str, place: &str) {
+use crate::area::Area;
+use crate::data_reader::NetworkData;
+use crate::error::{Error, Result};
+
+pub fn get_data_from_place(name: &str, place: &str) -> Result<()> {
     // poetry -C python-scripts run python3 osm_tool/__init__.py -p "José Mendes, Florianópolis" -n jose_mendes
-    let _ = Command::new("poetry")
+    run_osm_tool(&["-p", place, "-n", name])
+}
+
+// Radius in metres, osmnx downloads the box reaching that far from the center
+pub fn get_data_from_point(name: &str, latitude: f64, longitude: f64, radius: f64) -> Result<()> {
+    run_osm_tool(&[
+        "-a",
+        &latitude.to_string(),
+        "-o",
+        &longitude.to_string(),
+        "-r",
+        &(radius.round() as i64).to_string(),
+        "-n",
+        name,
+    ])
+}
+
+// Clips a larger local network, a directory of nodes.json and edges.json or an OSM extract,
+// to the area
+pub fn get_data_from_area(name: &str, path: &str, area: &Area) -> Result<NetworkData> {
+    let nw = match Path::new(path).is_dir() {
+        true => NetworkData::new_from_file(name.to_string(), path.to_string())?,
+        false => NetworkData::new_from_osm_extract(name.to_string(), path.to_string())?,
+    };
+    Ok(nw.clip(area, false))
+}
+
+fn run_osm_tool(args: &[&str]) -> Result<()> {
+    let status = Command::new("poetry")
         .arg("-C")
         .arg("python-scripts")
         .arg("run")
         .arg("python3")
         .arg("python-scripts/osm_tool/__init__.py")
-        .arg("-p")
-        .arg(place)
-        .arg("-n")
-        .arg(name)
+        .args(args)
         .spawn()
-        .expect("Rust hereby announces that Python forsaken ourselves")
-        .wait();
+        .and_then(|mut child| child.wait())
+        .map_err(|e| Error::io("poetry", e))?;
+
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::io(
+            "poetry",
+            io::Error::other(format!("osm_tool exited with {}", status)),
+        )),
+    }
 }
 
 mod tests {
     #[test]
+    #[cfg_attr(
+        not(feature = "osm-tool-tests"),
+        ignore = "downloads through osm_tool, run with --features osm-tool-tests"
+    )]
     fn get_osm_data() {
-        super::get_data_from_place("jose_mendes", "José Mendes, Florianópolis")
+        super::get_data_from_place("jose_mendes", "José Mendes, Florianópolis").unwrap()
+    }
+
+    #[test]
+    #[cfg_attr(
+        not(feature = "osm-tool-tests"),
+        ignore = "downloads through osm_tool, run with --features osm-tool-tests"
+    )]
+    fn get_osm_data_from_point() {
+        super::get_data_from_point("jose_mendes_point", -27.6161, -48.5225, 500.0).unwrap()
     }
 }
@@ -0,0 +1,601 @@
+/*
+- Read raw OSM elements from a local extract (.osm XML or .osm.pbf)
+- Keep node coordinates, tagged ways and relations for network building
+*/
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use flate2::read::ZlibDecoder;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::error::{Error, Result};
+
+const XML_SOURCE: &str = "OSM XML";
+const PBF_SOURCE: &str = "OSM PBF";
+// Limits of the PBF format, sizes past them come from a corrupt file
+const MAX_BLOB_HEADER_SIZE: usize = 64 * 1024;
+const MAX_BLOB_SIZE: usize = 32 * 1024 * 1024;
+
+type ProtoResult<T> = std::result::Result<T, String>;
+
+#[derive(Debug, Clone)]
+pub struct OsmWay {
+    pub id: u64,
+    pub nodes: Vec<u64>,
+    pub tags: HashMap<String, String>,
+}
+
+impl OsmWay {
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(|x| x.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OsmMemberType {
+    Node,
+    Way,
+    Relation,
+}
+
+#[derive(Debug, Clone)]
+pub struct OsmMember {
+    pub member_type: OsmMemberType,
+    pub id: u64,
+    pub role: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct OsmRelation {
+    pub id: u64,
+    pub members: Vec<OsmMember>,
+    pub tags: HashMap<String, String>,
+}
+
+impl OsmRelation {
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(|x| x.as_str())
+    }
+
+    pub fn members(&self, member_type: OsmMemberType, role: &str) -> Vec<u64> {
+        self.members
+            .iter()
+            .filter(|m| m.member_type == member_type && m.role == role)
+            .map(|m| m.id)
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct OsmExtract {
+    pub nodes: HashMap<u64, (f64, f64)>,
+    pub ways: Vec<OsmWay>,
+    pub relations: Vec<OsmRelation>,
+}
+
+impl OsmExtract {
+    pub fn new_from_file(path: &str) -> Result<Self> {
+        let file = File::open(Path::new(path)).map_err(|e| Error::io(path, e))?;
+        let reader = BufReader::new(file);
+        let extract = if path.ends_with(".pbf") {
+            OsmExtract::new_from_pbf(reader)
+        } else {
+            OsmExtract::new_from_xml(reader)
+        };
+
+        // Reader based errors only know the format, report the actual file instead
+        extract.map_err(|e| match e {
+            Error::Io { source, .. } => Error::io(path, source),
+            Error::Parse { index, message, .. } => Error::parse(path, index, message),
+            e => e,
+        })
+    }
+
+    pub fn new_from_xml<R: BufRead>(reader: R) -> Result<Self> {
+        let mut reader = Reader::from_reader(reader);
+        let mut extract = OsmExtract::default();
+        let mut current_way: Option<OsmWay> = None;
+        let mut current_relation: Option<OsmRelation> = None;
+        let mut records = 0;
+        let mut buf = Vec::new();
+
+        loop {
+            let event = reader
+                .read_event_into(&mut buf)
+                .map_err(|e| Error::parse(XML_SOURCE, Some(records), e))?;
+            match event {
+                Event::Start(ref e) | Event::Empty(ref e) => match e.name().as_ref() {
+                    b"node" => {
+                        records += 1;
+                        let attributes = xml_attributes(e);
+                        let coords = (
+                            attributes.get("id").and_then(|x| x.parse::<u64>().ok()),
+                            attributes.get("lat").and_then(|x| x.parse::<f64>().ok()),
+                            attributes.get("lon").and_then(|x| x.parse::<f64>().ok()),
+                        );
+                        if let (Some(id), Some(lat), Some(lon)) = coords {
+                            extract.nodes.insert(id, (lat, lon));
+                        }
+                    }
+                    b"way" => {
+                        records += 1;
+                        let attributes = xml_attributes(e);
+                        let way = OsmWay {
+                            id: attributes
+                                .get("id")
+                                .and_then(|x| x.parse::<u64>().ok())
+                                .ok_or_else(|| {
+                                    Error::parse(XML_SOURCE, Some(records), "way without id")
+                                })?,
+                            nodes: Vec::new(),
+                            tags: HashMap::new(),
+                        };
+                        if matches!(event, Event::Empty(_)) {
+                            extract.ways.push(way);
+                        } else {
+                            current_way = Some(way);
+                        }
+                    }
+                    b"nd" => {
+                        if let Some(way) = current_way.as_mut() {
+                            if let Some(id) = xml_attributes(e)
+                                .get("ref")
+                                .and_then(|x| x.parse::<u64>().ok())
+                            {
+                                way.nodes.push(id);
+                            }
+                        }
+                    }
+                    b"relation" => {
+                        records += 1;
+                        let attributes = xml_attributes(e);
+                        let relation = OsmRelation {
+                            id: attributes
+                                .get("id")
+                                .and_then(|x| x.parse::<u64>().ok())
+                                .ok_or_else(|| {
+                                    Error::parse(XML_SOURCE, Some(records), "relation without id")
+                                })?,
+                            members: Vec::new(),
+                            tags: HashMap::new(),
+                        };
+                        if matches!(event, Event::Empty(_)) {
+                            extract.relations.push(relation);
+                        } else {
+                            current_relation = Some(relation);
+                        }
+                    }
+                    b"member" => {
+                        if let Some(relation) = current_relation.as_mut() {
+                            let mut attributes = xml_attributes(e);
+                            let member_type = match attributes.get("type").map(|x| x.as_str()) {
+                                Some("node") => Some(OsmMemberType::Node),
+                                Some("way") => Some(OsmMemberType::Way),
+                                Some("relation") => Some(OsmMemberType::Relation),
+                                _ => None,
+                            };
+                            let id = attributes.get("ref").and_then(|x| x.parse::<u64>().ok());
+                            if let (Some(member_type), Some(id)) = (member_type, id) {
+                                relation.members.push(OsmMember {
+                                    member_type,
+                                    id,
+                                    role: attributes.remove("role").unwrap_or_default(),
+                                });
+                            }
+                        }
+                    }
+                    b"tag" => {
+                        let tags = match (current_way.as_mut(), current_relation.as_mut()) {
+                            (Some(way), _) => Some(&mut way.tags),
+                            (None, Some(relation)) => Some(&mut relation.tags),
+                            (None, None) => None,
+                        };
+                        let mut attributes = xml_attributes(e);
+                        if let (Some(tags), Some(k), Some(v)) =
+                            (tags, attributes.remove("k"), attributes.remove("v"))
+                        {
+                            tags.insert(k, v);
+                        }
+                    }
+                    _ => (),
+                },
+                Event::End(e) if e.name().as_ref() == b"way" => {
+                    if let Some(way) = current_way.take() {
+                        extract.ways.push(way);
+                    }
+                }
+                Event::End(e) if e.name().as_ref() == b"relation" => {
+                    if let Some(relation) = current_relation.take() {
+                        extract.relations.push(relation);
+                    }
+                }
+                Event::Eof => break,
+                _ => (),
+            }
+            buf.clear();
+        }
+        Ok(extract)
+    }
+
+    pub fn new_from_pbf<R: Read>(mut reader: R) -> Result<Self> {
+        let mut extract = OsmExtract::default();
+
+        for blob_index in 0.. {
+            let mut size_buf = [0u8; 4];
+            match reader.read_exact(&mut size_buf) {
+                Ok(_) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(Error::io(PBF_SOURCE, e)),
+            }
+            let header_size = u32::from_be_bytes(size_buf) as usize;
+            if header_size > MAX_BLOB_HEADER_SIZE {
+                return Err(Error::parse(
+                    PBF_SOURCE,
+                    Some(blob_index),
+                    format!(
+                        "blob header of {} bytes, at most {} allowed",
+                        header_size, MAX_BLOB_HEADER_SIZE
+                    ),
+                ));
+            }
+            let mut header_buf = vec![0u8; header_size];
+            reader
+                .read_exact(&mut header_buf)
+                .map_err(|e| Error::parse(PBF_SOURCE, Some(blob_index), e))?;
+
+            let blob = pbf_blob_header(&header_buf).and_then(|(blob_type, blob_size)| {
+                if blob_size > MAX_BLOB_SIZE {
+                    return Err(format!(
+                        "blob of {} bytes, at most {} allowed",
+                        blob_size, MAX_BLOB_SIZE
+                    ));
+                }
+                let mut blob_buf = vec![0u8; blob_size];
+                reader
+                    .read_exact(&mut blob_buf)
+                    .map_err(|e| e.to_string())?;
+                match blob_type.as_str() {
+                    "OSMData" => extract.read_primitive_block(&pbf_blob_data(&blob_buf)?),
+                    _ => Ok(()),
+                }
+            });
+            blob.map_err(|e| Error::parse(PBF_SOURCE, Some(blob_index), e))?;
+        }
+        Ok(extract)
+    }
+
+    fn read_primitive_block(&mut self, block: &[u8]) -> ProtoResult<()> {
+        let mut strings: Vec<String> = Vec::new();
+        let mut groups: Vec<&[u8]> = Vec::new();
+        let mut granularity = 100i64;
+        let mut lat_offset = 0i64;
+        let mut lon_offset = 0i64;
+
+        for field in ProtoReader::new(block) {
+            match field? {
+                (1, ProtoValue::Bytes(b)) => {
+                    for s in ProtoReader::new(b) {
+                        if let (_, ProtoValue::Bytes(s)) = s? {
+                            strings.push(String::from_utf8_lossy(s).to_string());
+                        }
+                    }
+                }
+                (2, ProtoValue::Bytes(b)) => groups.push(b),
+                (17, ProtoValue::Varint(v)) => granularity = v as i64,
+                (19, ProtoValue::Varint(v)) => lat_offset = v as i64,
+                (20, ProtoValue::Varint(v)) => lon_offset = v as i64,
+                _ => (),
+            }
+        }
+
+        let coord = |offset: i64, value: i64| 1e-9 * (offset + granularity * value) as f64;
+
+        for group in groups {
+            for field in ProtoReader::new(group) {
+                let (field, ProtoValue::Bytes(b)) = field? else {
+                    continue;
+                };
+                match field {
+                    1 => {
+                        let (mut id, mut lat, mut lon) = (0i64, 0i64, 0i64);
+                        for field in ProtoReader::new(b) {
+                            match field? {
+                                (1, ProtoValue::Varint(v)) => id = zigzag(v),
+                                (8, ProtoValue::Varint(v)) => lat = zigzag(v),
+                                (9, ProtoValue::Varint(v)) => lon = zigzag(v),
+                                _ => (),
+                            }
+                        }
+                        self.nodes
+                            .insert(id as u64, (coord(lat_offset, lat), coord(lon_offset, lon)));
+                    }
+                    2 => {
+                        let (mut ids, mut lats, mut lons) = (Vec::new(), Vec::new(), Vec::new());
+                        for field in ProtoReader::new(b) {
+                            let (field, value) = field?;
+                            match field {
+                                1 => ids = value.packed_sint64()?,
+                                8 => lats = value.packed_sint64()?,
+                                9 => lons = value.packed_sint64()?,
+                                _ => (),
+                            }
+                        }
+                        let (mut id, mut lat, mut lon) = (0i64, 0i64, 0i64);
+                        for i in 0..ids.len().min(lats.len()).min(lons.len()) {
+                            id += ids[i];
+                            lat += lats[i];
+                            lon += lons[i];
+                            self.nodes.insert(
+                                id as u64,
+                                (coord(lat_offset, lat), coord(lon_offset, lon)),
+                            );
+                        }
+                    }
+                    3 => {
+                        let mut way = OsmWay {
+                            id: 0,
+                            nodes: Vec::new(),
+                            tags: HashMap::new(),
+                        };
+                        let (mut keys, mut vals) = (Vec::new(), Vec::new());
+                        for field in ProtoReader::new(b) {
+                            let (field, value) = field?;
+                            match field {
+                                1 => way.id = value.packed_uint64()?.first().copied().unwrap_or(0),
+                                2 => keys = value.packed_uint64()?,
+                                3 => vals = value.packed_uint64()?,
+                                8 => {
+                                    let mut id = 0i64;
+                                    for delta in value.packed_sint64()? {
+                                        id += delta;
+                                        way.nodes.push(id as u64);
+                                    }
+                                }
+                                _ => (),
+                            }
+                        }
+                        for (k, v) in keys.iter().zip(vals.iter()) {
+                            if let (Some(k), Some(v)) =
+                                (strings.get(*k as usize), strings.get(*v as usize))
+                            {
+                                way.tags.insert(k.clone(), v.clone());
+                            }
+                        }
+                        self.ways.push(way);
+                    }
+                    4 => {
+                        let mut relation = OsmRelation {
+                            id: 0,
+                            members: Vec::new(),
+                            tags: HashMap::new(),
+                        };
+                        let (mut keys, mut vals) = (Vec::new(), Vec::new());
+                        let (mut roles, mut ids, mut types) = (Vec::new(), Vec::new(), Vec::new());
+                        for field in ProtoReader::new(b) {
+                            let (field, value) = field?;
+                            match field {
+                                1 => {
+                                    relation.id =
+                                        value.packed_uint64()?.first().copied().unwrap_or(0)
+                                }
+                                2 => keys = value.packed_uint64()?,
+                                3 => vals = value.packed_uint64()?,
+                                8 => roles = value.packed_uint64()?,
+                                9 => ids = value.packed_sint64()?,
+                                10 => types = value.packed_uint64()?,
+                                _ => (),
+                            }
+                        }
+                        for (k, v) in keys.iter().zip(vals.iter()) {
+                            if let (Some(k), Some(v)) =
+                                (strings.get(*k as usize), strings.get(*v as usize))
+                            {
+                                relation.tags.insert(k.clone(), v.clone());
+                            }
+                        }
+                        let mut id = 0i64;
+                        for ((role, delta), member_type) in
+                            roles.iter().zip(ids.iter()).zip(types.iter())
+                        {
+                            id += delta;
+                            let member_type = match member_type {
+                                0 => OsmMemberType::Node,
+                                1 => OsmMemberType::Way,
+                                _ => OsmMemberType::Relation,
+                            };
+                            relation.members.push(OsmMember {
+                                member_type,
+                                id: id as u64,
+                                role: strings.get(*role as usize).cloned().unwrap_or_default(),
+                            });
+                        }
+                        self.relations.push(relation);
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn xml_attributes(e: &BytesStart) -> HashMap<String, String> {
+    e.attributes()
+        .filter_map(|a| a.ok())
+        .filter_map(|a| {
+            let key = String::from_utf8_lossy(a.key.as_ref()).to_string();
+            let value = a.unescape_value().ok()?.to_string();
+            Some((key, value))
+        })
+        .collect()
+}
+
+fn pbf_blob_header(header: &[u8]) -> ProtoResult<(String, usize)> {
+    let mut blob_type = String::new();
+    let mut blob_size = 0usize;
+    for field in ProtoReader::new(header) {
+        match field? {
+            (1, ProtoValue::Bytes(b)) => blob_type = String::from_utf8_lossy(b).to_string(),
+            (3, ProtoValue::Varint(v)) => blob_size = v as usize,
+            _ => (),
+        }
+    }
+    Ok((blob_type, blob_size))
+}
+
+fn pbf_blob_data(blob: &[u8]) -> ProtoResult<Vec<u8>> {
+    for field in ProtoReader::new(blob) {
+        match field? {
+            (1, ProtoValue::Bytes(b)) => return Ok(b.to_vec()),
+            (3, ProtoValue::Bytes(b)) => {
+                let mut data = Vec::new();
+                ZlibDecoder::new(b)
+                    .take(MAX_BLOB_SIZE as u64 + 1)
+                    .read_to_end(&mut data)
+                    .map_err(|e| e.to_string())?;
+                if data.len() > MAX_BLOB_SIZE {
+                    return Err(format!("blob inflates past {} bytes", MAX_BLOB_SIZE));
+                }
+                return Ok(data);
+            }
+            _ => (),
+        }
+    }
+    Err("blob uses an unsupported compression".to_string())
+}
+
+fn zigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+enum ProtoValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+impl ProtoValue<'_> {
+    fn packed_uint64(&self) -> ProtoResult<Vec<u64>> {
+        match self {
+            ProtoValue::Varint(v) => Ok(vec![*v]),
+            ProtoValue::Bytes(b) => {
+                let mut reader = ProtoReader::new(b);
+                let mut values = Vec::new();
+                while reader.pos < b.len() {
+                    values.push(reader.varint()?);
+                }
+                Ok(values)
+            }
+            ProtoValue::Fixed => Ok(Vec::new()),
+        }
+    }
+
+    fn packed_sint64(&self) -> ProtoResult<Vec<i64>> {
+        Ok(self.packed_uint64()?.into_iter().map(zigzag).collect())
+    }
+}
+
+// Minimal protobuf wire format reader, enough for the OSM PBF schema
+struct ProtoReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        ProtoReader { buf, pos: 0 }
+    }
+
+    fn varint(&mut self) -> ProtoResult<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = *self
+                .buf
+                .get(self.pos)
+                .filter(|_| shift < 64)
+                .ok_or("varint was not well-formatted")?;
+            self.pos += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> ProtoResult<&'a [u8]> {
+        let start = self.pos;
+        self.pos = start.saturating_add(len);
+        self.buf
+            .get(start..self.pos)
+            .ok_or_else(|| "field was truncated".to_string())
+    }
+
+    fn field(&mut self) -> ProtoResult<(u32, ProtoValue<'a>)> {
+        let key = self.varint()?;
+        let field = (key >> 3) as u32;
+        let value = match key & 0x7 {
+            0 => ProtoValue::Varint(self.varint()?),
+            1 => {
+                self.bytes(8)?;
+                ProtoValue::Fixed
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                ProtoValue::Bytes(self.bytes(len)?)
+            }
+            5 => {
+                self.bytes(4)?;
+                ProtoValue::Fixed
+            }
+            t => return Err(format!("field uses unsupported wire type {t}")),
+        };
+        Ok((field, value))
+    }
+}
+
+impl<'a> Iterator for ProtoReader<'a> {
+    type Item = ProtoResult<(u32, ProtoValue<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.buf.len() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_err() {
+            // Nothing after a malformed field can be trusted
+            self.pos = self.buf.len();
+        }
+        Some(field)
+    }
+}
+
+mod tests {
+    #[test]
+    fn oversized_pbf_blobs_are_rejected() {
+        use super::OsmExtract;
+
+        // A blob header claiming 1 GiB, and a well sized header announcing a 1 GiB blob
+        let huge_header = (1u32 << 30).to_be_bytes();
+        let error = OsmExtract::new_from_pbf(&huge_header[..])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("at most"), "{}", error);
+
+        let header = [
+            &[0x0a, 0x07][..],
+            b"OSMData",
+            &[0x18, 0x80, 0x80, 0x80, 0x80, 0x04],
+        ]
+        .concat();
+        let file = [&(header.len() as u32).to_be_bytes()[..], &header].concat();
+        let error = OsmExtract::new_from_pbf(&file[..]).unwrap_err().to_string();
+        assert!(error.contains("at most"), "{}", error);
+
+        assert!(OsmExtract::new_from_pbf(&[][..]).is_ok());
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+use crate::diagnostics::ROW_SUM_TOLERANCE;
+use crate::error::{Error, Result};
+use crate::markov_chain::TransitionMatrix;
+use crate::stationary::{solve_dense, DENSE_SOLVE_MAX_DIM};
+
+// Rows summing to less than this lose vehicles, which may then never reach a target
+const LEAK_TOLERANCE: f64 = 1e-9;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct FirstPassage {
+    pub targets: Vec<usize>,
+    // Probability of ever reaching a target, from each street
+    pub hitting_probabilities: Vec<f64>,
+    // Expected steps until the first target, zero on the targets themselves and infinite
+    // wherever a target may never be reached
+    pub mean_steps: Vec<f64>,
+    pub iterations: usize,
+    pub residual: f64,
+    pub converged: bool,
+}
+
+impl TransitionMatrix {
+    // Gauss-Seidel sweeps of h = P h and m = 1 + P m outside the targets. Which streets
+    // reach a target surely is settled from the graph first, so the sweeps for m only
+    // run where they converge.
+    pub fn first_passage(
+        &self,
+        targets: &[usize],
+        tolerance: f64,
+        max_iterations: usize,
+    ) -> Result<FirstPassage> {
+        let dim = self.dim();
+        if targets.is_empty() {
+            return Err(Error::InvalidInput("no target streets".to_string()));
+        }
+        if let Some(i) = targets.iter().find(|i| **i >= dim) {
+            return Err(Error::InvalidInput(format!("no street {}", i)));
+        }
+        let mut is_target = vec![false; dim];
+        targets.iter().for_each(|i| is_target[*i] = true);
+        let sure = self.surely_hitting(&is_target);
+
+        let mut hitting: Vec<f64> = (0..dim)
+            .map(|i| if is_target[i] || sure[i] { 1.0 } else { 0.0 })
+            .collect();
+        let mut mean_steps: Vec<f64> = (0..dim)
+            .map(|i| {
+                if is_target[i] || sure[i] {
+                    0.0
+                } else {
+                    f64::INFINITY
+                }
+            })
+            .collect();
+
+        let mut iterations = 0;
+        let mut residual = f64::INFINITY;
+        while iterations < max_iterations && residual > tolerance {
+            residual = 0.0;
+            for i in (0..dim).filter(|i| !is_target[*i]) {
+                let (cols, values) = self.row(i);
+                let known = || {
+                    cols.iter()
+                        .zip(values.iter())
+                        .filter(|(_, p)| p.is_finite())
+                };
+                let (previous, next) = match sure[i] {
+                    true => (
+                        mean_steps[i],
+                        1.0 + known().map(|(j, p)| p * mean_steps[*j]).sum::<f64>(),
+                    ),
+                    false => (
+                        hitting[i],
+                        known().map(|(j, p)| p * hitting[*j]).sum::<f64>(),
+                    ),
+                };
+                residual = f64::max(residual, (next - previous).abs());
+                match sure[i] {
+                    true => mean_steps[i] = next,
+                    false => hitting[i] = next,
+                }
+            }
+            iterations += 1;
+        }
+
+        Ok(FirstPassage {
+            targets: targets.to_vec(),
+            hitting_probabilities: hitting,
+            mean_steps,
+            iterations,
+            residual,
+            converged: residual <= tolerance,
+        })
+    }
+
+    // A street misses the targets with positive probability when a path avoiding them
+    // leads to a leaking row or to a street with no path to a target at all
+    fn surely_hitting(&self, is_target: &[bool]) -> Vec<bool> {
+        let dim = self.dim();
+        let predecessors = |j: usize| {
+            let (rows, values) = self.column(j);
+            rows.iter()
+                .zip(values.iter())
+                .filter(|(_, p)| p.is_finite() && **p > 0.0)
+                .map(|(i, _)| *i)
+                .collect::<Vec<usize>>()
+        };
+
+        let mut reaches = is_target.to_vec();
+        let mut queue: VecDeque<usize> = (0..dim).filter(|i| is_target[*i]).collect();
+        while let Some(j) = queue.pop_front() {
+            for i in predecessors(j) {
+                if !reaches[i] {
+                    reaches[i] = true;
+                    queue.push_back(i);
+                }
+            }
+        }
+
+        let mut misses: Vec<bool> = (0..dim)
+            .map(|i| {
+                let (_, values) = self.row(i);
+                let sum: f64 = values.iter().filter(|p| p.is_finite()).sum();
+                !is_target[i] && (!reaches[i] || sum < 1.0 - LEAK_TOLERANCE)
+            })
+            .collect();
+        let mut queue: VecDeque<usize> = (0..dim).filter(|i| misses[*i]).collect();
+        while let Some(j) = queue.pop_front() {
+            for i in predecessors(j) {
+                if !misses[i] && !is_target[i] {
+                    misses[i] = true;
+                    queue.push_back(i);
+                }
+            }
+        }
+
+        (0..dim).map(|i| !is_target[i] && !misses[i]).collect()
+    }
+
+    // Expected steps from any street to a street drawn from the stationary distribution,
+    // trace((I - P + 1π)⁻¹) - 1. Needs an irreducible stochastic matrix.
+    pub fn kemeny_constant(&self) -> Result<f64> {
+        let dim = self.dim();
+        let diagnostics = self.diagnose(ROW_SUM_TOLERANCE);
+        if !diagnostics.irreducible() || !diagnostics.row_issues.is_empty() {
+            return Err(Error::Solver(
+                "the Kemeny constant needs an irreducible chain with rows summing to one"
+                    .to_string(),
+            ));
+        }
+        if dim > DENSE_SOLVE_MAX_DIM {
+            return Err(Error::Solver(format!(
+                "dense solve limited to {} states, matrix has {}",
+                DENSE_SOLVE_MAX_DIM, dim
+            )));
+        }
+        let pi = self.stationary_distribution_dense()?.distribution;
+
+        let mut system: Vec<Vec<f64>> = (0..dim)
+            .map(|i| {
+                let mut row = pi.clone();
+                row[i] += 1.0;
+                row.extend((0..dim).map(|j| if i == j { 1.0 } else { 0.0 }));
+                row
+            })
+            .collect();
+        for (from, to, p) in self.entries().filter(|(_, _, p)| p.is_finite()) {
+            system[from][to] -= p;
+        }
+        let inverse = solve_dense(system)
+            .ok_or_else(|| Error::Solver("singular fundamental matrix".to_string()))?;
+        Ok((0..dim).map(|i| inverse[i][i]).sum::<f64>() - 1.0)
+    }
+}
+
+mod tests {
+    #[test]
+    fn passage_times() {
+        let t_mtx = crate::markov_chain::TransitionMatrix::new(
+            2,
+            vec![(0, 0, 0.9), (0, 1, 0.1), (1, 0, 0.5), (1, 1, 0.5)],
+        );
+        let to_second = t_mtx.first_passage(&[1], 1e-12, 10_000).unwrap();
+        assert!(to_second.converged);
+        assert!((to_second.mean_steps[0] - 10.0).abs() < 1e-9);
+        assert_eq!(to_second.mean_steps[1], 0.0);
+        let to_first = t_mtx.first_passage(&[0], 1e-12, 10_000).unwrap();
+        assert!((to_first.mean_steps[1] - 2.0).abs() < 1e-9);
+        // Second eigenvalue 0.4
+        assert!((t_mtx.kemeny_constant().unwrap() - 1.0 / 0.6).abs() < 1e-9);
+
+        // Half of the vehicles on street 0 leave the network instead
+        let leaking = crate::markov_chain::TransitionMatrix::new(
+            3,
+            vec![(0, 1, 0.5), (1, 1, 1.0), (2, 0, 1.0)],
+        );
+        let passage = leaking.first_passage(&[1], 1e-12, 10_000).unwrap();
+        assert!((passage.hitting_probabilities[2] - 0.5).abs() < 1e-12);
+        assert_eq!(passage.mean_steps[0], f64::INFINITY);
+        assert!(leaking.first_passage(&[3], 1e-12, 10).is_err());
+        assert!(leaking.kemeny_constant().is_err());
+    }
+}
@@ -0,0 +1,709 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::markov_chain::{EdgeState, MarkovChain};
+
+// Space on the right of the map for the legend, in pixels
+const LEGEND_WIDTH: u32 = 320;
+const MARGIN: f64 = 20.0;
+
+// Start and end in pixels, and colour
+type Stroke = ((f64, f64), (f64, f64), Rgb);
+
+// Python tool bins in vehicles per km per lane: green, lime, orange, red, dark red
+const DENSITY_BREAKS: [f64; 4] = [7.0, 16.0, 22.0, 28.0];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+// Streets without a value
+pub const NAN_COLOR: Rgb = Rgb(158, 158, 158);
+
+impl Rgb {
+    pub fn from_hex(s: &str) -> Result<Self> {
+        let hex = s.trim().trim_start_matches('#');
+        let channel = |i: usize| {
+            hex.get(i..i + 2)
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+                .ok_or_else(|| Error::InvalidInput(format!("bad colour '{}'", s)))
+        };
+        match hex.len() {
+            6 => Ok(Rgb(channel(0)?, channel(2)?, channel(4)?)),
+            _ => Err(Error::InvalidInput(format!("bad colour '{}'", s))),
+        }
+    }
+
+    pub fn to_hex(self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.0, self.1, self.2)
+    }
+
+    fn lerp(self, other: Rgb, t: f64) -> Rgb {
+        let channel = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+        Rgb(
+            channel(self.0, other.0),
+            channel(self.1, other.1),
+            channel(self.2, other.2),
+        )
+    }
+}
+
+pub fn named_ramp(name: &str) -> Result<Vec<Rgb>> {
+    let hex: &[&str] = match name {
+        "traffic" => &["#008000", "#00ff00", "#ffa500", "#ff0000", "#8b0000"],
+        "traffic_r" => &["#8b0000", "#ff0000", "#ffa500", "#00ff00", "#008000"],
+        "viridis" => &["#440154", "#3b528b", "#21918c", "#5ec962", "#fde725"],
+        "diverging" => &["#2166ac", "#92c5de", "#f7f7f7", "#f4a582", "#b2182b"],
+        "greys" => &["#f0f0f0", "#bdbdbd", "#737373", "#252525"],
+        _ => {
+            return Err(Error::InvalidInput(format!(
+            "unknown colour ramp '{}', expected traffic, traffic_r, viridis, diverging or greys",
+            name
+        )))
+        }
+    };
+    hex.iter().map(|x| Rgb::from_hex(x)).collect()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    Density,
+    Speed,
+    TravelTime,
+}
+
+impl std::str::FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "density" => Ok(Metric::Density),
+            "speed" => Ok(Metric::Speed),
+            "travel_time" | "travel-time" => Ok(Metric::TravelTime),
+            _ => Err(format!(
+                "expected density, speed or travel_time, got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+impl Metric {
+    // Densities in vehicles per km per lane, speeds in km/h, travel times in seconds
+    pub fn value(&self, edge: &EdgeState) -> f64 {
+        match self {
+            Metric::Density => edge.density * 1000.0,
+            Metric::Speed => edge.speed,
+            Metric::TravelTime => edge.travel_time * 3600.0,
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        match self {
+            Metric::Density => "density (veh/km/lane)",
+            Metric::Speed => "speed (km/h)",
+            Metric::TravelTime => "travel time (s)",
+        }
+    }
+}
+
+// Continuous ramp between two values, or one colour per class when there are breaks
+#[derive(Debug, Clone)]
+pub struct ColorScale {
+    colors: Vec<Rgb>,
+    breaks: Vec<f64>,
+    range: (f64, f64),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScaleOptions {
+    pub colors: Option<Vec<Rgb>>,
+    pub breaks: Vec<f64>,
+    pub range: Option<(f64, f64)>,
+}
+
+impl ColorScale {
+    // Colours are resampled to one per class when their count does not match
+    pub fn classes(colors: &[Rgb], breaks: Vec<f64>) -> Result<Self> {
+        if colors.is_empty() {
+            return Err(Error::InvalidInput(
+                "colour scale without colours".to_string(),
+            ));
+        }
+        if breaks
+            .windows(2)
+            .any(|w| w[0].partial_cmp(&w[1]) != Some(Ordering::Less))
+        {
+            return Err(Error::InvalidInput(
+                "class breaks must be increasing".to_string(),
+            ));
+        }
+        Ok(ColorScale {
+            colors: resample(colors, breaks.len() + 1),
+            range: (
+                breaks.first().copied().unwrap_or(0.0),
+                breaks.last().copied().unwrap_or(0.0),
+            ),
+            breaks,
+        })
+    }
+
+    pub fn ramp(colors: &[Rgb], min: f64, max: f64) -> Result<Self> {
+        if colors.is_empty() {
+            return Err(Error::InvalidInput(
+                "colour scale without colours".to_string(),
+            ));
+        }
+        Ok(ColorScale {
+            colors: colors.to_vec(),
+            breaks: Vec::new(),
+            range: (min, max),
+        })
+    }
+
+    // Density keeps the Python tool's classes, everything else ramps over the values.
+    // Differences ramp over a range centred on zero.
+    pub fn for_values(
+        metric: Metric,
+        diff: bool,
+        values: &[f64],
+        options: &ScaleOptions,
+    ) -> Result<Self> {
+        let colors = match &options.colors {
+            Some(colors) => colors.clone(),
+            None => named_ramp(match (diff, metric) {
+                (true, _) => "diverging",
+                (false, Metric::Density) => "traffic",
+                (false, Metric::Speed) => "traffic_r",
+                (false, Metric::TravelTime) => "viridis",
+            })?,
+        };
+        let default_classes = metric == Metric::Density
+            && !diff
+            && options.colors.is_none()
+            && options.range.is_none();
+        if !options.breaks.is_empty() || default_classes {
+            let breaks = match options.breaks.is_empty() {
+                true => DENSITY_BREAKS.to_vec(),
+                false => options.breaks.clone(),
+            };
+            return ColorScale::classes(&colors, breaks);
+        }
+
+        let finite = values.iter().copied().filter(|v| v.is_finite());
+        let (min, max) = match (options.range, diff) {
+            (Some(range), _) => range,
+            (None, true) => {
+                let extent = finite.map(f64::abs).fold(0.0, f64::max);
+                (-extent, extent)
+            }
+            (None, false) => finite.fold((f64::INFINITY, f64::NEG_INFINITY), |(a, b), v| {
+                (a.min(v), b.max(v))
+            }),
+        };
+        match min.is_finite() && max.is_finite() {
+            true => ColorScale::ramp(&colors, min, max),
+            false => ColorScale::ramp(&colors, 0.0, 0.0),
+        }
+    }
+
+    pub fn color(&self, value: f64) -> Rgb {
+        if !value.is_finite() {
+            return NAN_COLOR;
+        }
+        if !self.breaks.is_empty() {
+            let class = self.breaks.iter().take_while(|b| value > **b).count();
+            return self.colors[class];
+        }
+
+        let (min, max) = self.range;
+        let t = match max > min {
+            true => ((value - min) / (max - min)).clamp(0.0, 1.0),
+            false => 0.5,
+        };
+        let position = t * (self.colors.len() - 1) as f64;
+        let i = (position.floor() as usize).min(self.colors.len() - 1);
+        match self.colors.get(i + 1) {
+            Some(next) => self.colors[i].lerp(*next, position - i as f64),
+            None => self.colors[i],
+        }
+    }
+
+    // Swatches and labels, one per class or five along a ramp
+    pub fn legend(&self) -> Vec<(Rgb, String)> {
+        if !self.breaks.is_empty() {
+            let last = self.breaks.len() - 1;
+            return self
+                .colors
+                .iter()
+                .enumerate()
+                .map(|(i, color)| {
+                    let label = match i {
+                        0 => format!("<= {}", format_value(self.breaks[0])),
+                        i if i > last => format!("> {}", format_value(self.breaks[last])),
+                        i => format!(
+                            "{} - {}",
+                            format_value(self.breaks[i - 1]),
+                            format_value(self.breaks[i])
+                        ),
+                    };
+                    (*color, label)
+                })
+                .collect();
+        }
+
+        let (min, max) = self.range;
+        (0..5)
+            .map(|i| {
+                let value = min + (max - min) * i as f64 / 4.0;
+                (self.color(value), format_value(value))
+            })
+            .collect()
+    }
+}
+
+fn resample(colors: &[Rgb], count: usize) -> Vec<Rgb> {
+    if colors.len() == count {
+        return colors.to_vec();
+    }
+    let ramp = ColorScale {
+        colors: colors.to_vec(),
+        breaks: Vec::new(),
+        range: (0.0, 1.0),
+    };
+    (0..count)
+        .map(|i| match count {
+            1 => colors[0],
+            _ => ramp.color(i as f64 / (count - 1) as f64),
+        })
+        .collect()
+}
+
+fn format_value(value: f64) -> String {
+    match value.abs() {
+        v if v >= 100.0 => format!("{:.0}", value),
+        v if v >= 10.0 => format!("{:.1}", value),
+        _ => format!("{:.2}", value),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Segment {
+    // (latitude, longitude)
+    pub start: (f64, f64),
+    pub end: (f64, f64),
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RenderOptions {
+    // Whole image, legend included
+    pub width: u32,
+    pub height: u32,
+    pub line_width: f64,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            width: 2048,
+            height: 2048,
+            line_width: 2.0,
+        }
+    }
+}
+
+pub struct Map {
+    pub title: String,
+    pub segments: Vec<Segment>,
+    pub scale: ColorScale,
+}
+
+impl Map {
+    pub fn new(mkv_chain: &MarkovChain, metric: Metric, options: &ScaleOptions) -> Result<Self> {
+        let segments: Vec<Segment> = mkv_chain
+            .edge_states()
+            .iter()
+            .map(|x| Segment {
+                start: x.start_position,
+                end: x.end_position,
+                value: metric.value(x),
+            })
+            .collect();
+        let values: Vec<f64> = segments.iter().map(|x| x.value).collect();
+        Ok(Map {
+            title: metric.label().to_string(),
+            scale: ColorScale::for_values(metric, false, &values, options)?,
+            segments,
+        })
+    }
+
+    // Other minus baseline, matched by way and endpoints. Streets missing from either
+    // chain have no value.
+    pub fn diff(
+        baseline: &MarkovChain,
+        other: &MarkovChain,
+        metric: Metric,
+        options: &ScaleOptions,
+    ) -> Result<Self> {
+        let key = |x: &EdgeState| (x.id_osm, x.start, x.end);
+        let mut baseline_states: HashMap<(u64, u64, u64), EdgeState> = baseline
+            .edge_states()
+            .into_iter()
+            .map(|x| (key(&x), x))
+            .collect();
+
+        let mut segments: Vec<Segment> = other
+            .edge_states()
+            .iter()
+            .map(|x| Segment {
+                start: x.start_position,
+                end: x.end_position,
+                value: match baseline_states.remove(&key(x)) {
+                    Some(b) => metric.value(x) - metric.value(&b),
+                    None => f64::NAN,
+                },
+            })
+            .collect();
+        segments.extend(baseline_states.values().map(|x| Segment {
+            start: x.start_position,
+            end: x.end_position,
+            value: f64::NAN,
+        }));
+
+        let values: Vec<f64> = segments.iter().map(|x| x.value).collect();
+        Ok(Map {
+            title: format!("{} change", metric.label()),
+            scale: ColorScale::for_values(metric, true, &values, options)?,
+            segments,
+        })
+    }
+
+    // Screen coordinates and colour of every segment, streets without a value first so
+    // the coloured ones are drawn over them. Each direction of a two-way street is drawn
+    // on its own side of the road, as driving on the right.
+    fn layout(&self, options: &RenderOptions) -> Vec<Stroke> {
+        let positions = self.segments.iter().flat_map(|x| [x.start, x.end]);
+        let (min_lat, max_lat, min_lon, max_lon) = positions.clone().fold(
+            (
+                f64::INFINITY,
+                f64::NEG_INFINITY,
+                f64::INFINITY,
+                f64::NEG_INFINITY,
+            ),
+            |(a, b, c, d), (lat, lon)| (a.min(lat), b.max(lat), c.min(lon), d.max(lon)),
+        );
+        if !min_lat.is_finite() {
+            return Vec::new();
+        }
+        // Equirectangular, good enough at city scale
+        let cos_lat = ((min_lat + max_lat) / 2.0).to_radians().cos();
+        let map_width = options.width.saturating_sub(LEGEND_WIDTH) as f64 - 2.0 * MARGIN;
+        let map_height = options.height as f64 - 2.0 * MARGIN;
+        let extent_x = ((max_lon - min_lon) * cos_lat).max(f64::EPSILON);
+        let extent_y = (max_lat - min_lat).max(f64::EPSILON);
+        let scale = (map_width / extent_x).min(map_height / extent_y);
+        let offset_x = MARGIN + (map_width - extent_x * scale) / 2.0;
+        let offset_y = MARGIN + (map_height - extent_y * scale) / 2.0;
+        let project = |(lat, lon): (f64, f64)| {
+            (
+                offset_x + (lon - min_lon) * cos_lat * scale,
+                options.height as f64 - offset_y - (lat - min_lat) * scale,
+            )
+        };
+
+        let mut segments: Vec<&Segment> = self.segments.iter().collect();
+        segments.sort_by_key(|x| x.value.is_finite());
+        segments
+            .into_iter()
+            .map(|x| {
+                let (a, b) = (project(x.start), project(x.end));
+                let length = ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2)).sqrt();
+                let shift = match length > 0.0 {
+                    true => 0.6 * options.line_width / length,
+                    false => 0.0,
+                };
+                let (nx, ny) = (-(b.1 - a.1) * shift, (b.0 - a.0) * shift);
+                (
+                    (a.0 + nx, a.1 + ny),
+                    (b.0 + nx, b.1 + ny),
+                    self.scale.color(x.value),
+                )
+            })
+            .collect()
+    }
+
+    pub fn to_svg(&self, options: &RenderOptions) -> String {
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+             viewBox=\"0 0 {w} {h}\">\n<rect width=\"{w}\" height=\"{h}\" fill=\"#ffffff\"/>\n\
+             <g stroke-width=\"{lw}\" stroke-linecap=\"round\">\n",
+            w = options.width,
+            h = options.height,
+            lw = options.line_width
+        );
+        for (a, b, color) in self.layout(options) {
+            svg.push_str(&format!(
+                "<line x1=\"{:.2}\" y1=\"{:.2}\" x2=\"{:.2}\" y2=\"{:.2}\" stroke=\"{}\"/>\n",
+                a.0,
+                a.1,
+                b.0,
+                b.1,
+                color.to_hex()
+            ));
+        }
+        svg.push_str("</g>\n<g font-family=\"sans-serif\" font-size=\"16\" fill=\"#000000\">\n");
+
+        let x = options.width.saturating_sub(LEGEND_WIDTH) as f64 + MARGIN;
+        svg.push_str(&format!(
+            "<text x=\"{}\" y=\"{}\" font-weight=\"bold\">{}</text>\n",
+            x,
+            MARGIN + 20.0,
+            self.title
+        ));
+        for (i, (color, label)) in self.scale.legend().iter().enumerate() {
+            let y = MARGIN + 44.0 + 28.0 * i as f64;
+            svg.push_str(&format!(
+                "<rect x=\"{}\" y=\"{}\" width=\"30\" height=\"16\" fill=\"{}\"/>\n\
+                 <text x=\"{}\" y=\"{}\">{}</text>\n",
+                x,
+                y,
+                color.to_hex(),
+                x + 40.0,
+                y + 14.0,
+                label.replace('<', "&lt;").replace('>', "&gt;")
+            ));
+        }
+        svg.push_str("</g>\n</svg>\n");
+        svg
+    }
+
+    pub fn to_png(&self, options: &RenderOptions) -> Result<Vec<u8>> {
+        let mut canvas = Canvas::new(options.width, options.height);
+        for (a, b, color) in self.layout(options) {
+            canvas.line(a, b, options.line_width / 2.0, color);
+        }
+
+        let x = options.width.saturating_sub(LEGEND_WIDTH) as f64 + MARGIN;
+        canvas.text(x, MARGIN + 6.0, &self.title, Rgb(0, 0, 0));
+        for (i, (color, label)) in self.scale.legend().iter().enumerate() {
+            let y = MARGIN + 44.0 + 28.0 * i as f64;
+            canvas.rect(x, y, 30.0, 16.0, *color);
+            canvas.text(x + 40.0, y + 1.0, label, Rgb(0, 0, 0));
+        }
+
+        let mut png_data: Vec<u8> = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png_data, options.width, options.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&canvas.pixels))
+            .map_err(|e| Error::InvalidInput(format!("could not encode image: {}", e)))?;
+        Ok(png_data)
+    }
+
+    // Format from the extension, .svg or .png
+    pub fn save(&self, path: &str, options: &RenderOptions) -> Result<()> {
+        let data = match path.rsplit('.').next().map(|x| x.to_lowercase()).as_deref() {
+            Some("svg") => self.to_svg(options).into_bytes(),
+            Some("png") => self.to_png(options)?,
+            _ => {
+                return Err(Error::InvalidInput(format!(
+                    "{} is neither .png nor .svg",
+                    path
+                )))
+            }
+        };
+        fs::write(path, data).map_err(|e| Error::io(path, e))
+    }
+}
+
+// White RGB raster
+struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: u32, height: u32) -> Self {
+        Canvas {
+            width,
+            height,
+            pixels: vec![255; width as usize * height as usize * 3],
+        }
+    }
+
+    fn blend(&mut self, x: i64, y: i64, color: Rgb, coverage: f64) {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return;
+        }
+        let i = (y as usize * self.width as usize + x as usize) * 3;
+        for (channel, value) in [color.0, color.1, color.2].into_iter().enumerate() {
+            let old = self.pixels[i + channel] as f64;
+            self.pixels[i + channel] = (old + (value as f64 - old) * coverage).round() as u8;
+        }
+    }
+
+    // Antialiased by how far each pixel centre is from the segment
+    fn line(&mut self, a: (f64, f64), b: (f64, f64), half_width: f64, color: Rgb) {
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+        let length_sq = dx * dx + dy * dy;
+        let reach = half_width + 1.0;
+        let (x0, x1) = (a.0.min(b.0) - reach, a.0.max(b.0) + reach);
+        let (y0, y1) = (a.1.min(b.1) - reach, a.1.max(b.1) + reach);
+
+        for y in y0.floor() as i64..=y1.ceil() as i64 {
+            for x in x0.floor() as i64..=x1.ceil() as i64 {
+                let (px, py) = (x as f64 + 0.5, y as f64 + 0.5);
+                let t = match length_sq > 0.0 {
+                    true => (((px - a.0) * dx + (py - a.1) * dy) / length_sq).clamp(0.0, 1.0),
+                    false => 0.0,
+                };
+                let distance = ((px - a.0 - t * dx).powi(2) + (py - a.1 - t * dy).powi(2)).sqrt();
+                let coverage = (half_width + 0.5 - distance).clamp(0.0, 1.0);
+                if coverage > 0.0 {
+                    self.blend(x, y, color, coverage);
+                }
+            }
+        }
+    }
+
+    fn rect(&mut self, x: f64, y: f64, width: f64, height: f64, color: Rgb) {
+        for py in y as i64..(y + height) as i64 {
+            for px in x as i64..(x + width) as i64 {
+                self.blend(px, py, color, 1.0);
+            }
+        }
+    }
+
+    // 5x7 glyphs doubled, uppercase only
+    fn text(&mut self, x: f64, y: f64, text: &str, color: Rgb) {
+        for (i, c) in text.chars().enumerate() {
+            let left = x as i64 + i as i64 * 12;
+            for (row, bits) in glyph(c.to_ascii_uppercase()).iter().enumerate() {
+                for column in 0..5 {
+                    if bits & (0x10 >> column) != 0 {
+                        let (px, py) = (left + column * 2, y as i64 + row as i64 * 2);
+                        for (ox, oy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                            self.blend(px + ox, py + oy, color, 1.0);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn glyph(c: char) -> [u8; 7] {
+    match c {
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '+' => [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00],
+        '=' => [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00],
+        '<' => [0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02],
+        '>' => [0x08, 0x04, 0x02, 0x01, 0x02, 0x04, 0x08],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+        _ => [0x00; 7],
+    }
+}
+
+mod tests {
+    #[actix_rt::test]
+    async fn render_density_map() {
+        use crate::data_reader::fixtures::{node, street};
+        use crate::data_reader::NetworkData;
+        use crate::render::{ColorScale, Map, Metric, RenderOptions, Rgb, ScaleOptions};
+
+        let classes =
+            ColorScale::for_values(Metric::Density, false, &[], &Default::default()).unwrap();
+        assert_eq!(classes.color(10.0), Rgb(0, 255, 0));
+        assert_eq!(classes.color(f64::NAN), super::NAN_COLOR);
+        assert_eq!(classes.legend()[4].1, "> 28.0");
+        let ramp = ColorScale::ramp(&[Rgb(0, 0, 0), Rgb(255, 255, 255)], 0.0, 10.0).unwrap();
+        assert_eq!(ramp.color(5.0), Rgb(128, 128, 128));
+
+        let nw = NetworkData::new(
+            "line".to_string(),
+            vec![node(1, -27.6, -48.5), node(2, -27.6, -48.499)],
+            vec![street(10, 2, 1), street(10, 1, 2)],
+        );
+        let mkv_chain = crate::markov_chain::MarkovChain::new_from_network(
+            &crate::traffic_source::OpenStreetMap,
+            nw,
+            &crate::markov_chain::TransitionOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let options = RenderOptions {
+            width: 600,
+            height: 200,
+            line_width: 4.0,
+        };
+        let map = Map::new(&mkv_chain, Metric::Speed, &ScaleOptions::default()).unwrap();
+        let svg = map.to_svg(&options);
+        assert_eq!(svg.matches("<line").count(), 2);
+        assert!(svg.contains("speed (km/h)"));
+
+        let png_data = map.to_png(&options).unwrap();
+        let decoder = png::Decoder::new(&png_data[..]);
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().width, 600);
+
+        let diff = Map::diff(
+            &mkv_chain,
+            &mkv_chain,
+            Metric::Speed,
+            &ScaleOptions::default(),
+        )
+        .unwrap();
+        assert!(diff.segments.iter().all(|x| x.value == 0.0));
+    }
+}
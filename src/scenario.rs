@@ -0,0 +1,385 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::data_reader::{NetworkData, Street};
+use crate::error::{Error, Result};
+use crate::markov_chain::{MarkovChain, TransitionMatrix, TransitionOptions};
+use crate::traffic_source::TrafficSource;
+
+// Changes to every street of an OSM way, fields left out keep their value
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct StreetEdit {
+    pub id_osm: u64,
+    #[serde(default)]
+    pub lanes: Option<f64>,
+    #[serde(default)]
+    pub maxspeed: Option<u8>,
+    #[serde(default)]
+    pub oneway: Option<bool>,
+    // The direction kept when making a two-way road one-way
+    #[serde(default)]
+    pub direction: Option<Direction>,
+    #[serde(default)]
+    pub closed: bool,
+}
+
+// Two intersections along a way, traffic goes from the first towards the second
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Direction {
+    pub from: u64,
+    pub to: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Scenario {
+    #[serde(default = "default_name")]
+    pub name: String,
+    pub edits: Vec<StreetEdit>,
+}
+
+fn default_name() -> String {
+    "scenario".to_string()
+}
+
+// Baseline and scenario values of a street, NaN on the side it does not exist in
+#[derive(Debug, Serialize, Clone)]
+pub struct EdgeDiff {
+    pub id_osm: u64,
+    pub start: u64,
+    pub end: u64,
+    pub baseline_travel_time: f64,
+    pub scenario_travel_time: f64,
+    pub baseline_density: f64,
+    pub scenario_density: f64,
+}
+
+impl Scenario {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        serde_json::from_str(&content).map_err(|e| Error::parse(path, None, e))
+    }
+
+    // Edits apply in order, so a later edit of the same way sees the earlier ones
+    pub fn apply(&self, network_graph: &NetworkData) -> Result<NetworkData> {
+        let mut nw = network_graph.clone();
+        for edit in self.edits.iter() {
+            if !nw.edges.iter().any(|x| x.id == edit.id_osm) {
+                return Err(Error::InvalidInput(format!(
+                    "no street with way id {}",
+                    edit.id_osm
+                )));
+            }
+            if edit.closed {
+                nw = nw.without_way(edit.id_osm);
+                continue;
+            }
+
+            for x in nw.edges.iter_mut().filter(|x| x.id == edit.id_osm) {
+                x.lanes = edit.lanes.unwrap_or(x.lanes);
+                x.maxspeed = edit.maxspeed.unwrap_or(x.maxspeed);
+            }
+            match edit.oneway {
+                Some(true) => nw.edges = one_way(nw.edges, edit.id_osm, edit.direction)?,
+                Some(false) => nw.edges = two_way(nw.edges, edit.id_osm),
+                None => (),
+            }
+        }
+        Ok(nw)
+    }
+
+    // Both chains spread the baseline's vehicle count over their stationary distribution
+    pub async fn run(
+        &self,
+        traffic_source: &dyn TrafficSource,
+        network_graph: &NetworkData,
+        options: &TransitionOptions,
+        tolerance: f64,
+        max_iterations: usize,
+    ) -> Result<(MarkovChain, MarkovChain)> {
+        let scenario_graph = self.apply(network_graph)?;
+        let mut baseline =
+            MarkovChain::new_from_network(traffic_source, network_graph.clone(), options).await?;
+        let mut scenario =
+            MarkovChain::new_from_network(traffic_source, scenario_graph, options).await?;
+
+        let vehicle_count = baseline.estimate_vehicle_count();
+        for mkv_chain in [&mut baseline, &mut scenario] {
+            let stationary = TransitionMatrix::new_from_markov_chain(mkv_chain)
+                .stationary_distribution(tolerance, max_iterations);
+            mkv_chain.calculate_density_from_stationary(&stationary, Some(vehicle_count));
+        }
+        Ok((baseline, scenario))
+    }
+}
+
+// Keeps the streets of the way leading away from the direction's first intersection, hops
+// along the way telling which end of a street is further. Streets without a twin stay, and
+// are reversed when they run against the direction.
+fn one_way(edges: Vec<Street>, id_osm: u64, direction: Option<Direction>) -> Result<Vec<Street>> {
+    let twin = |x: &Street| {
+        edges
+            .iter()
+            .any(|y| y.id == id_osm && y.start == x.end && y.end == x.start)
+    };
+    let paired = edges.iter().any(|x| x.id == id_osm && twin(x));
+
+    let mut hops: HashMap<u64, usize> = HashMap::new();
+    match (direction, paired) {
+        (Some(direction), _) => {
+            hops.insert(direction.from, 0);
+            let mut queue = VecDeque::from([direction.from]);
+            while let Some(node) = queue.pop_front() {
+                let next = edges
+                    .iter()
+                    .filter(|x| x.id == id_osm)
+                    .filter_map(|x| match (x.start == node, x.end == node) {
+                        (true, _) => Some(x.end),
+                        (_, true) => Some(x.start),
+                        _ => None,
+                    })
+                    .collect::<Vec<u64>>();
+                for id in next {
+                    if !hops.contains_key(&id) {
+                        hops.insert(id, hops[&node] + 1);
+                        queue.push_back(id);
+                    }
+                }
+            }
+            if !hops.contains_key(&direction.to) {
+                return Err(Error::InvalidInput(format!(
+                    "intersections {} and {} are not connected along way {}",
+                    direction.from, direction.to, id_osm
+                )));
+            }
+        }
+        (None, true) => {
+            return Err(Error::InvalidInput(format!(
+                "making way {} one-way needs a direction",
+                id_osm
+            )))
+        }
+        (None, false) => (),
+    }
+
+    let mut kept: Vec<Street> = Vec::with_capacity(edges.len());
+    for x in edges.iter() {
+        if x.id != id_osm {
+            kept.push(x.clone());
+            continue;
+        }
+        if !twin(x) {
+            let mut x = x.clone();
+            if let (Some(start), Some(end)) = (hops.get(&x.start), hops.get(&x.end)) {
+                if start > end {
+                    std::mem::swap(&mut x.start, &mut x.end);
+                }
+            }
+            kept.push(x);
+            continue;
+        }
+        match (hops.get(&x.start), hops.get(&x.end)) {
+            (Some(start), Some(end)) if start < end => kept.push(x.clone()),
+            (Some(_), Some(_)) => (),
+            _ => {
+                return Err(Error::InvalidInput(format!(
+                    "street from {} to {} of way {} is not connected to {}",
+                    x.start,
+                    x.end,
+                    id_osm,
+                    direction.map_or(0, |d| d.from)
+                )))
+            }
+        }
+    }
+    kept.iter_mut()
+        .filter(|x| x.id == id_osm)
+        .for_each(|x| x.oneway = true);
+    Ok(kept)
+}
+
+fn two_way(edges: Vec<Street>, id_osm: u64) -> Vec<Street> {
+    let mut both: Vec<Street> = Vec::with_capacity(edges.len());
+    for x in edges.iter() {
+        let lone = x.id == id_osm
+            && !edges
+                .iter()
+                .any(|y| y.id == id_osm && y.start == x.end && y.end == x.start);
+        if lone {
+            both.push(Street {
+                start: x.end,
+                end: x.start,
+                oneway: false,
+                ..x.clone()
+            });
+        }
+        both.push(Street {
+            oneway: x.oneway && x.id != id_osm,
+            ..x.clone()
+        });
+    }
+    both
+}
+
+// Streets are matched by way and endpoints, ids differ once streets are added or removed
+pub fn diff(baseline: &MarkovChain, scenario: &MarkovChain) -> Vec<EdgeDiff> {
+    let scenario_states: HashMap<(u64, u64, u64), (f64, f64)> = scenario
+        .edge_states()
+        .into_iter()
+        .map(|x| ((x.id_osm, x.start, x.end), (x.travel_time, x.density)))
+        .collect();
+
+    let mut matched: HashSet<(u64, u64, u64)> = HashSet::new();
+    let mut diffs: Vec<EdgeDiff> = baseline
+        .edge_states()
+        .into_iter()
+        .map(|x| {
+            let key = (x.id_osm, x.start, x.end);
+            let (travel_time, density) = match scenario_states.get(&key) {
+                Some(state) => {
+                    matched.insert(key);
+                    *state
+                }
+                None => (f64::NAN, f64::NAN),
+            };
+            EdgeDiff {
+                id_osm: x.id_osm,
+                start: x.start,
+                end: x.end,
+                baseline_travel_time: x.travel_time,
+                scenario_travel_time: travel_time,
+                baseline_density: x.density,
+                scenario_density: density,
+            }
+        })
+        .collect();
+
+    diffs.extend(
+        scenario
+            .edge_states()
+            .into_iter()
+            .filter(|x| !matched.contains(&(x.id_osm, x.start, x.end)))
+            .map(|x| EdgeDiff {
+                id_osm: x.id_osm,
+                start: x.start,
+                end: x.end,
+                baseline_travel_time: f64::NAN,
+                scenario_travel_time: x.travel_time,
+                baseline_density: f64::NAN,
+                scenario_density: x.density,
+            }),
+    );
+    diffs
+}
+
+pub fn save_csv(diffs: &[EdgeDiff], path: &str) -> Result<()> {
+    let file = File::create(path).map_err(|e| Error::io(path, e))?;
+    let mut file = BufWriter::new(file);
+
+    file.write_all(
+        b"id_osm,start,end,baseline_travel_time,scenario_travel_time,travel_time_change,\
+          baseline_density,scenario_density,density_change\n",
+    )
+    .map_err(|e| Error::io(path, e))?;
+    for x in diffs {
+        let line = format!(
+            "{},{},{},{},{},{},{},{},{}\n",
+            x.id_osm,
+            x.start,
+            x.end,
+            x.baseline_travel_time,
+            x.scenario_travel_time,
+            x.scenario_travel_time - x.baseline_travel_time,
+            x.baseline_density,
+            x.scenario_density,
+            x.scenario_density - x.baseline_density
+        );
+        file.write_all(line.as_bytes())
+            .map_err(|e| Error::io(path, e))?;
+    }
+    file.flush().map_err(|e| Error::io(path, e))
+}
+
+mod tests {
+    #[actix_rt::test]
+    async fn lane_speed_and_oneway_edits() {
+        use crate::data_reader::fixtures::{node, one_way, street};
+        use crate::data_reader::NetworkData;
+
+        // Way 10 is two-way from 1 to 2, way 11 one-way from 2 to 3
+        let nw = NetworkData::new(
+            "line".to_string(),
+            vec![node(1, 0.0, 0.0), node(2, 0.0, 0.001), node(3, 0.0, 0.002)],
+            vec![street(10, 2, 1), street(10, 1, 2), one_way(11, 2, 3)],
+        );
+
+        let scenario: super::Scenario = serde_json::from_str(
+            r#"{"edits": [
+                {"id_osm": 10, "oneway": true, "direction": {"from": 1, "to": 2}, "maxspeed": 30},
+                {"id_osm": 11, "oneway": false, "lanes": 3}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(scenario.name, "scenario");
+        let edited = scenario.apply(&nw).unwrap();
+        let ends: Vec<(u64, u64, u64)> = edited
+            .edges
+            .iter()
+            .map(|x| (x.id, x.start, x.end))
+            .collect();
+        assert_eq!(ends, vec![(10, 1, 2), (11, 3, 2), (11, 2, 3)]);
+        assert!(edited.edges[0].oneway && edited.edges[0].maxspeed == 30);
+        assert!(!edited.edges[1].oneway && edited.edges[1].lanes == 3.0);
+
+        let (baseline, changed) = scenario
+            .run(
+                &crate::traffic_source::OpenStreetMap,
+                &nw,
+                &crate::markov_chain::TransitionOptions::default(),
+                1e-10,
+                10_000,
+            )
+            .await
+            .unwrap();
+        let diffs = super::diff(&baseline, &changed);
+        assert_eq!(diffs.len(), 4);
+        // The street from 2 back to 1 is gone, the one from 3 to 2 is new
+        assert!(diffs[0].scenario_travel_time.is_nan());
+        assert!(diffs[1].scenario_travel_time > diffs[1].baseline_travel_time);
+        assert_eq!((diffs[3].start, diffs[3].end), (3, 2));
+        assert!(diffs[3].baseline_density.is_nan());
+
+        let closure: super::Scenario = serde_json::from_str(
+            r#"{"name": "closed", "edits": [{"id_osm": 99, "closed": true}]}"#,
+        )
+        .unwrap();
+        assert!(closure.apply(&nw).is_err());
+
+        // The direction is matched on intersections, whatever order the twins come in
+        let against: super::Scenario = serde_json::from_str(
+            r#"{"edits": [{"id_osm": 10, "oneway": true, "direction": {"from": 2, "to": 1}}]}"#,
+        )
+        .unwrap();
+        let mut swapped = nw.clone();
+        swapped.edges.swap(0, 1);
+        for nw in [&nw, &swapped] {
+            let edited = against.apply(nw).unwrap();
+            assert_eq!((edited.edges[0].start, edited.edges[0].end), (2, 1));
+            assert_eq!(edited.edges.len(), 2);
+        }
+        let unknown: super::Scenario =
+            serde_json::from_str(r#"{"edits": [{"id_osm": 10, "oneway": true}]}"#).unwrap();
+        assert!(unknown.apply(&nw).is_err());
+
+        // A one-way way driven the other way round is reversed
+        let reversed: super::Scenario = serde_json::from_str(
+            r#"{"edits": [{"id_osm": 11, "oneway": true, "direction": {"from": 3, "to": 2}}]}"#,
+        )
+        .unwrap();
+        let edited = reversed.apply(&nw).unwrap();
+        assert_eq!((edited.edges[2].start, edited.edges[2].end), (3, 2));
+        assert!(edited.edges[2].oneway);
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use actix_web::http::StatusCode;
+use actix_web::{web, App, HttpResponse, HttpServer, ResponseError};
+use serde::{Deserialize, Serialize};
+
+use crate::data_reader::{Intersection, NetworkData, Street, TurnRestriction};
+use crate::error::{Error, Result};
+use crate::markov_chain::{MarkovChain, TransitionMatrix, TransitionOptions};
+use crate::stationary::StationaryDistribution;
+use crate::traffic_source;
+
+// Uploaded networks easily exceed actix's 32 KiB default JSON limit
+const MAX_UPLOAD_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct NetworkUpload {
+    pub nodes: Vec<Intersection>,
+    pub edges: Vec<Street>,
+    #[serde(default)]
+    pub restrictions: Vec<TurnRestriction>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChainRequest {
+    #[serde(default = "default_datasource")]
+    pub datasource: String,
+    #[serde(default, flatten)]
+    pub options: TransitionOptions,
+    #[serde(default)]
+    pub stationary: bool,
+    #[serde(flatten)]
+    pub solver: SolverParams,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SolverParams {
+    #[serde(default = "default_tolerance")]
+    pub tolerance: f64,
+    #[serde(default = "default_max_iterations")]
+    pub max_iterations: usize,
+}
+
+fn default_datasource() -> String {
+    "osm".to_string()
+}
+
+fn default_tolerance() -> f64 {
+    1e-10
+}
+
+fn default_max_iterations() -> usize {
+    10000
+}
+
+#[derive(Debug, Serialize)]
+struct NetworkSummary {
+    name: String,
+    nodes: usize,
+    edges: usize,
+    restrictions: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct ChainSummary {
+    name: String,
+    datasource: String,
+    states: usize,
+    transitions: usize,
+    vehicle_count: u64,
+    stationary: Option<StationaryDistribution>,
+}
+
+#[derive(Debug, Serialize)]
+struct MatrixResponse {
+    dim: usize,
+    nnz: usize,
+    entries: Vec<(usize, usize, f64)>,
+}
+
+struct ChainResults {
+    chain: MarkovChain,
+    matrix: TransitionMatrix,
+    stationary: Option<StationaryDistribution>,
+}
+
+struct Session {
+    network: NetworkData,
+    results: Option<ChainResults>,
+}
+
+// Networks and the chains built from them, keyed by network name
+#[derive(Default)]
+pub struct AppState {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+#[derive(Debug)]
+enum ApiError {
+    UnknownNetwork(String),
+    NoChain(String),
+    Failed(Error),
+}
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        ApiError::Failed(e)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::UnknownNetwork(name) => write!(f, "No network named '{}'", name),
+            ApiError::NoChain(name) => write!(f, "No markov chain built for '{}'", name),
+            ApiError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::UnknownNetwork(_) | ApiError::NoChain(_) => StatusCode::NOT_FOUND,
+            ApiError::Failed(Error::DanglingEndpoint { .. })
+            | ApiError::Failed(Error::Parse { .. })
+            | ApiError::Failed(Error::InvalidInput(_)) => StatusCode::BAD_REQUEST,
+            ApiError::Failed(Error::TrafficProvider { .. }) => StatusCode::BAD_GATEWAY,
+            ApiError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .json(serde_json::json!({ "error": self.to_string() }))
+    }
+}
+
+type ApiResult = std::result::Result<HttpResponse, ApiError>;
+
+async fn upload_network(
+    state: web::Data<AppState>,
+    name: web::Path<String>,
+    upload: web::Json<NetworkUpload>,
+) -> HttpResponse {
+    let name = name.into_inner();
+    let upload = upload.into_inner();
+    let mut network = NetworkData::new(name.clone(), upload.nodes, upload.edges);
+    network.restrictions = upload.restrictions;
+
+    let summary = NetworkSummary {
+        name: name.clone(),
+        nodes: network.nodes.len(),
+        edges: network.edges.len(),
+        restrictions: network.restrictions.len(),
+    };
+    let session = Session {
+        network,
+        results: None,
+    };
+    state.sessions.lock().unwrap().insert(name, session);
+    HttpResponse::Created().json(summary)
+}
+
+async fn build_chain(
+    state: web::Data<AppState>,
+    name: web::Path<String>,
+    request: web::Json<ChainRequest>,
+) -> ApiResult {
+    let name = name.into_inner();
+    let network = match state.sessions.lock().unwrap().get(&name) {
+        Some(session) => session.network.clone(),
+        None => return Err(ApiError::UnknownNetwork(name)),
+    };
+
+    let data_source = traffic_source::from_str(&request.datasource).await?;
+    let mut chain =
+        MarkovChain::new_from_network(data_source.as_ref(), network, &request.options).await?;
+    let matrix = TransitionMatrix::new_from_markov_chain(&chain);
+    let stationary = match request.stationary {
+        true => {
+            let stationary = matrix
+                .stationary_distribution(request.solver.tolerance, request.solver.max_iterations);
+            chain.calculate_density_from_stationary(&stationary, None);
+            Some(stationary)
+        }
+        false => {
+            chain.calculate_density_from_matrix(&matrix, None);
+            None
+        }
+    };
+
+    let summary = ChainSummary {
+        name: name.clone(),
+        datasource: request.datasource.clone(),
+        states: matrix.dim(),
+        transitions: matrix.nnz(),
+        vehicle_count: chain.estimate_vehicle_count(),
+        stationary: stationary.clone(),
+    };
+    match state.sessions.lock().unwrap().get_mut(&name) {
+        Some(session) => {
+            session.results = Some(ChainResults {
+                chain,
+                matrix,
+                stationary,
+            })
+        }
+        None => return Err(ApiError::UnknownNetwork(name)),
+    }
+    Ok(HttpResponse::Created().json(summary))
+}
+
+fn with_results<T: Serialize>(
+    state: &AppState,
+    name: String,
+    f: impl FnOnce(&mut ChainResults) -> T,
+) -> ApiResult {
+    let mut sessions = state.sessions.lock().unwrap();
+    let session = sessions
+        .get_mut(&name)
+        .ok_or_else(|| ApiError::UnknownNetwork(name.clone()))?;
+    let results = session.results.as_mut().ok_or(ApiError::NoChain(name))?;
+    Ok(HttpResponse::Ok().json(f(results)))
+}
+
+async fn get_matrix(state: web::Data<AppState>, name: web::Path<String>) -> ApiResult {
+    with_results(&state, name.into_inner(), |results| MatrixResponse {
+        dim: results.matrix.dim(),
+        nnz: results.matrix.nnz(),
+        entries: results.matrix.entries().collect(),
+    })
+}
+
+async fn get_densities(state: web::Data<AppState>, name: web::Path<String>) -> ApiResult {
+    with_results(&state, name.into_inner(), |results| {
+        results.chain.densities()
+    })
+}
+
+async fn get_geojson(state: web::Data<AppState>, name: web::Path<String>) -> ApiResult {
+    with_results(&state, name.into_inner(), |results| {
+        results.chain.to_geojson()
+    })
+}
+
+// Solved on first request unless the chain was built with a stationary density
+async fn get_stationary(
+    state: web::Data<AppState>,
+    name: web::Path<String>,
+    params: web::Query<SolverParams>,
+) -> ApiResult {
+    with_results(&state, name.into_inner(), |results| {
+        results
+            .stationary
+            .get_or_insert_with(|| {
+                results
+                    .matrix
+                    .stationary_distribution(params.tolerance, params.max_iterations)
+            })
+            .clone()
+    })
+}
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(web::JsonConfig::default().limit(MAX_UPLOAD_BYTES))
+        .route("/networks/{name}", web::put().to(upload_network))
+        .route("/networks/{name}/chain", web::post().to(build_chain))
+        .route("/networks/{name}/matrix", web::get().to(get_matrix))
+        .route("/networks/{name}/densities", web::get().to(get_densities))
+        .route("/networks/{name}/geojson", web::get().to(get_geojson))
+        .route("/networks/{name}/stationary", web::get().to(get_stationary));
+}
+
+pub async fn serve(address: &str, port: u16) -> Result<()> {
+    let bind_address = format!("{}:{}", address, port);
+    let state = web::Data::new(AppState::default());
+    HttpServer::new(move || App::new().app_data(state.clone()).configure(routes))
+        .bind((address, port))
+        .map_err(|e| Error::io(&bind_address, e))?
+        .run()
+        .await
+        .map_err(|e| Error::io(&bind_address, e))
+}
+
+mod tests {
+    #[actix_rt::test]
+    async fn upload_build_and_query() {
+        use actix_web::{test, web, App};
+
+        let state = web::Data::new(super::AppState::default());
+        let app =
+            test::init_service(App::new().app_data(state.clone()).configure(super::routes)).await;
+
+        let network = serde_json::json!({
+            "nodes": [
+                {"id": 1, "latitude": 0.0, "longitude": 0.0},
+                {"id": 2, "latitude": 0.001, "longitude": 0.0}
+            ],
+            "edges": [
+                {"id": 10, "start": 1, "end": 2, "lanes": 1.0, "maxspeed": 30,
+                 "length": 100.0, "oneway": false, "highway": "residential"},
+                {"id": 10, "start": 2, "end": 1, "lanes": 1.0, "maxspeed": 30,
+                 "length": 100.0, "oneway": false, "highway": "residential"}
+            ]
+        });
+        let request = test::TestRequest::put()
+            .uri("/networks/loop")
+            .set_json(&network)
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 201);
+
+        let request = test::TestRequest::get()
+            .uri("/networks/loop/densities")
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 404);
+
+        let request = test::TestRequest::post()
+            .uri("/networks/loop/chain")
+            .set_json(serde_json::json!({"datasource": "osm", "stationary": true}))
+            .to_request();
+        let summary: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(summary["states"], 2);
+        assert_eq!(summary["transitions"], 4);
+
+        let request = test::TestRequest::get()
+            .uri("/networks/loop/stationary")
+            .to_request();
+        let stationary: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        let share = stationary["distribution"][0].as_f64().unwrap();
+        assert!((share - 0.5).abs() < 1e-9);
+
+        let request = test::TestRequest::get()
+            .uri("/networks/loop/matrix")
+            .to_request();
+        let matrix: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(matrix["dim"], 2);
+    }
+}
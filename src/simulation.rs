@@ -0,0 +1,207 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+use crate::markov_chain::{MarkovChain, TransitionMatrix};
+
+#[derive(Debug, Clone)]
+pub enum InitialDistribution {
+    // Same density on every street
+    Uniform,
+    // Vehicles on each street, by markov node id
+    Counts(Vec<f64>),
+    // Same density on the streets of the given OSM ways, none elsewhere
+    Concentrated(Vec<u64>),
+}
+
+// Vehicles and densities of every street at each step, indexed [step][street]
+#[derive(Debug, Serialize, Clone)]
+pub struct Simulation {
+    pub ids: Vec<u64>,
+    pub ids_osm: Vec<u64>,
+    pub vehicles: Vec<Vec<f64>>,
+    pub densities: Vec<Vec<f64>>,
+}
+
+impl Simulation {
+    // Moves the initial vehicles through the matrix for the given number of steps.
+    // Vehicles on streets without outgoing transitions leave the network.
+    pub fn run(
+        mkv_chain: &MarkovChain,
+        t_mtx: &TransitionMatrix,
+        initial: &InitialDistribution,
+        vehicle_count: Option<u64>,
+        steps: usize,
+    ) -> Result<Self> {
+        let lane_lengths = mkv_chain.lane_lengths();
+        let vehicle_count =
+            vehicle_count.unwrap_or_else(|| mkv_chain.estimate_vehicle_count()) as f64;
+
+        let spread = |streets: &[usize]| {
+            let capacity: f64 = streets.iter().map(|i| lane_lengths[*i]).sum();
+            let mut vehicles = vec![0.0; lane_lengths.len()];
+            for i in streets {
+                vehicles[*i] = vehicle_count * lane_lengths[*i] / capacity;
+            }
+            vehicles
+        };
+        let start = match initial {
+            InitialDistribution::Uniform => spread(&(0..lane_lengths.len()).collect::<Vec<_>>()),
+            InitialDistribution::Counts(counts) if counts.len() == lane_lengths.len() => {
+                counts.clone()
+            }
+            InitialDistribution::Counts(counts) => {
+                return Err(Error::InvalidInput(format!(
+                    "{} vehicle counts for {} streets",
+                    counts.len(),
+                    lane_lengths.len()
+                )))
+            }
+            InitialDistribution::Concentrated(ways) => {
+                let mut streets: Vec<usize> = Vec::new();
+                for way in ways {
+                    match mkv_chain.nodes_of_way(*way) {
+                        v if v.is_empty() => {
+                            return Err(Error::InvalidInput(format!(
+                                "no street with way id {}",
+                                way
+                            )))
+                        }
+                        v => streets.extend(v),
+                    }
+                }
+                spread(&streets)
+            }
+        };
+
+        let mut vehicles = vec![start];
+        for _ in 0..steps {
+            vehicles.push(step(t_mtx, vehicles.last().unwrap()));
+        }
+        let densities = vehicles
+            .iter()
+            .map(|v| {
+                v.iter()
+                    .zip(lane_lengths.iter())
+                    .map(|(n, l)| n / l)
+                    .collect()
+            })
+            .collect();
+
+        let (ids, ids_osm) = mkv_chain
+            .densities()
+            .into_iter()
+            .map(|x| (x.id, x.id_osm))
+            .unzip();
+        Ok(Simulation {
+            ids,
+            ids_osm,
+            vehicles,
+            densities,
+        })
+    }
+
+    pub fn total_vehicles(&self, step: usize) -> f64 {
+        self.vehicles.get(step).map_or(0.0, |v| v.iter().sum())
+    }
+
+    // Long format, one row per step and street
+    pub fn save_csv(&self, path: &str) -> Result<()> {
+        let file = File::create(path).map_err(|e| Error::io(path, e))?;
+        let mut file = BufWriter::new(file);
+
+        file.write_all(b"step,id,id_osm,vehicles,density\n")
+            .map_err(|e| Error::io(path, e))?;
+        for (step, (vehicles, densities)) in
+            self.vehicles.iter().zip(self.densities.iter()).enumerate()
+        {
+            for (i, (n, d)) in vehicles.iter().zip(densities.iter()).enumerate() {
+                let line = format!("{},{},{},{},{}\n", step, self.ids[i], self.ids_osm[i], n, d);
+                file.write_all(line.as_bytes())
+                    .map_err(|e| Error::io(path, e))?;
+            }
+        }
+        file.flush().map_err(|e| Error::io(path, e))
+    }
+
+    pub fn save_json(&self, path: &str) -> Result<()> {
+        let output_str: String = serde_json::to_string(&self)?;
+        fs::write(path, output_str).map_err(|e| Error::io(path, e))
+    }
+}
+
+// One step x P, skipping transitions without a known probability
+fn step(t_mtx: &TransitionMatrix, vehicles: &[f64]) -> Vec<f64> {
+    (0..t_mtx.dim())
+        .map(|j| {
+            let (rows, values) = t_mtx.column(j);
+            rows.iter()
+                .zip(values.iter())
+                .filter(|(_, p)| p.is_finite())
+                .map(|(i, p)| vehicles[*i] * p)
+                .sum()
+        })
+        .collect()
+}
+
+// Reads "id,vehicles" rows into a vector over all streets, streets not listed are empty
+pub fn read_counts(path: &str, dim: usize) -> Result<Vec<f64>> {
+    let content = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+    let mut counts = vec![0.0; dim];
+    for (i, line) in content.lines().enumerate() {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        match fields.as_slice() {
+            [""] => continue,
+            [id, _] if i == 0 && id.parse::<usize>().is_err() => continue,
+            [id, vehicles] => {
+                let id: usize = id.parse().map_err(|e| Error::parse(path, Some(i), e))?;
+                let vehicles: f64 = vehicles
+                    .parse()
+                    .map_err(|e| Error::parse(path, Some(i), e))?;
+                match counts.get_mut(id) {
+                    Some(c) => *c += vehicles,
+                    None => return Err(Error::parse(path, Some(i), format!("no street {}", id))),
+                }
+            }
+            _ => return Err(Error::parse(path, Some(i), "expected id,vehicles")),
+        }
+    }
+    Ok(counts)
+}
+
+mod tests {
+    #[actix_rt::test]
+    async fn vehicles_move_between_streets() {
+        use crate::data_reader::fixtures::{node, one_way};
+        use crate::data_reader::NetworkData;
+
+        // A dead-end pair of streets, vehicles either stay or turn back
+        let nw = NetworkData::new(
+            "pair".to_string(),
+            vec![node(1, 0.0, 0.0), node(2, 0.001, 0.0)],
+            vec![one_way(10, 1, 2), one_way(11, 2, 1)],
+        );
+        let mkv_chain = crate::markov_chain::MarkovChain::new_from_network(
+            &crate::traffic_source::OpenStreetMap,
+            nw,
+            &crate::markov_chain::TransitionOptions::default(),
+        )
+        .await
+        .unwrap();
+        let t_mtx = crate::markov_chain::TransitionMatrix::new_from_markov_chain(&mkv_chain);
+
+        let initial = super::InitialDistribution::Concentrated(vec![10]);
+        let simulation = super::Simulation::run(&mkv_chain, &t_mtx, &initial, Some(10), 3).unwrap();
+        assert_eq!(simulation.vehicles.len(), 4);
+        assert_eq!(simulation.vehicles[0], vec![10.0, 0.0]);
+        assert!(simulation.vehicles[1][1] > 0.0);
+        assert_eq!(simulation.densities[0], vec![0.1, 0.0]);
+        // Every street has an exit, so no vehicle leaves
+        assert!((simulation.total_vehicles(3) - 10.0).abs() < 1e-9);
+
+        let unknown = super::InitialDistribution::Concentrated(vec![99]);
+        assert!(super::Simulation::run(&mkv_chain, &t_mtx, &unknown, None, 1).is_err());
+    }
+}
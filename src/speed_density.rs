@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+
+// Speeds never drop below this, a stopped street would have an infinite travel time
+const MIN_SPEED: f64 = 1.0;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpeedDensityModel {
+    Greenshields,
+    Greenberg,
+    Underwood,
+}
+
+impl std::str::FromStr for SpeedDensityModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "greenshields" => Ok(SpeedDensityModel::Greenshields),
+            "greenberg" => Ok(SpeedDensityModel::Greenberg),
+            "underwood" => Ok(SpeedDensityModel::Underwood),
+            _ => Err(format!(
+                "expected greenshields, greenberg or underwood, got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+// Densities in vehicles per km per lane, speeds in km/h
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SpeedDensity {
+    pub model: SpeedDensityModel,
+    pub jam_density: f64,
+    pub critical_density: f64,
+}
+
+impl SpeedDensity {
+    pub fn new(model: SpeedDensityModel) -> Self {
+        SpeedDensity {
+            model,
+            jam_density: 150.0,
+            critical_density: 40.0,
+        }
+    }
+
+    pub fn speed(&self, free_speed: f64, density: f64) -> f64 {
+        if density.is_nan() || density <= 0.0 {
+            return free_speed;
+        }
+        let speed = match self.model {
+            SpeedDensityModel::Greenshields => free_speed * (1.0 - density / self.jam_density),
+            // Scaled so the speed reaches free flow at the critical density
+            SpeedDensityModel::Greenberg => {
+                let optimum_speed = free_speed / (self.jam_density / self.critical_density).ln();
+                optimum_speed * (self.jam_density / density).ln()
+            }
+            SpeedDensityModel::Underwood => free_speed * (-density / self.critical_density).exp(),
+        };
+        speed.clamp(MIN_SPEED.min(free_speed), free_speed)
+    }
+}
+
+mod tests {
+    #[test]
+    fn speeds_fall_with_density() {
+        use super::{SpeedDensity, SpeedDensityModel};
+
+        let greenshields = SpeedDensity::new(SpeedDensityModel::Greenshields);
+        assert_eq!(greenshields.speed(60.0, 0.0), 60.0);
+        assert_eq!(greenshields.speed(60.0, 75.0), 30.0);
+        assert_eq!(greenshields.speed(60.0, 300.0), 1.0);
+
+        let greenberg = SpeedDensity::new(SpeedDensityModel::Greenberg);
+        assert_eq!(greenberg.speed(60.0, 10.0), 60.0);
+        assert!((greenberg.speed(60.0, 40.0) - 60.0).abs() < 1e-9);
+        assert!(greenberg.speed(60.0, 100.0) < 30.0);
+
+        let underwood: SpeedDensity = SpeedDensity::new("Underwood".parse().unwrap());
+        assert!((underwood.speed(60.0, 40.0) - 60.0 / std::f64::consts::E).abs() < 1e-9);
+    }
+}
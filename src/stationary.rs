@@ -0,0 +1,287 @@
+use serde::Serialize;
+
+use crate::diagnostics::{RowIssue, ROW_SUM_TOLERANCE};
+use crate::error::{Error, Result};
+use crate::markov_chain::TransitionMatrix;
+
+// Dense elimination is O(n³) in time and O(n²) in memory, past this size only the sparse
+// solvers are sane
+pub const DENSE_SOLVE_MAX_DIM: usize = 2000;
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub enum StationaryMethod {
+    PowerIteration,
+    // Sweeps over the columns of the sparse matrix, updating in place
+    GaussSeidel,
+    Dense,
+}
+
+impl StationaryMethod {
+    // Dense elimination while it fits, Gauss-Seidel otherwise
+    pub fn for_dim(dim: usize) -> Self {
+        match dim <= DENSE_SOLVE_MAX_DIM {
+            true => StationaryMethod::Dense,
+            false => StationaryMethod::GaussSeidel,
+        }
+    }
+}
+
+impl std::str::FromStr for StationaryMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim() {
+            "power" => Ok(StationaryMethod::PowerIteration),
+            "gauss-seidel" => Ok(StationaryMethod::GaussSeidel),
+            "dense" => Ok(StationaryMethod::Dense),
+            s => Err(format!(
+                "expected power, gauss-seidel or dense, got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct StationaryDistribution {
+    pub method: StationaryMethod,
+    pub distribution: Vec<f64>,
+    pub iterations: usize,
+    pub residual: f64,
+    pub converged: bool,
+    // Rows leaking or gaining probability, no distribution is stationary while there are any
+    pub row_issues: Vec<RowIssue>,
+}
+
+impl TransitionMatrix {
+    pub fn stationary(
+        &self,
+        method: StationaryMethod,
+        tolerance: f64,
+        max_iterations: usize,
+    ) -> Result<StationaryDistribution> {
+        match method {
+            StationaryMethod::PowerIteration => {
+                Ok(self.stationary_distribution(tolerance, max_iterations))
+            }
+            StationaryMethod::GaussSeidel => {
+                Ok(self.stationary_distribution_gauss_seidel(tolerance, max_iterations))
+            }
+            StationaryMethod::Dense => self.stationary_distribution_dense(),
+        }
+    }
+
+    // Iterates the lazy chain (I + P) / 2, which shares P's stationary vector but cannot oscillate
+    pub fn stationary_distribution(
+        &self,
+        tolerance: f64,
+        max_iterations: usize,
+    ) -> StationaryDistribution {
+        let dim = self.dim();
+        let mut pi = vec![1.0 / dim as f64; dim];
+        let mut iterations = 0;
+        let mut residual = f64::INFINITY;
+
+        while iterations < max_iterations && residual > tolerance {
+            let step = self.step(&pi);
+            residual = l1_distance(&step, &pi);
+            pi = pi
+                .iter()
+                .zip(step.iter())
+                .map(|(a, b)| (a + b) / 2.0)
+                .collect();
+            iterations += 1;
+        }
+
+        self.stationary_from(StationaryMethod::PowerIteration, pi, iterations, tolerance)
+    }
+
+    // Solves π(I - P) = 0 on the column storage, π_j = Σ_{i≠j} π_i P_ij / (1 - P_jj) with
+    // the values of this sweep where already updated. O(nnz) per sweep, usually far fewer
+    // sweeps than power iteration. The scale is fixed after every sweep.
+    pub fn stationary_distribution_gauss_seidel(
+        &self,
+        tolerance: f64,
+        max_iterations: usize,
+    ) -> StationaryDistribution {
+        let dim = self.dim();
+        let mut pi = vec![1.0 / dim as f64; dim];
+        let mut iterations = 0;
+        let mut residual = f64::INFINITY;
+
+        while iterations < max_iterations && residual > tolerance {
+            for j in 0..dim {
+                let (rows, values) = self.column(j);
+                let (mut inflow, mut stay) = (0.0, 0.0);
+                for (i, p) in rows
+                    .iter()
+                    .zip(values.iter())
+                    .filter(|(_, p)| p.is_finite())
+                {
+                    match *i == j {
+                        true => stay += p,
+                        false => inflow += pi[*i] * p,
+                    }
+                }
+                // A street keeping everything it gets has no balance equation to solve
+                if stay < 1.0 {
+                    pi[j] = inflow / (1.0 - stay);
+                }
+            }
+            let total: f64 = pi.iter().sum();
+            if total > 0.0 {
+                pi.iter_mut().for_each(|x| *x /= total);
+            }
+            residual = l1_distance(&self.step(&pi), &pi);
+            iterations += 1;
+        }
+
+        self.stationary_from(StationaryMethod::GaussSeidel, pi, iterations, tolerance)
+    }
+
+    // Solves π(P - I) = 0 with Σπ = 1 replacing the last balance equation by dense
+    // Gaussian elimination, exact but O(n³) and limited to DENSE_SOLVE_MAX_DIM states
+    pub fn stationary_distribution_dense(&self) -> Result<StationaryDistribution> {
+        let dim = self.dim();
+        if dim == 0 {
+            return Err(Error::Solver("empty transition matrix".to_string()));
+        }
+        if dim > DENSE_SOLVE_MAX_DIM {
+            return Err(Error::Solver(format!(
+                "dense solve limited to {} states, matrix has {}",
+                DENSE_SOLVE_MAX_DIM, dim
+            )));
+        }
+
+        // Row i of the system is the balance equation of state i: Σ_j π_j (P_ji - δ_ji) = 0
+        let mut system = vec![vec![0.0; dim + 1]; dim];
+        for (i, row) in system.iter_mut().enumerate() {
+            row[i] = -1.0;
+        }
+        for (from, to, p) in self.entries().filter(|(_, _, p)| p.is_finite()) {
+            system[to][from] += p;
+        }
+        system[dim - 1] = vec![1.0; dim + 1];
+
+        let pi = gaussian_elimination(system)?;
+        Ok(self.stationary_from(StationaryMethod::Dense, pi, 1, f64::INFINITY))
+    }
+
+    fn stationary_from(
+        &self,
+        method: StationaryMethod,
+        distribution: Vec<f64>,
+        iterations: usize,
+        tolerance: f64,
+    ) -> StationaryDistribution {
+        let residual = l1_distance(&self.step(&distribution), &distribution);
+        let row_issues = self.row_issues(ROW_SUM_TOLERANCE);
+        StationaryDistribution {
+            method,
+            distribution,
+            iterations,
+            residual,
+            converged: residual <= tolerance && row_issues.is_empty(),
+            row_issues,
+        }
+    }
+
+    // One step π P. Rows not summing to one leak or add mass, which is left to show.
+    fn step(&self, pi: &[f64]) -> Vec<f64> {
+        let mut next = vec![0.0; pi.len()];
+        for (j, x) in next.iter_mut().enumerate() {
+            let (rows, values) = self.column(j);
+            *x = rows
+                .iter()
+                .zip(values.iter())
+                .filter(|(_, p)| p.is_finite())
+                .map(|(i, p)| pi[*i] * p)
+                .sum();
+        }
+        next
+    }
+}
+
+fn l1_distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y).abs()).sum()
+}
+
+fn gaussian_elimination(system: Vec<Vec<f64>>) -> Result<Vec<f64>> {
+    match solve_dense(system) {
+        Some(solution) => Ok(solution.into_iter().map(|x| x[0]).collect()),
+        None => Err(Error::Solver(
+            "singular system, the chain has more than one closed class".to_string(),
+        )),
+    }
+}
+
+// Solves A X = B for the augmented rows [A | B] with partial pivoting, None when A is
+// singular. Row i of the result holds the i-th unknown of every right-hand side.
+pub(crate) fn solve_dense(mut system: Vec<Vec<f64>>) -> Option<Vec<Vec<f64>>> {
+    let dim = system.len();
+    let width = system.first().map_or(0, |row| row.len());
+    for col in 0..dim {
+        let pivot = (col..dim)
+            .max_by(|a, b| system[*a][col].abs().total_cmp(&system[*b][col].abs()))
+            .unwrap_or(col);
+        if system[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        system.swap(col, pivot);
+
+        let (upper, lower) = system.split_at_mut(col + 1);
+        let pivot_row = &upper[col];
+        for row in lower.iter_mut() {
+            let factor = row[col] / pivot_row[col];
+            if factor != 0.0 {
+                for (x, p) in row[col..].iter_mut().zip(pivot_row[col..].iter()) {
+                    *x -= factor * p;
+                }
+            }
+        }
+    }
+
+    let mut solution = vec![vec![0.0; width - dim]; dim];
+    for row in (0..dim).rev() {
+        for c in 0..width - dim {
+            let known: f64 = (row + 1..dim)
+                .map(|k| system[row][k] * solution[k][c])
+                .sum();
+            solution[row][c] = (system[row][dim + c] - known) / system[row][row];
+        }
+    }
+    Some(solution)
+}
+
+mod tests {
+    #[test]
+    fn two_state_chain() {
+        let t_mtx = crate::markov_chain::TransitionMatrix::new(
+            2,
+            vec![(0, 0, 0.9), (0, 1, 0.1), (1, 0, 0.5), (1, 1, 0.5)],
+        );
+
+        let power = t_mtx.stationary_distribution(1e-12, 10_000);
+        assert!(power.converged);
+        assert!((power.distribution[0] - 5.0 / 6.0).abs() < 1e-9);
+
+        let dense = t_mtx.stationary_distribution_dense().unwrap();
+        assert!((dense.distribution[0] - 5.0 / 6.0).abs() < 1e-12);
+        assert!((dense.distribution[1] - 1.0 / 6.0).abs() < 1e-12);
+
+        let sparse = t_mtx.stationary_distribution_gauss_seidel(1e-12, 10_000);
+        assert!(sparse.converged && sparse.iterations < power.iterations);
+        assert!((sparse.distribution[0] - 5.0 / 6.0).abs() < 1e-9);
+
+        // State 1 leaks a tenth of its mass, which is reported rather than normalised away
+        let leaking = crate::markov_chain::TransitionMatrix::new(
+            2,
+            vec![(0, 0, 0.9), (0, 1, 0.1), (1, 0, 0.4), (1, 1, 0.5)],
+        );
+        let power = leaking.stationary_distribution(1e-12, 10_000);
+        assert!(!power.converged);
+        assert_eq!(power.row_issues.len(), 1);
+        assert_eq!(power.row_issues[0].row, 1);
+        assert!(power.distribution.iter().sum::<f64>() < 0.5);
+    }
+}
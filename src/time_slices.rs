@@ -0,0 +1,272 @@
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+
+use crate::data_reader::NetworkData;
+use crate::error::{Error, Result};
+use crate::markov_chain::{MarkovChain, TransitionMatrix, TransitionOptions};
+use crate::traffic_source::TrafficSource;
+use crate::trajectory::Trajectory;
+
+const MINUTES_PER_WEEK: i64 = 7 * 24 * 60;
+
+// Consecutive intervals of slice_minutes from start, repeating every week
+#[derive(Debug, Clone)]
+pub struct TimeSlicing {
+    start: DateTime<Utc>,
+    slice_minutes: u32,
+    slice_count: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct TimeSlice {
+    pub index: usize,
+    pub start: DateTime<Utc>,
+    pub minutes: u32,
+}
+
+impl TimeSlice {
+    // Weekday and time of day, e.g. "mon-0815", unique within a week
+    pub fn label(&self) -> String {
+        self.start.format("%a-%H%M").to_string().to_lowercase()
+    }
+}
+
+impl TimeSlicing {
+    pub fn new(start: DateTime<Utc>, slice_minutes: u32, slice_count: usize) -> Result<Self> {
+        if slice_minutes == 0 || slice_count == 0 {
+            return Err(Error::InvalidInput(
+                "time slices need a positive length and count".to_string(),
+            ));
+        }
+        if slice_minutes as i64 * slice_count as i64 > MINUTES_PER_WEEK {
+            return Err(Error::InvalidInput(format!(
+                "{} slices of {} minutes span more than a week",
+                slice_count, slice_minutes
+            )));
+        }
+        Ok(TimeSlicing {
+            start,
+            slice_minutes,
+            slice_count,
+        })
+    }
+
+    // Every slice of a week from start, the caller picks it so the slices do not depend on
+    // when they are built, see next_monday
+    pub fn weekly(start: DateTime<Utc>, slice_minutes: u32) -> Result<Self> {
+        let slice_count = match slice_minutes {
+            0 => 0,
+            m => (MINUTES_PER_WEEK / m as i64) as usize,
+        };
+        TimeSlicing::new(start, slice_minutes, slice_count)
+    }
+
+    pub fn len(&self) -> usize {
+        self.slice_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slice_count == 0
+    }
+
+    pub fn slice(&self, index: usize) -> TimeSlice {
+        TimeSlice {
+            index,
+            start: self.start + Duration::minutes(self.slice_minutes as i64 * index as i64),
+            minutes: self.slice_minutes,
+        }
+    }
+
+    pub fn slices(&self) -> Vec<TimeSlice> {
+        (0..self.slice_count).map(|i| self.slice(i)).collect()
+    }
+
+    // Slice holding a unix timestamp, at the same weekday and time in any week
+    pub fn slice_of(&self, timestamp: f64) -> Option<usize> {
+        let minutes = (timestamp - self.start.timestamp() as f64) / 60.0;
+        let index =
+            (minutes.rem_euclid(MINUTES_PER_WEEK as f64) / self.slice_minutes as f64) as usize;
+        (index < self.slice_count).then_some(index)
+    }
+
+    // Keeps matched samples recorded during the slice, the rest become unmatched
+    pub fn mask<T: Clone>(
+        &self,
+        index: usize,
+        trajectories: &[Trajectory],
+        matched: &[Vec<Option<T>>],
+    ) -> Vec<Vec<Option<T>>> {
+        trajectories
+            .iter()
+            .zip(matched.iter())
+            .map(|(trajectory, edges)| {
+                trajectory
+                    .points
+                    .iter()
+                    .zip(edges.iter())
+                    .map(|(p, edge)| {
+                        edge.clone()
+                            .filter(|_| self.slice_of(p.timestamp) == Some(index))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+// Midnight UTC of the Monday after now, the CLI's default start, read once per run
+pub fn next_monday(now: DateTime<Utc>) -> DateTime<Utc> {
+    let days = 7 - now.weekday().num_days_from_monday() as i64;
+    let date = (now + Duration::days(days)).date_naive();
+    Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+}
+
+// One chain per time slice, node i of every chain is the same street
+pub struct TimeSlicedChain {
+    pub slicing: TimeSlicing,
+    pub chains: Vec<MarkovChain>,
+}
+
+impl TimeSlicedChain {
+    // Streets, turns and turn weights are the same in every slice, only traffic is
+    // requested again for each one
+    pub async fn new_from_network(
+        traffic_source: &dyn TrafficSource,
+        network_graph: NetworkData,
+        options: &TransitionOptions,
+        slicing: TimeSlicing,
+    ) -> Result<Self> {
+        let topology = MarkovChain::topology(network_graph, options)?;
+        let mut chains = Vec::with_capacity(slicing.len());
+        for slice in slicing.slices() {
+            chains.push(
+                topology
+                    .with_traffic_at(traffic_source, Some(slice.start))
+                    .await?,
+            );
+        }
+        Ok(TimeSlicedChain { slicing, chains })
+    }
+
+    pub fn transition_matrices(&self) -> Vec<TransitionMatrix> {
+        self.chains
+            .iter()
+            .map(TransitionMatrix::new_from_markov_chain)
+            .collect()
+    }
+
+    pub fn chain_at(&self, timestamp: f64) -> Option<&MarkovChain> {
+        self.chains.get(self.slicing.slice_of(timestamp)?)
+    }
+}
+
+mod tests {
+    #[test]
+    fn weekly_slices() {
+        use chrono::{TimeZone, Utc};
+
+        let start = Utc.with_ymd_and_hms(2024, 5, 6, 7, 0, 0).unwrap();
+        let slicing = super::TimeSlicing::new(start, 15, 8).unwrap();
+        assert_eq!(slicing.slice(0).label(), "mon-0700");
+        assert_eq!(slicing.slice(5).label(), "mon-0815");
+
+        // 08:20 on the following Monday falls in the 08:15 slice
+        let later = Utc.with_ymd_and_hms(2024, 5, 13, 8, 20, 0).unwrap();
+        assert_eq!(slicing.slice_of(later.timestamp() as f64), Some(5));
+        assert_eq!(slicing.slice_of(start.timestamp() as f64 - 60.0), None);
+
+        assert!(super::TimeSlicing::new(start, 60, 24 * 8).is_err());
+        let weekly = super::TimeSlicing::weekly(start, 15).unwrap();
+        assert_eq!(weekly.len(), 672);
+        assert_eq!(weekly.slice(4).label(), "mon-0800");
+        let monday = super::next_monday(later);
+        assert_eq!(monday, Utc.with_ymd_and_hms(2024, 5, 20, 0, 0, 0).unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn slices_only_requery_traffic() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use async_trait::async_trait;
+        use chrono::{DateTime, TimeZone, Timelike, Utc};
+
+        use crate::data_reader::fixtures::{node, street};
+        use crate::data_reader::{Intersection, NetworkData, Street};
+        use crate::error::Result;
+        use crate::markov_chain::{
+            MarkovChain, TrafficFlow, TransitionMatrix, TransitionOptions, Value,
+        };
+        use crate::traffic_source::TrafficSource;
+
+        // Way 11 is twice as slow from 08:00, counting every request
+        struct RushHour(AtomicUsize);
+
+        #[async_trait]
+        impl TrafficSource for RushHour {
+            async fn traffic_flow(
+                &self,
+                street: &Street,
+                start: &Intersection,
+                end: &Intersection,
+            ) -> Result<TrafficFlow> {
+                let t = Utc.with_ymd_and_hms(2024, 5, 6, 0, 0, 0).unwrap();
+                self.traffic_flow_at(street, start, end, t).await
+            }
+
+            async fn traffic_flow_at(
+                &self,
+                street: &Street,
+                _start: &Intersection,
+                _end: &Intersection,
+                departure: DateTime<Utc>,
+            ) -> Result<TrafficFlow> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                let slow = match street.id == 11 && departure.hour() >= 8 {
+                    true => 2.0,
+                    false => 1.0,
+                };
+                let hours = slow * street.length / 1000.0 / street.maxspeed as f64;
+                Ok(TrafficFlow::new(
+                    Value::Known(hours),
+                    Value::Known(street.length / 1000.0 / hours),
+                ))
+            }
+        }
+
+        let nw = NetworkData::new(
+            "rush".to_string(),
+            (1..=3).map(|id| node(id, 0.0, id as f64 * 0.001)).collect(),
+            vec![
+                street(10, 1, 2),
+                street(10, 2, 1),
+                street(11, 2, 3),
+                street(11, 3, 2),
+            ],
+        );
+        let options = TransitionOptions::default();
+        let start = Utc.with_ymd_and_hms(2024, 5, 6, 7, 30, 0).unwrap();
+        let slicing = super::TimeSlicing::new(start, 30, 2).unwrap();
+
+        let source = RushHour(AtomicUsize::new(0));
+        let sliced =
+            super::TimeSlicedChain::new_from_network(&source, nw.clone(), &options, slicing)
+                .await
+                .unwrap();
+        assert_eq!(source.0.load(Ordering::SeqCst), 2 * nw.edges.len());
+
+        let matrices = sliced.transition_matrices();
+        for (i, slice) in sliced.slicing.slices().iter().enumerate() {
+            let rebuilt =
+                MarkovChain::new_from_network_at(&source, nw.clone(), &options, Some(slice.start))
+                    .await
+                    .unwrap();
+            let rebuilt = TransitionMatrix::new_from_markov_chain(&rebuilt);
+            for from in 0..4 {
+                for to in 0..4 {
+                    assert_eq!(matrices[i][(from, to)], rebuilt[(from, to)]);
+                }
+            }
+        }
+        // Slowed down at 08:00, street 2 -> 3 keeps its vehicles longer
+        assert!(matrices[1][(2, 2)] > matrices[0][(2, 2)]);
+    }
+}
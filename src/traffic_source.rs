@@ -0,0 +1,82 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::data_reader::*;
+use crate::error::{Error, Result};
+use crate::google_routes::*;
+use crate::markov_chain::{TrafficFlow, Value};
+
+#[async_trait]
+pub trait TrafficSource: Send + Sync {
+    async fn traffic_flow(
+        &self,
+        street: &Street,
+        street_start: &Intersection,
+        street_end: &Intersection,
+    ) -> Result<TrafficFlow>;
+
+    // Flow for a departure at the given time, sources without time-dependent data
+    // report the same flow at every time
+    async fn traffic_flow_at(
+        &self,
+        street: &Street,
+        street_start: &Intersection,
+        street_end: &Intersection,
+        _departure: DateTime<Utc>,
+    ) -> Result<TrafficFlow> {
+        self.traffic_flow(street, street_start, street_end).await
+    }
+}
+
+// Free-flow estimate from the street's maxspeed
+pub struct OpenStreetMap;
+
+#[async_trait]
+impl TrafficSource for OpenStreetMap {
+    async fn traffic_flow(
+        &self,
+        street: &Street,
+        _street_start: &Intersection,
+        _street_end: &Intersection,
+    ) -> Result<TrafficFlow> {
+        Ok(TrafficFlow::new(
+            Value::Known((street.length / 1000.0) / (street.maxspeed as f64)),
+            Value::Known(street.maxspeed as f64),
+        ))
+    }
+}
+
+pub async fn from_str(s: &str) -> Result<Box<dyn TrafficSource>> {
+    match s {
+        "gmaps" => Ok(Box::new(
+            GoogleMapsHandler::new("insert_key_here".to_string()).await?,
+        )),
+        "osm" => Ok(Box::new(OpenStreetMap)),
+        _ => Err(Error::traffic_provider(
+            None,
+            format!("unknown traffic data source '{}'", s),
+        )),
+    }
+}
+
+mod tests {
+    #[actix_rt::test]
+    async fn free_flow_from_maxspeed() {
+        use super::TrafficSource;
+        use crate::data_reader::fixtures::{node, one_way};
+
+        let start = node(1, -27.6, -48.5);
+        let end = node(2, -27.601, -48.5);
+        let street = crate::data_reader::Street {
+            maxspeed: 40,
+            length: 200.0,
+            ..one_way(10, 1, 2)
+        };
+        let flow = super::OpenStreetMap
+            .traffic_flow(&street, &start, &end)
+            .await
+            .unwrap();
+        assert_eq!(flow.estimated_travel_time().as_f64(), 0.005);
+        assert_eq!(flow.estimated_average_speed().as_f64(), 40.0);
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::fs;
+
+use chrono::{DateTime, NaiveDateTime};
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+use crate::map_matching::Router;
+
+// Matched samples further apart than this along the streets are not joined
+const MAX_GAP_METRES: f64 = 1000.0;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct GpsPoint {
+    // Seconds since the unix epoch
+    pub timestamp: f64,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Trajectory {
+    pub vehicle: String,
+    pub points: Vec<GpsPoint>,
+}
+
+// Observed moves between markov node ids, staying on a street counts as (id, id)
+pub type TransitionCounts = HashMap<(u64, u64), u64>;
+
+// The street a sample is matched to, with the streets driven since the previous sample
+// when the matcher knows them
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchedStreet {
+    pub edge: u64,
+    pub route: Option<Vec<u64>>,
+}
+
+// Moves seen in matched trajectories and how consecutive samples were joined
+#[derive(Debug, Default, Clone)]
+pub struct ObservedTransitions {
+    pub counts: TransitionCounts,
+    // Sample pairs joined through streets in between
+    pub expanded: usize,
+    // Sample pairs no route joins, left out
+    pub skipped: usize,
+}
+
+// Reads "vehicle_id,timestamp,lat,lon" rows into one time-ordered trajectory per
+// vehicle, timestamps are unix seconds or RFC 3339 / "%Y-%m-%d %H:%M:%S" in UTC
+pub fn read_csv(path: &str) -> Result<Vec<Trajectory>> {
+    let content = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+
+    let mut trajectories: Vec<Trajectory> = Vec::new();
+    let mut vehicles: HashMap<String, usize> = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if line.trim().is_empty()
+            || (i == 0 && fields.get(2).is_some_and(|f| f.parse::<f64>().is_err()))
+        {
+            continue;
+        }
+        let (vehicle, timestamp, latitude, longitude) = match fields.as_slice() {
+            [vehicle, timestamp, latitude, longitude] => (vehicle, timestamp, latitude, longitude),
+            _ => {
+                return Err(Error::parse(
+                    path,
+                    Some(i),
+                    format!("expected 4 columns, found {}", fields.len()),
+                ))
+            }
+        };
+        let point = GpsPoint {
+            timestamp: parse_timestamp(timestamp).ok_or_else(|| {
+                Error::parse(path, Some(i), format!("bad timestamp '{}'", timestamp))
+            })?,
+            latitude: latitude
+                .parse()
+                .map_err(|e| Error::parse(path, Some(i), e))?,
+            longitude: longitude
+                .parse()
+                .map_err(|e| Error::parse(path, Some(i), e))?,
+        };
+
+        let index = *vehicles.entry(vehicle.to_string()).or_insert_with(|| {
+            trajectories.push(Trajectory {
+                vehicle: vehicle.to_string(),
+                points: Vec::new(),
+            });
+            trajectories.len() - 1
+        });
+        trajectories[index].points.push(point);
+    }
+
+    for trajectory in trajectories.iter_mut() {
+        trajectory
+            .points
+            .sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp));
+    }
+    Ok(trajectories)
+}
+
+fn parse_timestamp(s: &str) -> Option<f64> {
+    if let Ok(seconds) = s.parse::<f64>() {
+        return Some(seconds);
+    }
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(s) {
+        return Some(datetime.timestamp_millis() as f64 / 1000.0);
+    }
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .map(|datetime| datetime.and_utc().timestamp() as f64)
+}
+
+// Counts every move between consecutive matched samples, along the matcher's route or
+// else the shortest one, so samples skipping streets still count each turn taken.
+// Unmatched samples break the sequence.
+pub fn count_transitions(
+    matched: &[Vec<Option<MatchedStreet>>],
+    router: &Router,
+) -> ObservedTransitions {
+    let mut observed = ObservedTransitions::default();
+    for sequence in matched {
+        for pair in sequence.windows(2) {
+            let (from, to) = match pair {
+                [Some(from), Some(to)] => (from, to),
+                _ => continue,
+            };
+            let route = match (&to.route, from.edge == to.edge) {
+                (Some(route), _) => Some(route.clone()),
+                (None, true) => Some(Vec::new()),
+                (None, false) => router.between(from.edge, to.edge, MAX_GAP_METRES),
+            };
+            let route = match route {
+                Some(route) => route,
+                None => {
+                    observed.skipped += 1;
+                    continue;
+                }
+            };
+            if !route.is_empty() {
+                observed.expanded += 1;
+            }
+
+            let path: Vec<u64> = [from.edge]
+                .into_iter()
+                .chain(route)
+                .chain([to.edge])
+                .collect();
+            for hop in path.windows(2) {
+                *observed.counts.entry((hop[0], hop[1])).or_insert(0) += 1;
+            }
+        }
+    }
+    observed
+}
+
+mod tests {
+    #[test]
+    fn read_and_count_trajectories() {
+        let dir = std::env::temp_dir().join("geomarkover_trajectories");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("gps.csv").to_string_lossy().to_string();
+        std::fs::write(
+            &path,
+            "vehicle_id,timestamp,lat,lon\n\
+             a,20,-27.60,-48.50\n\
+             b,2024-05-01T12:00:00Z,-27.70,-48.60\n\
+             a,10,-27.61,-48.51\n\
+             b,2024-05-01 12:00:05,-27.71,-48.61\n",
+        )
+        .unwrap();
+
+        let trajectories = super::read_csv(&path).unwrap();
+        assert_eq!(trajectories.len(), 2);
+        assert_eq!(trajectories[0].vehicle, "a");
+        assert_eq!(trajectories[0].points[0].timestamp, 10.0);
+        assert_eq!(trajectories[1].points[1].timestamp, 1714564805.0);
+
+        // Streets 0 -> 1 -> 2 -> 3 in a row, then 4 far away
+        let router = crate::map_matching::Router::new(
+            vec![(1, 2), (2, 3), (3, 4), (4, 5), (8, 9)],
+            vec![100.0; 5],
+        );
+        let street = |edge| Some(super::MatchedStreet { edge, route: None });
+        let observed = super::count_transitions(
+            &[
+                vec![street(0), street(0), street(1), None, street(2)],
+                vec![street(0), street(3), street(4)],
+            ],
+            &router,
+        );
+        let counts = &observed.counts;
+        assert_eq!(counts[&(0, 0)], 1);
+        assert_eq!(counts[&(0, 1)], 2);
+        assert_eq!(counts.get(&(1, 2)), Some(&1));
+        assert_eq!(counts[&(2, 3)], 1);
+        assert_eq!((observed.expanded, observed.skipped), (1, 1));
+    }
+}
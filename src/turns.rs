@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+
+use crate::data_reader::Intersection;
+
+// Turns within this many degrees of the incoming heading count as going straight
+const STRAIGHT_THRESHOLD: f64 = 30.0;
+// Turns sharper than this are treated as turning back
+const U_TURN_THRESHOLD: f64 = 150.0;
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub enum TurnKind {
+    Straight,
+    Right,
+    Left,
+    UTurn,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TurnWeights {
+    pub straight: f64,
+    pub right: f64,
+    pub left: f64,
+    pub u_turn: f64,
+}
+
+impl TurnWeights {
+    // Every turn as likely as any other, U-turns included
+    pub fn uniform() -> Self {
+        TurnWeights {
+            straight: 1.0,
+            right: 1.0,
+            left: 1.0,
+            u_turn: 1.0,
+        }
+    }
+
+    pub fn weight(&self, kind: TurnKind) -> f64 {
+        match kind {
+            TurnKind::Straight => self.straight,
+            TurnKind::Right => self.right,
+            TurnKind::Left => self.left,
+            TurnKind::UTurn => self.u_turn,
+        }
+    }
+}
+
+// Parses "straight,right,left,u_turn", e.g. "4,2,1.5,0.25"
+impl std::str::FromStr for TurnWeights {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let weights = s
+            .split(',')
+            .map(|x| x.trim().parse::<f64>().ok().filter(|w| *w >= 0.0))
+            .collect::<Option<Vec<f64>>>();
+        match weights.as_deref() {
+            Some(&[straight, right, left, u_turn]) => Ok(TurnWeights {
+                straight,
+                right,
+                left,
+                u_turn,
+            }),
+            _ => Err(format!(
+                "expected four non-negative weights straight,right,left,u_turn, got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+// Drivers mostly keep going, turn right more readily than across traffic and rarely turn back
+impl Default for TurnWeights {
+    fn default() -> Self {
+        TurnWeights {
+            straight: 4.0,
+            right: 2.0,
+            left: 1.5,
+            u_turn: 0.25,
+        }
+    }
+}
+
+// Initial great-circle bearing in degrees clockwise from north
+pub fn bearing(from: &Intersection, to: &Intersection) -> f64 {
+    let (lat1, lat2) = (from.latitude.to_radians(), to.latitude.to_radians());
+    let d_lon = (to.longitude - from.longitude).to_radians();
+    let y = d_lon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * d_lon.cos();
+    y.atan2(x).to_degrees().rem_euclid(360.0)
+}
+
+// Signed heading change in (-180, 180], positive when turning right
+pub fn turn_angle(
+    incoming: (&Intersection, &Intersection),
+    outgoing: (&Intersection, &Intersection),
+) -> f64 {
+    let delta = bearing(outgoing.0, outgoing.1) - bearing(incoming.0, incoming.1);
+    let delta = delta.rem_euclid(360.0);
+    match delta > 180.0 {
+        true => delta - 360.0,
+        false => delta,
+    }
+}
+
+pub fn classify(
+    incoming: (&Intersection, &Intersection),
+    outgoing: (&Intersection, &Intersection),
+) -> TurnKind {
+    // Driving back along the reverse street is a U-turn whatever its geometry
+    if outgoing.1.id == incoming.0.id {
+        return TurnKind::UTurn;
+    }
+    let angle = turn_angle(incoming, outgoing);
+    match angle.abs() {
+        a if a <= STRAIGHT_THRESHOLD => TurnKind::Straight,
+        a if a >= U_TURN_THRESHOLD => TurnKind::UTurn,
+        _ if angle > 0.0 => TurnKind::Right,
+        _ => TurnKind::Left,
+    }
+}
+
+mod tests {
+    #[test]
+    fn classify_turns_at_crossing() {
+        use super::TurnKind;
+        use crate::data_reader::fixtures::node;
+
+        // Heading north into a crossing at the origin
+        let south = node(1, -0.001, 0.0);
+        let center = node(2, 0.0, 0.0);
+        let north = node(3, 0.001, 0.0);
+        let east = node(4, 0.0, 0.001);
+        let west = node(5, 0.0, -0.001);
+
+        let incoming = (&south, &center);
+        assert_eq!(
+            super::classify(incoming, (&center, &north)),
+            TurnKind::Straight
+        );
+        assert_eq!(super::classify(incoming, (&center, &east)), TurnKind::Right);
+        assert_eq!(super::classify(incoming, (&center, &west)), TurnKind::Left);
+        assert_eq!(
+            super::classify(incoming, (&center, &south)),
+            TurnKind::UTurn
+        );
+    }
+}